@@ -0,0 +1,71 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use sp::node::{Context, Node};
+use sp::parser::statement;
+use sp::{resolver, vm};
+
+const FIB: &str = "fn fib(a) { if a < 2 { a; } else { fib(a-1) + fib(a-2); }; }";
+const SUM: &str = "fn sum(n) { s = 0; for i = 0; i < n; i = i + 1 { s = s + i; }; s; }";
+const GLOBAL_SUM: &str = "while i < 10000 { s = s + i; i = i + 1; }";
+// The language has no strings yet, so string building can't be measured
+
+fn parse(source: &str) -> Node {
+    let (_, parsed) = statement(source.as_bytes()).unwrap();
+    parsed
+}
+
+// Defines every function in a fresh Context and returns the resolved program
+fn prepare(definitions: &[&str], program: &str) -> (Context, Node) {
+    let mut context = Context::default();
+    for definition in definitions.iter() {
+        let definition = resolver::resolve(&parse(definition), &context);
+        context.evaluate(&definition).unwrap();
+    }
+    let program = resolver::resolve(&parse(program), &context);
+    (context, program)
+}
+
+fn recursion(c: &mut Criterion) {
+    let (context, program) = prepare(&[FIB], "fib(20)");
+    c.bench_function("fib(20) tree walker", |b| {
+        b.iter(|| context.clone().evaluate(&program).unwrap())
+    });
+    c.bench_function("fib(20) vm", |b| {
+        b.iter(|| {
+            let mut context = context.clone();
+            context
+                .run(|context| vm::evaluate(&program, context))
+                .unwrap()
+        })
+    });
+}
+
+fn loops(c: &mut Criterion) {
+    let (context, program) = prepare(&[SUM], "sum(10000)");
+    c.bench_function("local loop sum tree walker", |b| {
+        b.iter(|| context.clone().evaluate(&program).unwrap())
+    });
+    c.bench_function("local loop sum vm", |b| {
+        b.iter(|| {
+            let mut context = context.clone();
+            context
+                .run(|context| vm::evaluate(&program, context))
+                .unwrap()
+        })
+    });
+
+    let (context, program) = prepare(&["s = 0", "i = 0"], GLOBAL_SUM);
+    c.bench_function("global loop sum tree walker", |b| {
+        b.iter(|| context.clone().evaluate(&program).unwrap())
+    });
+    let unresolved = parse(GLOBAL_SUM);
+    c.bench_function("global loop sum unresolved", |b| {
+        b.iter(|| context.clone().evaluate(&unresolved).unwrap())
+    });
+}
+
+fn parsing(c: &mut Criterion) {
+    c.bench_function("parse fib", |b| b.iter(|| parse(FIB)));
+}
+
+criterion_group!(benches, recursion, loops, parsing);
+criterion_main!(benches);
@@ -0,0 +1,138 @@
+#![no_main]
+use arbitrary::{Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+use sp::node::{Annotation, Function, Node, Operation, Value};
+use sp::{typecheck, Interpreter};
+use std::rc::Rc;
+
+const OPERATIONS: [Operation; 10] = [
+    Operation::Plus,
+    Operation::Minus,
+    Operation::Divide,
+    Operation::Multiply,
+    Operation::Less,
+    Operation::More,
+    Operation::Equal,
+    Operation::NotEqual,
+    Operation::Or,
+    Operation::And,
+];
+
+// Few names, so that variables and calls find what other statements define
+const NAMES: [&str; 4] = ["a", "b", "f", "g"];
+
+fn name(u: &mut Unstructured) -> Result<String> {
+    Ok(u.choose(&NAMES)?.to_string())
+}
+
+fn leaf(u: &mut Unstructured) -> Result<Node> {
+    Ok(match u.int_in_range(0..=3)? {
+        0 => Node::Constant(Value::Number(u.arbitrary()?)),
+        1 => Node::Constant(Value::Bool(u.arbitrary()?)),
+        2 => Node::Constant(Value::None),
+        _ => Node::Variable(name(u)?, None),
+    })
+}
+
+fn annotation(u: &mut Unstructured) -> Result<Option<Annotation>> {
+    Ok(match u.int_in_range(0..=2)? {
+        0 => Some(Annotation::Number),
+        1 => Some(Annotation::Bool),
+        _ => None,
+    })
+}
+
+fn nodes(u: &mut Unstructured, depth: usize) -> Result<Vec<Rc<Node>>> {
+    let mut nodes = Vec::new();
+    for _ in 0..u.int_in_range(0..=3)? {
+        nodes.push(Rc::new(node(u, depth)?));
+    }
+    Ok(nodes)
+}
+
+// Any tree the parser could give, without having to get through the grammar
+fn node(u: &mut Unstructured, depth: usize) -> Result<Node> {
+    if depth == 0 {
+        return leaf(u);
+    }
+    let depth = depth - 1;
+    Ok(match u.int_in_range(0..=9)? {
+        0 | 1 => leaf(u)?,
+        2 => Node::BinaryOperation(
+            *u.choose(&OPERATIONS)?,
+            Rc::new(node(u, depth)?),
+            Rc::new(node(u, depth)?),
+        ),
+        3 => Node::Block(nodes(u, depth)?),
+        4 => Node::Assignment(name(u)?, None, Rc::new(node(u, depth)?)),
+        5 => {
+            let mut parameters = Vec::new();
+            for parameter in NAMES.iter() {
+                if u.arbitrary()? {
+                    parameters.push(parameter.to_string());
+                }
+            }
+            let mut types = Vec::new();
+            for _ in parameters.iter() {
+                types.push(annotation(u)?);
+            }
+            let function = Function {
+                parameters,
+                types,
+                returns: annotation(u)?,
+                memoized: u.arbitrary()?,
+                body: Rc::new(Node::Block(nodes(u, depth)?)),
+            };
+            Node::Function(name(u)?, Rc::new(function))
+        }
+        6 => Node::Call(name(u)?, nodes(u, depth)?),
+        7 => {
+            let condition = Rc::new(node(u, depth)?);
+            let if_body = Rc::new(node(u, depth)?);
+            let else_body = match u.arbitrary()? {
+                true => Some(Rc::new(node(u, depth)?)),
+                false => None,
+            };
+            Node::IfElse(condition, if_body, else_body)
+        }
+        8 => Node::While(Rc::new(node(u, depth)?), Rc::new(node(u, depth)?)),
+        _ => Node::For(
+            Rc::new(node(u, depth)?),
+            Rc::new(node(u, depth)?),
+            Rc::new(node(u, depth)?),
+            Rc::new(node(u, depth)?),
+        ),
+    })
+}
+
+fn program(u: &mut Unstructured) -> Result<Vec<(usize, Node)>> {
+    let mut program = Vec::new();
+    for line in 1..=u.int_in_range(1..=8)? {
+        program.push((line, node(u, 5)?));
+    }
+    Ok(program)
+}
+
+// Evaluation may fail but must not panic in either evaluator, with or without
+// optimizing first. Fuel and recursion limits keep every input short.
+fuzz_target!(|data: &[u8]| {
+    let program = match program(&mut Unstructured::new(data)) {
+        Ok(program) => program,
+        Err(_) => return,
+    };
+    typecheck::check(&program);
+    for (use_vm, optimize) in [(false, false), (true, false), (false, true)].iter() {
+        let mut interpreter = Interpreter::builder()
+            .sandbox(true)
+            .fuel(100_000)
+            .max_recursion(64)
+            .vm(*use_vm)
+            .optimize(*optimize)
+            .build();
+        for (_, statement) in program.iter() {
+            if interpreter.evaluate(statement, &mut Vec::new()).is_err() {
+                break;
+            }
+        }
+    }
+});
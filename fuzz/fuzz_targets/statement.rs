@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sp::{diagnostics, parser, printer, Context};
+
+// Any input may fail to parse but must not panic, and what parses has to
+// print and check
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, node)) = parser::statement(data) {
+        printer::source(&node);
+        printer::tree(&node);
+    }
+    parser::tokens(data);
+    if let Ok(source) = std::str::from_utf8(data) {
+        diagnostics::check(source, &Context::default());
+    }
+});
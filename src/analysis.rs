@@ -0,0 +1,340 @@
+use crate::node::{is_builtin, Context, Node, Value, BUILTINS};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    Function(Vec<String>),
+    // What the assigned expression is known to be without running it
+    Variable(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: Kind,
+    // Nodes have no positions, names defined inside a function get its line
+    pub line: usize,
+}
+
+impl Symbol {
+    pub fn describe(&self) -> String {
+        match &self.kind {
+            Kind::Function(parameters) => format!("fn {}({})", self.name, parameters.join(", ")),
+            Kind::Variable(kind) => format!("{}: {}", self.name, kind),
+        }
+    }
+}
+
+// Functions, parameters and assigned variables of a parsed program in the
+// order they are defined
+pub fn symbols(program: &[(usize, Node)]) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    for (line, node) in program.iter() {
+        collect(node, *line, &mut symbols);
+    }
+    symbols
+}
+
+fn collect(node: &Node, line: usize, symbols: &mut Vec<Symbol>) {
+    let mut define = |name: &str, kind: Kind| {
+        symbols.push(Symbol {
+            name: name.to_string(),
+            kind,
+            line,
+        })
+    };
+    match node {
+        Node::Function(name, function) => {
+            define(name, Kind::Function(function.parameters.clone()));
+            for parameter in function.parameters.iter() {
+                define(parameter, Kind::Variable("parameter"));
+            }
+            collect(&function.body, line, symbols);
+        }
+        Node::Assignment(name, _, value) => define(name, Kind::Variable(kind(value))),
+        Node::Block(body) => {
+            for expression in body.iter() {
+                collect(expression, line, symbols);
+            }
+        }
+        Node::IfElse(_, if_body, else_body) => {
+            collect(if_body, line, symbols);
+            if let Some(else_body) = else_body {
+                collect(else_body, line, symbols);
+            }
+        }
+        Node::While(_, body) => collect(body, line, symbols),
+        Node::For(init, _, body, step) => {
+            collect(init, line, symbols);
+            collect(body, line, symbols);
+            collect(step, line, symbols);
+        }
+        Node::Constant(_) | Node::Variable(_, _) | Node::BinaryOperation(_, _, _) => {}
+        Node::Call(_, _) => {}
+    }
+}
+
+// Variables and functions referenced but defined neither by the program nor in
+// the context, with the line of the statement they are in. Checking them up
+// front keeps a script from failing halfway with some of its effects applied.
+// Order isn't considered, a variable assigned anywhere counts as defined, and
+// parameters only count inside their function.
+pub fn undefined_names(program: &[(usize, Node)], context: &Context) -> Vec<(usize, String)> {
+    let symbols = symbols(program);
+    let defined: BTreeSet<&str> = symbols
+        .iter()
+        .filter(|symbol| symbol.kind != Kind::Variable("parameter"))
+        .map(|symbol| symbol.name.as_str())
+        .collect();
+    let mut errors = Vec::new();
+    for (line, node) in program.iter() {
+        let mut found = Vec::new();
+        references(node, &[], &mut found);
+        for (name, call) in found {
+            let error = match call {
+                true if defined.contains(name.as_str())
+                    || context.function(&name).is_some()
+                    || context.host_function(&name).is_some()
+                    || context.async_function(&name).is_some()
+                    || is_builtin(&name) =>
+                {
+                    continue
+                }
+                true => format!("{} function is not defined", name),
+                false if defined.contains(name.as_str()) || context.get_var(&name).is_some() => {
+                    continue
+                }
+                false => format!("{} is not defined", name),
+            };
+            if !errors.contains(&(*line, error.clone())) {
+                errors.push((*line, error));
+            }
+        }
+    }
+    errors
+}
+
+// Names read or called outside of the given parameters, calls marked true
+fn references(node: &Node, parameters: &[String], found: &mut Vec<(String, bool)>) {
+    match node {
+        Node::Constant(_) => {}
+        Node::Variable(name, _) => {
+            if !parameters.contains(name) {
+                found.push((name.clone(), false));
+            }
+        }
+        Node::BinaryOperation(_, left_node, right_node) => {
+            references(left_node, parameters, found);
+            references(right_node, parameters, found);
+        }
+        Node::Block(body) => {
+            for expression in body.iter() {
+                references(expression, parameters, found);
+            }
+        }
+        Node::Assignment(_, _, value) => references(value, parameters, found),
+        Node::Function(_, function) => references(&function.body, &function.parameters, found),
+        Node::Call(name, arguments) => {
+            found.push((name.clone(), true));
+            for argument in arguments.iter() {
+                references(argument, parameters, found);
+            }
+        }
+        Node::IfElse(condition, if_body, else_body) => {
+            references(condition, parameters, found);
+            references(if_body, parameters, found);
+            if let Some(else_body) = else_body {
+                references(else_body, parameters, found);
+            }
+        }
+        Node::While(condition, body) => {
+            references(condition, parameters, found);
+            references(body, parameters, found);
+        }
+        Node::For(init, condition, body, step) => {
+            references(init, parameters, found);
+            references(condition, parameters, found);
+            references(body, parameters, found);
+            references(step, parameters, found);
+        }
+    }
+}
+
+// Number of parameters of every function the program defines or the context
+// knows. A name defined more than once may be called with any of its counts.
+// Host functions take any number of arguments and are left out.
+pub fn signatures(program: &[(usize, Node)], context: &Context) -> BTreeMap<String, Vec<usize>> {
+    let mut signatures: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    let mut add = |name: &str, count: usize| {
+        let counts = signatures.entry(name.to_string()).or_default();
+        if !counts.contains(&count) {
+            counts.push(count);
+        }
+    };
+    for (name, counts, _) in BUILTINS.iter() {
+        for count in counts.iter() {
+            add(name, *count);
+        }
+    }
+    for (name, function) in context.functions() {
+        add(name, function.parameters.len());
+    }
+    for symbol in symbols(program) {
+        if let Kind::Function(parameters) = symbol.kind {
+            add(&symbol.name, parameters.len());
+        }
+    }
+    signatures
+}
+
+// Calls with a different number of arguments than the function they call takes
+pub fn arity_mismatches(program: &[(usize, Node)], context: &Context) -> Vec<(usize, String)> {
+    let signatures = signatures(program, context);
+    let mut errors = Vec::new();
+    for (line, node) in program.iter() {
+        let mut calls = Vec::new();
+        collect_calls(node, &mut calls);
+        for (name, count) in calls {
+            let counts = match signatures.get(&name) {
+                Some(counts) if !counts.contains(&count) => counts,
+                _ => continue,
+            };
+            let takes: Vec<String> = counts.iter().map(usize::to_string).collect();
+            let error = format!(
+                "{} function takes {} params provided {}",
+                name,
+                takes.join(" or "),
+                count
+            );
+            if !errors.contains(&(*line, error.clone())) {
+                errors.push((*line, error));
+            }
+        }
+    }
+    errors
+}
+
+fn collect_calls(node: &Node, calls: &mut Vec<(String, usize)>) {
+    match node {
+        Node::Constant(_) | Node::Variable(_, _) => {}
+        Node::BinaryOperation(_, left_node, right_node) => {
+            collect_calls(left_node, calls);
+            collect_calls(right_node, calls);
+        }
+        Node::Block(body) => {
+            for expression in body.iter() {
+                collect_calls(expression, calls);
+            }
+        }
+        Node::Assignment(_, _, value) => collect_calls(value, calls),
+        Node::Function(_, function) => collect_calls(&function.body, calls),
+        Node::Call(name, arguments) => {
+            calls.push((name.clone(), arguments.len()));
+            for argument in arguments.iter() {
+                collect_calls(argument, calls);
+            }
+        }
+        Node::IfElse(condition, if_body, else_body) => {
+            collect_calls(condition, calls);
+            collect_calls(if_body, calls);
+            if let Some(else_body) = else_body {
+                collect_calls(else_body, calls);
+            }
+        }
+        Node::While(condition, body) => {
+            collect_calls(condition, calls);
+            collect_calls(body, calls);
+        }
+        Node::For(init, condition, body, step) => {
+            collect_calls(init, calls);
+            collect_calls(condition, calls);
+            collect_calls(body, calls);
+            collect_calls(step, calls);
+        }
+    }
+}
+
+// Everything found before evaluation that would make a program fail
+pub fn errors(program: &[(usize, Node)], context: &Context) -> Vec<(usize, String)> {
+    let mut errors = undefined_names(program, context);
+    errors.extend(arity_mismatches(program, context));
+    errors.sort_by_key(|(line, _)| *line);
+    errors
+}
+
+pub fn kind(node: &Node) -> &'static str {
+    match node {
+        Node::Constant(Value::Number(_)) => "number",
+        Node::Constant(Value::Bool(_)) => "bool",
+        Node::Constant(Value::None) => "None",
+        Node::BinaryOperation(operation, _, _) if operation.is_arithmetic() => "number",
+        Node::BinaryOperation(_, _, _) => "bool",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::analysis::{arity_mismatches, symbols, undefined_names, Kind};
+    use crate::node::{Context, Value};
+    use crate::parser::program;
+
+    #[test]
+    fn finds_definitions() {
+        let source = "fn twice(a) { b = a * 2; b; }\nready = 1 < 2\nx = twice(3)\n";
+        let symbols = symbols(&program(source).unwrap());
+        let described: Vec<(String, usize)> = symbols
+            .iter()
+            .map(|symbol| (symbol.describe(), symbol.line))
+            .collect();
+        assert_eq!(
+            described,
+            vec![
+                ("fn twice(a)".to_string(), 1),
+                ("a: parameter".to_string(), 1),
+                ("b: number".to_string(), 1),
+                ("ready: bool".to_string(), 2),
+                ("x: unknown".to_string(), 3),
+            ]
+        );
+        assert_eq!(symbols[0].kind, Kind::Function(vec!["a".to_string()]));
+    }
+
+    #[test]
+    fn finds_undefined_names() {
+        let source = "fn f(a) { a + g(b) + limit; }\n\
+                      b = f(1)\n\
+                      c = a + h(2) + exit(0)";
+        let mut context = Context::default();
+        context.set_var("limit", Value::Number(3.0));
+        assert_eq!(
+            undefined_names(&program(source).unwrap(), &context),
+            vec![
+                (1, "g function is not defined".to_string()),
+                (3, "a is not defined".to_string()),
+                (3, "h function is not defined".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_arity_mismatches() {
+        let source = "fn f(a, b) { a + b; }\n\
+                      x = f(1) + f(1, 2)\n\
+                      fn g() { exit(); }";
+        let mut context = Context::default();
+        context.register_fn("host", |_: &[Value]| Ok(Value::None));
+        assert!(arity_mismatches(&program("host(1, 2)").unwrap(), &context).is_empty());
+        assert_eq!(
+            arity_mismatches(&program("round_to(1)").unwrap(), &context),
+            vec![(1, "round_to function takes 2 params provided 1".to_string())]
+        );
+        assert_eq!(
+            arity_mismatches(&program(source).unwrap(), &context),
+            vec![
+                (2, "f function takes 2 params provided 1".to_string()),
+                (3, "exit function takes 1 params provided 0".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+// Language server speaking LSP over stdin and stdout. Documents are synced
+// whole on every change and reparsed, which is cheap for scripts this size.
+use serde_json::{json, Value as Json};
+use sp::analysis::{self, Kind, Symbol};
+use sp::node::Context;
+use sp::parser;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const KEYWORDS: [&str; 5] = ["fn", "if", "else", "while", "for"];
+
+// Reads one message framed by a Content-Length header, None at end of input
+fn read_message(input: &mut impl BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut content = vec![0; length?];
+    input.read_exact(&mut content).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+fn send(message: Json) {
+    let content = message.to_string();
+    let mut output = io::stdout();
+    let _ = write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    );
+    let _ = output.flush();
+}
+
+fn respond(id: Json, result: Json) {
+    send(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+}
+
+// LSP positions count UTF-16 code units, this is the byte offset in the line
+// of the character at one
+fn byte_offset(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (offset, c) in line.char_indices() {
+        if units >= character {
+            return offset;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+// Syntax errors and what the analysis finds cover the whole line their
+// statement starts on
+fn publish_diagnostics(uri: &str, text: &str) {
+    let mut errors = Vec::new();
+    let mut program = Vec::new();
+    for (line, parsed) in parser::statements(text) {
+        match parsed {
+            Ok(node) => program.push((line, node)),
+            Err(error) => errors.push((line, error)),
+        }
+    }
+    errors.extend(analysis::errors(&program, &Context::default()));
+    errors.sort_by_key(|(line, _)| *line);
+    let diagnostics: Vec<Json> = errors
+        .into_iter()
+        .map(|(line, error)| {
+            let length = text
+                .lines()
+                .nth(line - 1)
+                .map_or(0, |line| line.encode_utf16().count());
+            json!({
+                "range": {
+                    "start": { "line": line - 1, "character": 0 },
+                    "end": { "line": line - 1, "character": length },
+                },
+                "severity": 1,
+                "source": "sp",
+                "message": error,
+            })
+        })
+        .collect();
+    send(json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    }));
+}
+
+// Statements that don't parse are left out, the rest still gets analysed
+fn symbols(text: &str) -> Vec<Symbol> {
+    let program: Vec<_> = parser::statements(text)
+        .into_iter()
+        .filter_map(|(line, parsed)| parsed.ok().map(|node| (line, node)))
+        .collect();
+    analysis::symbols(&program)
+}
+
+fn word_at(text: &str, position: &Json) -> Option<String> {
+    let line = text.lines().nth(position["line"].as_u64()? as usize)?;
+    let column = byte_offset(line, position["character"].as_u64()? as usize);
+    // Qualified names like math::sqrt are one word
+    let is_name = |c: char| c.is_ascii_alphabetic() || c == '_' || c == ':';
+    let start = line[..column]
+        .char_indices()
+        .rfind(|(_, c)| !is_name(*c))
+        .map_or(0, |(index, c)| index + c.len_utf8());
+    let end = line[column..]
+        .find(|c| !is_name(c))
+        .map_or(line.len(), |index| column + index);
+    // A lone colon is the one of an annotation, as in a: number
+    let word = line[start..end].trim_matches(':');
+    match word.is_empty() {
+        true => None,
+        false => Some(word.to_string()),
+    }
+}
+
+fn hover(text: &str, position: &Json) -> Json {
+    let word = match word_at(text, position) {
+        Some(word) => word,
+        None => return Json::Null,
+    };
+    let symbols = symbols(text);
+    // A function signature says more than any variable of the same name
+    let symbol = symbols
+        .iter()
+        .find(|symbol| symbol.name == word && matches!(symbol.kind, Kind::Function(_)))
+        .or_else(|| symbols.iter().find(|symbol| symbol.name == word));
+    match symbol {
+        Some(symbol) => json!({ "contents": { "kind": "plaintext", "value": symbol.describe() } }),
+        None => Json::Null,
+    }
+}
+
+fn completion(text: &str) -> Json {
+    let mut items: Vec<Json> = KEYWORDS
+        .iter()
+        .map(|keyword| json!({ "label": keyword, "kind": 14 }))
+        .collect();
+    let mut seen = Vec::new();
+    for symbol in symbols(text) {
+        if seen.contains(&symbol.name) {
+            continue;
+        }
+        let kind = match symbol.kind {
+            Kind::Function(_) => 3,
+            Kind::Variable(_) => 6,
+        };
+        items.push(json!({ "label": symbol.name, "kind": kind, "detail": symbol.describe() }));
+        seen.push(symbol.name);
+    }
+    Json::Array(items)
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut documents: HashMap<String, String> = HashMap::new();
+    while let Some(message) = read_message(&mut input) {
+        let id = message.get("id").cloned();
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        match (message["method"].as_str().unwrap_or(""), id) {
+            ("initialize", Some(id)) => respond(
+                id,
+                json!({
+                    "capabilities": {
+                        "textDocumentSync": 1,
+                        "hoverProvider": true,
+                        "completionProvider": {},
+                    },
+                    "serverInfo": { "name": "sp-lsp" },
+                }),
+            ),
+            ("textDocument/didOpen", None) => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                publish_diagnostics(&uri, text);
+                documents.insert(uri, text.to_string());
+            }
+            ("textDocument/didChange", None) => {
+                let changes = params["contentChanges"].as_array();
+                let text = changes.and_then(|changes| changes.last()?["text"].as_str());
+                if let Some(text) = text {
+                    publish_diagnostics(&uri, text);
+                    documents.insert(uri, text.to_string());
+                }
+            }
+            ("textDocument/didClose", None) => {
+                documents.remove(&uri);
+                publish_diagnostics(&uri, "");
+            }
+            ("textDocument/hover", Some(id)) => {
+                let text = documents.get(&uri).map_or("", String::as_str);
+                respond(id, hover(text, &params["position"]));
+            }
+            ("textDocument/completion", Some(id)) => {
+                let text = documents.get(&uri).map_or("", String::as_str);
+                respond(id, completion(text));
+            }
+            ("shutdown", Some(id)) => respond(id, Json::Null),
+            ("exit", _) => return,
+            (method, Some(id)) => send(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": format!("{} is not supported", method) },
+            })),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,998 @@
+use crate::node::{Annotation, Function, Node, Operation, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Translates a parsed program into a Rust source file. Functions become fns on
+// f64, or bool where annotated, the other top level statements make up main
+// and top level expressions are printed. Rust fns can't see script globals, so functions may only use
+// their parameters and locals, and @memo is dropped.
+pub fn rust(program: &[(usize, Node)]) -> Result<String, String> {
+    // What each function returns, in order of definition so a call sees the
+    // functions defined before it. One still being typed returns a number.
+    let mut returns = BTreeMap::new();
+    for (line, node) in program.iter() {
+        if let Node::Function(name, function) = node {
+            returns.insert(name.clone(), Type::Number);
+            let mut emitter = Emitter::new(function.parameters.to_vec(), returns.clone());
+            let result = emitter
+                .result(name, function)
+                .map_err(|error| format!("{}: {}", line, error))?;
+            returns.insert(name.clone(), result);
+        }
+    }
+    let mut functions = String::new();
+    let mut main = Vec::new();
+    for (line, node) in program.iter() {
+        match node {
+            Node::Function(name, function) => {
+                let mut emitter = Emitter::new(function.parameters.to_vec(), returns.clone());
+                emitter
+                    .function(name, function)
+                    .map_err(|error| format!("{}: {}", line, error))?;
+                functions.push_str(&emitter.out);
+                functions.push('\n');
+            }
+            node => main.push((*line, node.clone())),
+        }
+    }
+    let mut emitter = Emitter::new(Vec::new(), returns);
+    emitter.main(&main)?;
+    Ok(functions + &emitter.out)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    Bool,
+}
+
+impl Type {
+    fn name(self) -> &'static str {
+        match self {
+            Type::Number => "f64",
+            Type::Bool => "bool",
+        }
+    }
+}
+
+struct Emitter {
+    out: String,
+    indent: usize,
+    // Every name the scope may read, parameters first
+    types: BTreeMap<String, Type>,
+    parameters: Vec<String>,
+    // Types the functions of the program return, calls to other names are
+    // builtins
+    returns: BTreeMap<String, Type>,
+    // Locals declared by the statement first assigning them
+    inline: BTreeSet<String>,
+    // Names assigned after they are declared
+    mutable: BTreeSet<String>,
+}
+
+impl Emitter {
+    fn new(parameters: Vec<String>, returns: BTreeMap<String, Type>) -> Emitter {
+        Emitter {
+            out: String::new(),
+            indent: 0,
+            types: parameters
+                .iter()
+                .map(|name| (name.clone(), Type::Number))
+                .collect(),
+            parameters,
+            returns,
+            inline: BTreeSet::new(),
+            mutable: BTreeSet::new(),
+        }
+    }
+
+    // Types the parameters and locals and finds what the function returns,
+    // which has to be what it is annotated with
+    fn result(&mut self, name: &str, function: &Function) -> Result<Type, String> {
+        for (index, parameter) in function.parameters.iter().enumerate() {
+            if let Some(Annotation::Bool) = function.parameter_type(index) {
+                self.types.insert(parameter.clone(), Type::Bool);
+            }
+        }
+        self.declare(&function.body)?;
+        let result = self.kind(last(&function.body))?;
+        let annotated = match function.returns {
+            Some(Annotation::Number) => Type::Number,
+            Some(Annotation::Bool) => Type::Bool,
+            None => return Ok(result),
+        };
+        match annotated == result {
+            true => Ok(result),
+            false => Err(format!(
+                "{} returns {} but is annotated to return {}",
+                name,
+                result.name(),
+                annotated.name()
+            )),
+        }
+    }
+
+    fn function(&mut self, name: &str, function: &Function) -> Result<(), String> {
+        let result = self.result(name, function)?;
+        let mut assigned = BTreeMap::new();
+        assignments(&function.body, &mut assigned);
+        let parameters: Vec<String> = self
+            .parameters
+            .iter()
+            .map(|parameter| match assigned.contains_key(parameter) {
+                true => format!("mut {}: {}", parameter, self.types[parameter].name()),
+                false => format!("{}: {}", parameter, self.types[parameter].name()),
+            })
+            .collect();
+        self.out.push_str(&format!(
+            "fn {}({}) -> {} ",
+            mangle(name),
+            parameters.join(", "),
+            result.name()
+        ));
+        self.block(&function.body, true)?;
+        self.out.push('\n');
+        Ok(())
+    }
+
+    fn main(&mut self, program: &[(usize, Node)]) -> Result<(), String> {
+        for (line, node) in program.iter() {
+            self.declare(node)
+                .map_err(|error| format!("{}: {}", line, error))?;
+        }
+        self.out.push_str("fn main() {\n");
+        self.indent += 1;
+        let body: Vec<&Node> = program.iter().map(|(_, node)| node).collect();
+        self.declarations(&body);
+        for (line, node) in program.iter() {
+            self.line();
+            match node {
+                node if has_value(node) => {
+                    self.out.push_str("println!(\"{}\", ");
+                    self.expression(node)
+                        .map_err(|error| format!("{}: {}", line, error))?;
+                    self.out.push_str(");\n");
+                }
+                node => self
+                    .statement(node)
+                    .map_err(|error| format!("{}: {}", line, error))?,
+            }
+        }
+        self.indent -= 1;
+        self.out.push_str("}\n");
+        Ok(())
+    }
+
+    // Finds the variables a scope assigns, typed by the first value given
+    fn declare(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Assignment(name, _, value) => {
+                if !self.types.contains_key(name) {
+                    let kind = self.kind(value)?;
+                    self.types.insert(name.clone(), kind);
+                }
+                Ok(())
+            }
+            Node::Block(body) => body.iter().try_for_each(|node| self.declare(node)),
+            Node::IfElse(_, if_body, else_body) => {
+                self.declare(if_body)?;
+                else_body.iter().try_for_each(|body| self.declare(body))
+            }
+            Node::While(_, body) => self.declare(body),
+            Node::For(init, _, body, step) => {
+                self.declare(init)?;
+                self.declare(body)?;
+                self.declare(step)
+            }
+            Node::Function(name, _) => Err(format!("{} has to be defined at the top level", name)),
+            _ => Ok(()),
+        }
+    }
+
+    // Declares the locals of a scope. One first assigned by a statement of the
+    // scope itself is declared right there, any other starts out as 0 or false
+    // because Rust won't read a variable only some paths have assigned.
+    fn declarations(&mut self, body: &[&Node]) {
+        let mut counts = BTreeMap::new();
+        body.iter().for_each(|node| assignments(node, &mut counts));
+        let mut seen = BTreeSet::new();
+        for node in body.iter() {
+            if let Node::Assignment(name, _, value) = node {
+                if !seen.contains(name) && !self.parameters.contains(name) && !mentions(value, name)
+                {
+                    self.inline.insert(name.clone());
+                }
+            }
+            assigned(node, &mut seen);
+        }
+        self.mutable = counts
+            .into_iter()
+            .filter(|(name, count)| *count > 1 && self.inline.contains(name))
+            .map(|(name, _)| name)
+            .collect();
+        let locals: Vec<(String, Type)> = self
+            .types
+            .iter()
+            .filter(|(name, _)| !self.parameters.contains(name) && !self.inline.contains(*name))
+            .map(|(name, kind)| (name.clone(), *kind))
+            .collect();
+        for (name, kind) in locals {
+            let initial = match kind {
+                Type::Number => "0.0",
+                Type::Bool => "false",
+            };
+            self.line();
+            self.out.push_str("#[allow(unused_assignments)]\n");
+            self.line();
+            self.out.push_str(&format!(
+                "let mut {}: {} = {};\n",
+                name,
+                kind.name(),
+                initial
+            ));
+        }
+    }
+
+    fn kind(&self, node: &Node) -> Result<Type, String> {
+        match node {
+            Node::Constant(Value::Number(_)) => Ok(Type::Number),
+            Node::Constant(Value::Bool(_)) => Ok(Type::Bool),
+            Node::Constant(Value::Quantity(_, _)) => {
+                Err("quantities with units have no Rust value".to_string())
+            }
+            Node::BinaryOperation(operation, _, _) if operation.is_arithmetic() => Ok(Type::Number),
+            Node::BinaryOperation(_, _, _) => Ok(Type::Bool),
+            Node::Variable(name, _) => {
+                self.types.get(name).cloned().ok_or_else(|| {
+                    format!("{} isn't a parameter or local, Rust can't see it", name)
+                })
+            }
+            Node::Call(name, _) => Ok(self.returns.get(name).cloned().unwrap_or(Type::Number)),
+            Node::IfElse(_, if_body, Some(_)) => self.kind(last(if_body)),
+            _ => Err("the value of a statement can't be used".to_string()),
+        }
+    }
+
+    fn line(&mut self) {
+        self.out.push_str(&"    ".repeat(self.indent));
+    }
+
+    // With value set the last statement gives the value of the block
+    fn block(&mut self, body: &Node, value: bool) -> Result<(), String> {
+        let body: Vec<&Node> = match body {
+            Node::Block(body) => body.iter().map(|node| &**node).collect(),
+            node => vec![node],
+        };
+        self.out.push_str("{\n");
+        self.indent += 1;
+        // A function body declares its locals, main does that itself
+        if self.indent == 1 {
+            self.declarations(&body);
+        }
+        for (index, node) in body.iter().enumerate() {
+            self.line();
+            if value && index + 1 == body.len() {
+                self.expression(node)?;
+                self.out.push('\n');
+            } else {
+                self.statement(node)?;
+            }
+        }
+        self.indent -= 1;
+        self.line();
+        self.out.push('}');
+        Ok(())
+    }
+
+    fn statement(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Assignment(name, _, value) if self.inline.remove(name) => {
+                let binding = match self.mutable.contains(name) {
+                    true => "let mut",
+                    false => "let",
+                };
+                let kind = self.types[name].name();
+                self.out
+                    .push_str(&format!("{} {}: {} = ", binding, name, kind));
+                self.expression(value)?;
+                self.out.push_str(";\n");
+            }
+            Node::Assignment(name, _, value) => {
+                self.out.push_str(&format!("{} = ", name));
+                self.expression(value)?;
+                self.out.push_str(";\n");
+            }
+            Node::IfElse(condition, if_body, else_body) => {
+                self.out.push_str("if ");
+                self.condition(condition)?;
+                self.out.push(' ');
+                self.block(if_body, false)?;
+                if let Some(else_body) = else_body {
+                    self.out.push_str(" else ");
+                    self.block(else_body, false)?;
+                }
+                self.out.push('\n');
+            }
+            Node::While(condition, body) => {
+                self.out.push_str("while ");
+                self.condition(condition)?;
+                self.out.push(' ');
+                self.block(body, false)?;
+                self.out.push('\n');
+            }
+            Node::For(init, condition, body, step) => {
+                self.statement(init)?;
+                self.line();
+                self.out.push_str("while ");
+                self.condition(condition)?;
+                let body = match &**body {
+                    Node::Block(body) => body.clone(),
+                    body => vec![std::rc::Rc::new(body.clone())],
+                };
+                let body = Node::Block(body.into_iter().chain(Some(step.clone())).collect());
+                self.out.push(' ');
+                self.block(&body, false)?;
+                self.out.push('\n');
+            }
+            Node::Function(name, _) => {
+                return Err(format!("{} has to be defined at the top level", name))
+            }
+            node => {
+                self.expression(node)?;
+                self.out.push_str(";\n");
+            }
+        }
+        Ok(())
+    }
+
+    // Scripts treat a number as true when it is zero
+    fn condition(&mut self, node: &Node) -> Result<(), String> {
+        match self.kind(node)? {
+            Type::Bool => self.expression(node),
+            Type::Number => {
+                self.out.push('(');
+                self.expression(node)?;
+                self.out.push_str(" == 0.0)");
+                Ok(())
+            }
+        }
+    }
+
+    fn expression(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Constant(Value::Number(number)) => self.out.push_str(&literal(*number)),
+            Node::Constant(Value::Bool(boolean)) => self.out.push_str(&boolean.to_string()),
+            Node::Constant(Value::None) => return Err("None has no Rust value".to_string()),
+            Node::Constant(Value::Quantity(_, _)) => {
+                return Err("quantities with units have no Rust value".to_string())
+            }
+            Node::Variable(name, _) => {
+                self.kind(node)?;
+                self.out.push_str(name);
+            }
+            Node::BinaryOperation(operation, left_node, right_node) => {
+                self.out.push('(');
+                self.expression(left_node)?;
+                self.out.push_str(&format!(" {} ", operation));
+                self.expression(right_node)?;
+                self.out.push(')');
+            }
+            Node::Call(name, parameters) if !self.returns.contains_key(name) => {
+                match (name.as_str(), parameters.as_slice()) {
+                    // Rounds like the script does, half away from zero
+                    ("round", [value, digits]) | ("round_to", [value, digits]) => {
+                        self.out.push_str("{ let scale = 10f64.powi(");
+                        self.expression(digits)?;
+                        self.out.push_str(" as i32); (");
+                        self.expression(value)?;
+                        self.out.push_str(" * scale).round() / scale }");
+                    }
+                    (name, [value]) => {
+                        let function = match name {
+                            "round" => "f64::round",
+                            "deg" => "f64::to_degrees",
+                            "rad" => "f64::to_radians",
+                            "sin" => "f64::sin",
+                            "cos" => "f64::cos",
+                            "tan" => "f64::tan",
+                            "asin" => "f64::asin",
+                            "acos" => "f64::acos",
+                            "atan" => "f64::atan",
+                            _ => return Err(format!("{} function is not defined", name)),
+                        };
+                        self.out.push_str(&format!("{}(", function));
+                        self.expression(value)?;
+                        self.out.push(')');
+                    }
+                    (name, _) => return Err(format!("{} function is not defined", name)),
+                }
+            }
+            Node::Call(name, parameters) => {
+                self.out.push_str(&format!("{}(", mangle(name)));
+                for (index, parameter) in parameters.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(parameter)?;
+                }
+                self.out.push(')');
+            }
+            Node::IfElse(condition, if_body, Some(else_body)) => {
+                self.out.push_str("if ");
+                self.condition(condition)?;
+                self.out.push(' ');
+                self.block(if_body, true)?;
+                self.out.push_str(" else ");
+                self.block(else_body, true)?;
+            }
+            _ => return Err("the value of a statement can't be used".to_string()),
+        }
+        Ok(())
+    }
+}
+
+// Translates a parsed program into JavaScript for node or a browser. Names
+// assigned at the top level are globals declared up front, any other name a
+// function assigns is local to it. Top level expressions are logged.
+pub fn javascript(program: &[(usize, Node)]) -> Result<String, String> {
+    let mut globals = BTreeSet::new();
+    for (_, node) in program.iter() {
+        if let Node::Function(_, _) = node {
+            continue;
+        }
+        assigned(node, &mut globals);
+    }
+    let mut script = Script {
+        out: String::new(),
+        indent: 0,
+        globals,
+        defined: defined(program),
+        valueless: program
+            .iter()
+            .filter_map(|(_, node)| match node {
+                Node::Function(name, function) if !has_value(last(&function.body)) => {
+                    Some(name.clone())
+                }
+                _ => None,
+            })
+            .collect(),
+        uses_truth: false,
+        uses_round: false,
+    };
+    for (line, node) in program.iter() {
+        script
+            .top_level(node)
+            .map_err(|error| format!("{}: {}", line, error))?;
+    }
+    let mut prelude = String::new();
+    if !script.globals.is_empty() {
+        let globals: Vec<&str> = script.globals.iter().map(String::as_str).collect();
+        prelude.push_str(&format!("let {};\n", globals.join(", ")));
+    }
+    if script.uses_truth {
+        prelude.push_str("const isTrue = (value) => value === true || value === 0;\n");
+    }
+    if script.uses_round {
+        prelude.push_str(
+            "const roundTo = (value, digits = 0) => \
+             Math.sign(value) * Math.round(Math.abs(value) * 10 ** digits) / 10 ** digits;\n",
+        );
+    }
+    if !prelude.is_empty() {
+        prelude.push('\n');
+    }
+    Ok(prelude + &script.out)
+}
+
+// Counts how often a scope assigns each name
+fn assignments(node: &Node, counts: &mut BTreeMap<String, usize>) {
+    match node {
+        Node::Assignment(name, _, _) => *counts.entry(name.clone()).or_default() += 1,
+        Node::Block(body) => body.iter().for_each(|node| assignments(node, counts)),
+        Node::IfElse(_, if_body, else_body) => {
+            assignments(if_body, counts);
+            else_body.iter().for_each(|body| assignments(body, counts));
+        }
+        Node::While(_, body) => assignments(body, counts),
+        Node::For(init, _, body, step) => {
+            assignments(init, counts);
+            assignments(body, counts);
+            assignments(step, counts);
+        }
+        _ => {}
+    }
+}
+
+fn assigned(node: &Node, names: &mut BTreeSet<String>) {
+    let mut counts = BTreeMap::new();
+    assignments(node, &mut counts);
+    names.extend(counts.into_keys());
+}
+
+// Whether an expression reads a name
+fn mentions(node: &Node, name: &str) -> bool {
+    match node {
+        Node::Variable(variable, _) => variable == name,
+        Node::BinaryOperation(_, left, right) => mentions(left, name) || mentions(right, name),
+        Node::Call(_, nodes) | Node::Block(nodes) => nodes.iter().any(|node| mentions(node, name)),
+        Node::Assignment(_, _, value) => mentions(value, name),
+        Node::IfElse(condition, if_body, else_body) => {
+            mentions(condition, name)
+                || mentions(if_body, name)
+                || else_body.iter().any(|body| mentions(body, name))
+        }
+        _ => false,
+    }
+}
+
+// Whether evaluating a statement gives a value the REPL would print
+fn has_value(node: &Node) -> bool {
+    match node {
+        Node::Constant(Value::None)
+        | Node::Assignment(_, _, _)
+        | Node::Function(_, _)
+        | Node::While(_, _)
+        | Node::For(_, _, _, _)
+        | Node::IfElse(_, _, None) => false,
+        Node::IfElse(_, if_body, Some(else_body)) => {
+            has_value(last(if_body)) && has_value(last(else_body))
+        }
+        Node::Block(body) => !body.is_empty() && has_value(last(node)),
+        _ => true,
+    }
+}
+
+struct Script {
+    out: String,
+    indent: usize,
+    globals: BTreeSet<String>,
+    defined: BTreeSet<String>,
+    // Functions whose last statement gives no value, they return undefined
+    valueless: BTreeSet<String>,
+    // Set once a condition needs the isTrue helper, or a call the roundTo one
+    uses_truth: bool,
+    uses_round: bool,
+}
+
+impl Script {
+    fn top_level(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Function(name, function) => self.function(name, function),
+            Node::Call(name, _) if self.valueless.contains(name) => self.statement(node),
+            node if has_value(node) => {
+                self.out.push_str("console.log(");
+                self.expression(node)?;
+                self.out.push_str(");\n");
+                Ok(())
+            }
+            node => self.statement(node),
+        }
+    }
+
+    fn function(&mut self, name: &str, function: &Function) -> Result<(), String> {
+        self.out.push_str(&format!(
+            "function {}({}) {{\n",
+            mangle(name),
+            function.parameters.join(", ")
+        ));
+        self.indent += 1;
+        let mut locals = BTreeSet::new();
+        assigned(&function.body, &mut locals);
+        let locals: Vec<String> = locals
+            .into_iter()
+            .filter(|name| !self.globals.contains(name) && !function.parameters.contains(name))
+            .collect();
+        if !locals.is_empty() {
+            self.line();
+            self.out.push_str(&format!("let {};\n", locals.join(", ")));
+        }
+        self.tail(&function.body)?;
+        self.indent -= 1;
+        self.out.push_str("}\n");
+        Ok(())
+    }
+
+    fn line(&mut self) {
+        self.out.push_str(&"    ".repeat(self.indent));
+    }
+
+    // Emits statements whose last value is returned
+    fn tail(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Block(body) => {
+                for (index, node) in body.iter().enumerate() {
+                    match index + 1 == body.len() {
+                        true => self.tail(node)?,
+                        false => {
+                            self.line();
+                            self.statement(node)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Node::IfElse(condition, if_body, else_body) => {
+                self.line();
+                self.out.push_str("if ");
+                self.condition(condition)?;
+                self.out.push_str(" {\n");
+                self.indent += 1;
+                self.tail(if_body)?;
+                self.indent -= 1;
+                self.line();
+                self.out.push('}');
+                if let Some(else_body) = else_body {
+                    self.out.push_str(" else {\n");
+                    self.indent += 1;
+                    self.tail(else_body)?;
+                    self.indent -= 1;
+                    self.line();
+                    self.out.push('}');
+                }
+                self.out.push('\n');
+                Ok(())
+            }
+            Node::Assignment(_, _, _)
+            | Node::While(_, _)
+            | Node::For(_, _, _, _)
+            | Node::Function(_, _) => {
+                self.line();
+                self.statement(node)
+            }
+            node => {
+                self.line();
+                self.out.push_str("return ");
+                self.expression(node)?;
+                self.out.push_str(";\n");
+                Ok(())
+            }
+        }
+    }
+
+    fn block(&mut self, body: &Node) -> Result<(), String> {
+        let body: Vec<&Node> = match body {
+            Node::Block(body) => body.iter().map(|node| &**node).collect(),
+            node => vec![node],
+        };
+        self.out.push_str("{\n");
+        self.indent += 1;
+        for node in body {
+            self.line();
+            self.statement(node)?;
+        }
+        self.indent -= 1;
+        self.line();
+        self.out.push('}');
+        Ok(())
+    }
+
+    fn statement(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Assignment(name, _, value) => {
+                self.out.push_str(&format!("{} = ", name));
+                self.expression(value)?;
+                self.out.push_str(";\n");
+            }
+            Node::IfElse(condition, if_body, else_body) => {
+                self.out.push_str("if ");
+                self.condition(condition)?;
+                self.out.push(' ');
+                self.block(if_body)?;
+                if let Some(else_body) = else_body {
+                    self.out.push_str(" else ");
+                    self.block(else_body)?;
+                }
+                self.out.push('\n');
+            }
+            Node::While(condition, body) => {
+                self.out.push_str("while ");
+                self.condition(condition)?;
+                self.out.push(' ');
+                self.block(body)?;
+                self.out.push('\n');
+            }
+            Node::For(init, condition, body, step) => {
+                self.statement(init)?;
+                self.line();
+                self.out.push_str("while ");
+                self.condition(condition)?;
+                let body = match &**body {
+                    Node::Block(body) => body.clone(),
+                    body => vec![std::rc::Rc::new(body.clone())],
+                };
+                let body = Node::Block(body.into_iter().chain(Some(step.clone())).collect());
+                self.out.push(' ');
+                self.block(&body)?;
+                self.out.push('\n');
+            }
+            Node::Function(name, _) => {
+                return Err(format!("{} has to be defined at the top level", name))
+            }
+            node => {
+                self.expression(node)?;
+                self.out.push_str(";\n");
+            }
+        }
+        Ok(())
+    }
+
+    // Comparisons are booleans already and come with their brackets, anything
+    // else may be a number that counts as true when it is zero
+    fn condition(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::BinaryOperation(operation, _, _) if !operation.is_arithmetic() => {
+                self.expression(node)
+            }
+            Node::Constant(Value::Bool(boolean)) => {
+                self.out.push_str(&format!("({})", boolean));
+                Ok(())
+            }
+            node => {
+                self.uses_truth = true;
+                self.out.push_str("(isTrue(");
+                self.expression(node)?;
+                self.out.push_str("))");
+                Ok(())
+            }
+        }
+    }
+
+    fn expression(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Constant(Value::Number(number)) => self.out.push_str(&match number {
+                number if number.is_nan() => "NaN".to_string(),
+                number if number.is_infinite() && *number > 0.0 => "Infinity".to_string(),
+                number if number.is_infinite() => "-Infinity".to_string(),
+                number if *number < 0.0 => format!("({})", number),
+                number => number.to_string(),
+            }),
+            Node::Constant(Value::Bool(boolean)) => self.out.push_str(&boolean.to_string()),
+            Node::Constant(Value::None) => self.out.push_str("undefined"),
+            Node::Constant(Value::Quantity(_, _)) => {
+                return Err("quantities with units have no JavaScript value".to_string())
+            }
+            Node::Variable(name, _) => self.out.push_str(name),
+            Node::BinaryOperation(operation, left_node, right_node) => {
+                let operator = match operation {
+                    Operation::Equal => "===".to_string(),
+                    Operation::NotEqual => "!==".to_string(),
+                    operation => operation.to_string(),
+                };
+                self.out.push('(');
+                self.expression(left_node)?;
+                self.out.push_str(&format!(" {} ", operator));
+                self.expression(right_node)?;
+                self.out.push(')');
+            }
+            Node::Call(name, parameters) if !self.defined.contains(name) => {
+                let function = match (name.as_str(), parameters.len()) {
+                    // Math.round rounds halves up, the script away from zero
+                    ("round", 1) | ("round", 2) | ("round_to", 2) => {
+                        self.uses_round = true;
+                        "roundTo"
+                    }
+                    ("deg", 1) => "((radians) => radians * 180 / Math.PI)",
+                    ("rad", 1) => "((degrees) => degrees * Math.PI / 180)",
+                    ("sin", 1) => "Math.sin",
+                    ("cos", 1) => "Math.cos",
+                    ("tan", 1) => "Math.tan",
+                    ("asin", 1) => "Math.asin",
+                    ("acos", 1) => "Math.acos",
+                    ("atan", 1) => "Math.atan",
+                    (name, _) => return Err(format!("{} function is not defined", name)),
+                };
+                self.out.push_str(&format!("{}(", function));
+                for (index, parameter) in parameters.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(parameter)?;
+                }
+                self.out.push(')');
+            }
+            Node::Call(name, parameters) => {
+                self.out.push_str(&format!("{}(", mangle(name)));
+                for (index, parameter) in parameters.iter().enumerate() {
+                    if index > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expression(parameter)?;
+                }
+                self.out.push(')');
+            }
+            // An if with a value becomes a function that is called right away
+            Node::IfElse(_, _, Some(_)) => {
+                self.out.push_str("(() => {\n");
+                self.indent += 1;
+                self.tail(node)?;
+                self.indent -= 1;
+                self.line();
+                self.out.push_str("})()");
+            }
+            _ => return Err("the value of a statement can't be used".to_string()),
+        }
+        Ok(())
+    }
+}
+
+// Names of the functions a program defines. Calls to any other name are
+// builtins, trigonometry is emitted for angles in radians, the default.
+fn defined(program: &[(usize, Node)]) -> BTreeSet<String> {
+    program
+        .iter()
+        .filter_map(|(_, node)| match node {
+            Node::Function(name, _) => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn last(body: &Node) -> &Node {
+    match body {
+        Node::Block(body) if !body.is_empty() => &body[body.len() - 1],
+        body => body,
+    }
+}
+
+// Neither language allows :: in a function name, math::sqrt becomes math__sqrt
+fn mangle(name: &str) -> String {
+    name.replace("::", "__")
+}
+
+fn literal(number: f32) -> String {
+    let text = number.to_string();
+    let text = match text.contains('.') || !number.is_finite() {
+        true => text,
+        false => text + ".0",
+    };
+    match number {
+        number if number.is_nan() => "f64::NAN".to_string(),
+        number if number.is_infinite() && number > 0.0 => "f64::INFINITY".to_string(),
+        number if number.is_infinite() => "f64::NEG_INFINITY".to_string(),
+        number if number < 0.0 => format!("({})", text),
+        _ => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::{javascript, rust};
+    use crate::parser::program;
+    use std::process::Command;
+
+    #[test]
+    fn emits_rust() {
+        let source = "fn fib(a) { if a < 2 { a; } else { fib(a-1) + fib(a-2); }; }\n\
+                      s = 0\n\
+                      for i = 0; i < 3; i = i + 1 { s = s + fib(i); }\n\
+                      s * 2";
+        let emitted = rust(&program(source).unwrap()).unwrap();
+        assert_eq!(
+            emitted,
+            "fn fib(a: f64) -> f64 {\n    \
+             if (a < 2.0) {\n        a\n    } else {\n        \
+             (fib((a - 1.0)) + fib((a - 2.0)))\n    }\n}\n\n\
+             fn main() {\n    #[allow(unused_assignments)]\n    let mut i: f64 = 0.0;\n    \
+             let mut s: f64 = 0.0;\n    i = 0.0;\n    while (i < 3.0) {\n        \
+             s = (s + fib(i));\n        i = (i + 1.0);\n    }\n    \
+             println!(\"{}\", (s * 2.0));\n}\n"
+        );
+    }
+
+    #[test]
+    fn calls_return_what_functions_return() {
+        let source = "fn pos(a: number) -> bool { a > 0; }\n\
+                      fn wrong(a) -> bool { a; }\n\
+                      x = 1\n\
+                      if pos(x) { x = 2; }";
+        let error = rust(&program(source).unwrap()).unwrap_err();
+        assert_eq!(
+            error,
+            "2: wrong returns f64 but is annotated to return bool"
+        );
+        let source = source.replace("fn wrong(a) -> bool { a; }\n", "") + "\nflag = pos(x)";
+        let emitted = rust(&program(&source).unwrap()).unwrap();
+        assert!(emitted.contains("fn pos(a: f64) -> bool {"));
+        assert!(emitted.contains("let flag: bool = pos(x);"));
+        assert!(emitted.contains("    if pos(x) {\n"));
+    }
+
+    #[test]
+    fn rejects_globals_in_functions() {
+        let source = "scale = 2\nfn f(a) { a * scale; }";
+        let error = rust(&program(source).unwrap()).unwrap_err();
+        assert_eq!(
+            error,
+            "2: scale isn't a parameter or local, Rust can't see it"
+        );
+    }
+
+    #[test]
+    fn emits_javascript() {
+        let source = "fn fib(a) { if a < 2 { a; } else { fib(a-1) + fib(a-2); }; }\n\
+                      s = 0\n\
+                      for i = 0; i < 3; i = i + 1 { s = s + fib(i); }\n\
+                      fn sign(a) { if a { t = 1; } else { t = 0 - 1; }; t; }\n\
+                      s * sign(2)";
+        let emitted = javascript(&program(source).unwrap()).unwrap();
+        assert_eq!(
+            emitted,
+            "let i, s;\n\
+             const isTrue = (value) => value === true || value === 0;\n\n\
+             function fib(a) {\n    if (a < 2) {\n        return a;\n    } else {\n        \
+             return (fib((a - 1)) + fib((a - 2)));\n    }\n}\n\
+             s = 0;\ni = 0;\nwhile (i < 3) {\n    s = (s + fib(i));\n    i = (i + 1);\n}\n\
+             function sign(a) {\n    let t;\n    if (isTrue(a)) {\n        t = 1;\n    \
+             } else {\n        t = (0 - 1);\n    }\n    return t;\n}\n\
+             console.log((s * sign(2)));\n"
+        );
+    }
+
+    #[test]
+    fn logs_top_level_values() {
+        let source = "fn f(a) { a * 2; }\n\
+                      fn g(a) { b = a; }\n\
+                      f(3)\n\
+                      g(3)\n\
+                      if f(1) { 5; } else { 6; }";
+        let emitted = javascript(&program(source).unwrap()).unwrap();
+        assert!(emitted.contains("\nconsole.log(f(3));\ng(3);\nconsole.log((() => {\n"));
+    }
+
+    #[test]
+    fn emits_builtins() {
+        let source = "fn cos(a) { a; }\nx = round(sin(1), 2) + cos(deg(1))";
+        let parsed = program(source).unwrap();
+        let emitted = rust(&parsed).unwrap();
+        assert!(emitted.contains(
+            "let x: f64 = ({ let scale = 10f64.powi(2.0 as i32); (f64::sin(1.0) * scale).round() / scale } \
+             + cos(f64::to_degrees(1.0)));"
+        ));
+        let emitted = javascript(&parsed).unwrap();
+        assert!(emitted.contains("const roundTo = "));
+        assert!(emitted.contains(
+            "x = (roundTo(Math.sin(1), 2) + cos(((radians) => radians * 180 / Math.PI)(1)));"
+        ));
+        let error = rust(&program("tan(1, 2)").unwrap()).unwrap_err();
+        assert_eq!(error, "1: tan function is not defined");
+    }
+
+    #[test]
+    fn emitted_rust_compiles() {
+        let source = "x = 1\n\
+                      if x > 0 { y = 1; }\n\
+                      y\n\
+                      fn f(a) { a * 2; }\n\
+                      f(3)\n\
+                      if x > 0 { 5; } else { 6; }\n\
+                      if x > 0 { x = 2; } else { x = 3; }\n\
+                      x";
+        let emitted = rust(&program(source).unwrap()).unwrap();
+        let directory = std::env::temp_dir().join(format!("sp-codegen-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("main.rs");
+        std::fs::write(&path, &emitted).unwrap();
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let compiled = Command::new(rustc)
+            .args([
+                "--edition",
+                "2018",
+                "-A",
+                "unused_parens",
+                "-D",
+                "warnings",
+                "-o",
+            ])
+            .arg(directory.join("main"))
+            .arg(&path)
+            .output()
+            .unwrap();
+        assert!(
+            compiled.status.success(),
+            "{}",
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+        let output = Command::new(directory.join("main")).output().unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n6\n5\n2\n");
+    }
+}
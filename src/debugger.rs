@@ -0,0 +1,76 @@
+use crate::node::Context;
+
+pub enum Resume {
+    Continue,
+    // Pause again before the next node, or instruction in the VM
+    Step,
+}
+
+// Called by either backend when it reaches a breakpoint or steps. The context
+// is the paused one, so variables can be inspected and changed in place.
+pub trait Debugger {
+    fn pause(&mut self, context: &mut Context, reason: &str) -> Resume;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::debugger::{Debugger, Resume};
+    use crate::node::{Context, Node, Value};
+    use crate::{parser, vm};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Script {
+        reasons: Vec<String>,
+    }
+
+    impl Debugger for Script {
+        fn pause(&mut self, context: &mut Context, reason: &str) -> Resume {
+            self.reasons.push(reason.to_string());
+            context.set_var("a", Value::Number(10.0));
+            Resume::Continue
+        }
+    }
+
+    #[test]
+    fn breakpoint_changes_local() {
+        let mut context = Context::default();
+        let function = parser::fixture("fn double(a) { a * 2; }");
+        Node::Function("double".to_string(), function)
+            .evaluate(&mut context)
+            .unwrap();
+
+        let script = Rc::new(RefCell::new(Script {
+            reasons: Vec::new(),
+        }));
+        context.set_debugger(Some(script.clone()));
+        context.add_breakpoint("double");
+        let call = Node::Call(
+            "double".to_string(),
+            vec![Rc::new(Node::Constant(Value::Number(1.0)))],
+        );
+        let value = call.evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 20.0);
+        assert_eq!(script.borrow().reasons, vec!["at breakpoint in double"]);
+        assert!(context.get_var("a").is_none());
+    }
+
+    #[test]
+    fn vm_pauses_at_breakpoints() {
+        let mut context = Context::default();
+        let script = Rc::new(RefCell::new(Script {
+            reasons: Vec::new(),
+        }));
+        context.set_debugger(Some(script.clone()));
+        context.add_breakpoint("double");
+        let source = "fn double(a) { a * 2; }\nx = double(1)\nbreakpoint()";
+        for (_, statement) in parser::program(source).unwrap() {
+            vm::evaluate(&statement, &mut context).unwrap();
+        }
+        assert_eq!(context.get_var("x").unwrap().to_number().unwrap(), 20.0);
+        assert_eq!(
+            script.borrow().reasons,
+            vec!["at breakpoint in double", "at breakpoint()"]
+        );
+    }
+}
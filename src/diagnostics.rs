@@ -0,0 +1,168 @@
+use crate::node::{Context, Node};
+use crate::{analysis, lint, parser};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+// Messages are only colored once the binary has found the stream they go to
+// is a terminal
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_color(color: bool) {
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+fn style(code: &'static str) -> &'static str {
+    match COLOR.load(Ordering::Relaxed) {
+        true => code,
+        false => "",
+    }
+}
+
+// Shows where parsing stopped by underlining the rest of the source from offset
+pub fn parse_error(source: &str, offset: usize, reason: &str) -> String {
+    let column = source[..offset].chars().count();
+    let width = source[offset..].trim_end().chars().count().max(1);
+    format!(
+        "{}parse error{}: {}\n  {}\n  {}{}^{}{}",
+        style(RED),
+        style(RESET),
+        reason,
+        source,
+        " ".repeat(column),
+        style(RED),
+        "~".repeat(width - 1),
+        style(RESET)
+    )
+}
+
+// Underlines the part of the source between start and end
+pub fn warning(source: &str, start: usize, end: usize, reason: &str) -> String {
+    let column = source[..start].chars().count();
+    let width = source[start..end].chars().count().max(1);
+    format!(
+        "{}warning{}: {}\n  {}\n  {}{}^{}{}",
+        style(YELLOW),
+        style(RESET),
+        reason,
+        source,
+        " ".repeat(column),
+        style(YELLOW),
+        "~".repeat(width - 1),
+        style(RESET)
+    )
+}
+
+// Warnings that don't point at a part of the source
+pub fn warning_line(reason: &str) -> String {
+    format!("{}warning{}: {}", style(YELLOW), style(RESET), reason)
+}
+
+// Problems found before evaluation that keep a statement from running
+pub fn error(reason: &str) -> String {
+    format!("{}error{}: {}", style(RED), style(RESET), reason)
+}
+
+pub fn runtime_error(error: &dyn std::error::Error) -> String {
+    format!("{}runtime error{}: {}", style(RED), style(RESET), error)
+}
+
+// What checking a program found, each with the line of its statement. Errors
+// keep a program from running, warnings are only reported.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics {
+    pub errors: Vec<(usize, String)>,
+    pub warnings: Vec<(usize, String)>,
+}
+
+impl Diagnostics {
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.warnings.is_empty()
+    }
+
+    pub fn warn(&mut self, line: usize, warning: String) {
+        if !self.warnings.contains(&(line, warning.clone())) {
+            self.warnings.push((line, warning));
+        }
+    }
+}
+
+// Parses a whole program and checks it without running anything. Statements
+// that don't parse are errors and left out of the program.
+pub fn check(source: &str, context: &Context) -> (Vec<(usize, Node)>, Diagnostics) {
+    let mut diagnostics = Diagnostics::default();
+    let mut program = Vec::new();
+    for (line, parsed) in parser::statements(source) {
+        match parsed {
+            Ok(node) => program.push((line, node)),
+            Err(error) => {
+                let prefix = format!("{}: ", line);
+                let error = error.trim_start_matches(prefix.as_str()).to_string();
+                diagnostics.errors.push((line, error));
+            }
+        }
+    }
+    diagnostics
+        .errors
+        .extend(analysis::errors(&program, context));
+    diagnostics.errors.sort_by_key(|(line, _)| *line);
+    let mut warnings = lint::unused_variables(&program, context);
+    warnings.extend(lint::unreachable_code(&program));
+    for (line, node) in program.iter() {
+        let source = source.lines().nth(line - 1).unwrap_or("");
+        for (_, warning) in lint::mixed_types(source, node) {
+            warnings.push((*line, warning));
+        }
+    }
+    warnings.sort_by_key(|(line, _)| *line);
+    for (line, warning) in warnings {
+        diagnostics.warn(line, warning);
+    }
+    (program, diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diagnostics::{check, parse_error};
+    use crate::node::Context;
+
+    #[test]
+    fn underlines_rest_of_input() {
+        let message = parse_error("x = 1 +* 2", 6, "unexpected input");
+        let lines: Vec<&str> = message.lines().collect();
+        assert_eq!(lines[1], "  x = 1 +* 2");
+        // Uncolored, the binary only turns colors on for terminals
+        assert_eq!(lines[2], "        ^~~~");
+    }
+
+    #[test]
+    fn separates_errors_from_warnings() {
+        let source = "fn f(a, b) { c = 1; a + b; }\n\
+                      f(1)\n\
+                      y = 1 +* 2\n\
+                      (1 < 2) * 3";
+        let (program, diagnostics) = check(source, &Context::default());
+        assert_eq!(program.len(), 3);
+        assert_eq!(
+            diagnostics.errors,
+            vec![
+                (2, "f function takes 2 params provided 1".to_string()),
+                (3, "can't parse y = 1 +* 2".to_string()),
+            ]
+        );
+        assert_eq!(
+            diagnostics.warnings,
+            vec![
+                (1, "c is assigned in f but never read".to_string()),
+                (4, "arithmetic on bool in 1<2*3".to_string()),
+            ]
+        );
+        assert!(diagnostics.has_errors());
+    }
+}
@@ -0,0 +1,108 @@
+// C interface to the interpreter. A context is created by sp_new_context and
+// owned by the caller until sp_free. Every function taking a context expects
+// one of those, and strings have to be NUL terminated UTF-8.
+#![allow(clippy::missing_safety_doc)]
+use crate::interpreter::Interpreter;
+use crate::node::Value;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_double, c_int};
+use std::ptr;
+
+pub struct SpContext {
+    interpreter: Interpreter,
+    // Kept so the pointer sp_last_error returns stays valid until the next eval
+    error: Option<CString>,
+}
+
+#[no_mangle]
+pub extern "C" fn sp_new_context() -> *mut SpContext {
+    Box::into_raw(Box::new(SpContext {
+        interpreter: Interpreter::builder().build(),
+        error: None,
+    }))
+}
+
+// Runs a program, returns 0 on success and 1 on an error sp_last_error describes
+#[no_mangle]
+pub unsafe extern "C" fn sp_eval(context: *mut SpContext, source: *const c_char) -> c_int {
+    let context = &mut *context;
+    let result = CStr::from_ptr(source)
+        .to_str()
+        .map_err(|error| error.into())
+        .and_then(|source| context.interpreter.eval_str(source));
+    match result {
+        Ok(_) => {
+            context.error = None;
+            0
+        }
+        Err(error) => {
+            let message = error.to_string().replace('\0', " ");
+            context.error = CString::new(message).ok();
+            1
+        }
+    }
+}
+
+// Null when the last evaluation succeeded
+#[no_mangle]
+pub unsafe extern "C" fn sp_last_error(context: *const SpContext) -> *const c_char {
+    match &(*context).error {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+// Stores the value of a numeric variable in number, returns 0 when there is
+// one and 1 when the variable is undefined or not a number
+#[no_mangle]
+pub unsafe extern "C" fn sp_get_number(
+    context: *const SpContext,
+    name: *const c_char,
+    number: *mut c_double,
+) -> c_int {
+    let value = CStr::from_ptr(name)
+        .to_str()
+        .ok()
+        .and_then(|name| (*context).interpreter.context().get_var(name));
+    match value {
+        Some(Value::Number(value)) => {
+            *number = c_double::from(value);
+            0
+        }
+        _ => 1,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sp_free(context: *mut SpContext) {
+    if !context.is_null() {
+        drop(Box::from_raw(context));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ffi::{sp_eval, sp_free, sp_get_number, sp_last_error, sp_new_context};
+    use std::ffi::{CStr, CString};
+
+    #[test]
+    fn evaluates_through_c_interface() {
+        let source = CString::new("fn twice(a) { a * 2; }\nx = twice(21)").unwrap();
+        let name = CString::new("x").unwrap();
+        let missing = CString::new("y").unwrap();
+        let broken = CString::new("y + 1").unwrap();
+        unsafe {
+            let context = sp_new_context();
+            assert_eq!(sp_eval(context, source.as_ptr()), 0);
+            assert!(sp_last_error(context).is_null());
+            let mut number = 0.0;
+            assert_eq!(sp_get_number(context, name.as_ptr(), &mut number), 0);
+            assert_eq!(number, 42.0);
+            assert_eq!(sp_get_number(context, missing.as_ptr(), &mut number), 1);
+            assert_eq!(sp_eval(context, broken.as_ptr()), 1);
+            let error = CStr::from_ptr(sp_last_error(context)).to_str().unwrap();
+            assert_eq!(error, "y is not defined");
+            sp_free(context);
+        }
+    }
+}
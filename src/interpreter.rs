@@ -0,0 +1,616 @@
+use crate::diagnostics::{self, Diagnostics};
+use crate::node::{Angles, Context, EvalError, Node, Value, DEFAULT_MAX_DEPTH};
+use crate::{optimizer, parser, resolver, vm};
+use std::future::Future;
+use std::time::Duration;
+
+// Fuel given to a sandbox that doesn't set any. Every value a script creates
+// takes a step, so this bounds memory as well as time.
+pub const DEFAULT_SANDBOX_FUEL: u64 = 10_000_000;
+
+// A Context together with how statements are evaluated in it, so the REPL and
+// embedders set up evaluation the same way
+pub struct Interpreter {
+    context: Context,
+    use_vm: bool,
+    optimize: bool,
+}
+
+pub struct Builder {
+    max_recursion: usize,
+    strict_types: bool,
+    sandbox: bool,
+    angles: Angles,
+    max_variables: Option<usize>,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    use_vm: bool,
+    optimize: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder {
+            max_recursion: DEFAULT_MAX_DEPTH,
+            strict_types: false,
+            sandbox: false,
+            angles: Angles::Radians,
+            max_variables: None,
+            fuel: None,
+            timeout: None,
+            use_vm: false,
+            optimize: true,
+        }
+    }
+}
+
+impl Builder {
+    pub fn max_recursion(&mut self, max_recursion: usize) -> &mut Builder {
+        self.max_recursion = max_recursion;
+        self
+    }
+
+    pub fn strict_types(&mut self, strict_types: bool) -> &mut Builder {
+        self.strict_types = strict_types;
+        self
+    }
+
+    // Unit of the angles sin, cos and tan take and their inverses return
+    pub fn angles(&mut self, angles: Angles) -> &mut Builder {
+        self.angles = angles;
+        self
+    }
+
+    // For untrusted scripts: builtins reaching outside of the interpreter are
+    // disabled and evaluation always runs on limited fuel
+    pub fn sandbox(&mut self, sandbox: bool) -> &mut Builder {
+        self.sandbox = sandbox;
+        self
+    }
+
+    // Globals and locals of running calls a script may have bound at once
+    pub fn max_variables(&mut self, max_variables: usize) -> &mut Builder {
+        self.max_variables = Some(max_variables);
+        self
+    }
+
+    // Evaluation steps a single statement may take
+    pub fn fuel(&mut self, fuel: u64) -> &mut Builder {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Builder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Runs statements on the bytecode VM instead of the tree walker
+    pub fn vm(&mut self, use_vm: bool) -> &mut Builder {
+        self.use_vm = use_vm;
+        self
+    }
+
+    // Folds constants and drops unreachable code before evaluating
+    pub fn optimize(&mut self, optimize: bool) -> &mut Builder {
+        self.optimize = optimize;
+        self
+    }
+
+    pub fn build(&self) -> Interpreter {
+        let mut context = Context::with_max_depth(self.max_recursion);
+        context.set_strict_types(self.strict_types);
+        context.set_sandboxed(self.sandbox);
+        context.set_angles(self.angles);
+        context.set_max_variables(self.max_variables);
+        match (self.fuel, self.sandbox) {
+            (None, true) => context.set_fuel(Some(DEFAULT_SANDBOX_FUEL)),
+            (fuel, _) => context.set_fuel(fuel),
+        }
+        context.set_timeout(self.timeout);
+        Interpreter {
+            context,
+            use_vm: self.use_vm,
+            optimize: self.optimize,
+        }
+    }
+}
+
+impl Interpreter {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+
+    pub fn register_fn<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, EvalError> + 'static,
+    {
+        self.context.register_fn(name, function);
+    }
+
+    pub fn register_async_fn<F, R>(&mut self, name: &str, function: F)
+    where
+        F: Fn(Vec<Value>) -> R + 'static,
+        R: Future<Output = Result<Value, EvalError>> + 'static,
+    {
+        self.context.register_async_fn(name, function);
+    }
+
+    // Optimizes and resolves a parsed statement, warnings about code that
+    // can't run are added to warnings
+    pub fn prepare(&self, node: &Node, warnings: &mut Vec<String>) -> Node {
+        let node = match self.optimize {
+            true => optimizer::fold_constants(node),
+            false => node.clone(),
+        };
+        // Pruning treats numbers as conditions, strict types leave that to evaluation
+        let node = match self.optimize && !self.context.strict_types() {
+            true => optimizer::eliminate_dead_code(&node, warnings),
+            false => node,
+        };
+        resolver::resolve(&node, &self.context)
+    }
+
+    // Evaluates a statement returned by prepare. The VM doesn't report the
+    // nodes it evaluates, so statements are walked while tracing.
+    pub fn run(&mut self, node: &Node) -> Result<Value, Box<dyn std::error::Error>> {
+        if self.use_vm && !self.context.trace() {
+            self.context.run(|context| vm::evaluate(node, context))
+        } else {
+            self.context.evaluate(node)
+        }
+    }
+
+    pub fn evaluate(
+        &mut self,
+        node: &Node,
+        warnings: &mut Vec<String>,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let node = self.prepare(node, warnings);
+        self.run(&node)
+    }
+
+    // Runs a whole program and returns the value of its last statement
+    pub fn eval_str(&mut self, source: &str) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut value = Value::None;
+        for (_, statement) in parser::program(source)? {
+            value = self.evaluate(&statement, &mut Vec::new())?;
+        }
+        Ok(value)
+    }
+
+    // Same as eval_str, awaiting calls of async host functions. Statements are
+    // evaluated by the tree walker even if the VM is chosen. The future holds
+    // the Rc based Context so it isn't Send, under tokio it has to run on a
+    // LocalSet or with block_on instead of tokio::spawn.
+    pub async fn eval_str_async(
+        &mut self,
+        source: &str,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut value = Value::None;
+        for (_, statement) in parser::program(source)? {
+            let statement = self.prepare(&statement, &mut Vec::new());
+            value = self.context.evaluate_async(&statement).await?;
+        }
+        Ok(value)
+    }
+
+    // Runs a whole program the way eval_str does, unless checking it finds
+    // errors. Warnings found while checking or preparing statements are
+    // returned either way.
+    pub fn eval_checked(
+        &mut self,
+        source: &str,
+    ) -> (Result<Value, Box<dyn std::error::Error>>, Diagnostics) {
+        let (program, mut diagnostics) = diagnostics::check(source, &self.context);
+        if diagnostics.has_errors() {
+            let errors: Vec<String> = diagnostics
+                .errors
+                .iter()
+                .map(|(line, error)| format!("{}: {}", line, error))
+                .collect();
+            return (Err(errors.join("\n").into()), diagnostics);
+        }
+        let mut value = Value::None;
+        for (line, statement) in program {
+            let mut warnings = Vec::new();
+            let statement = self.prepare(&statement, &mut warnings);
+            for warning in warnings {
+                diagnostics.warn(line, warning);
+            }
+            value = match self.run(&statement) {
+                Ok(value) => value,
+                Err(error) => return (Err(error), diagnostics),
+            };
+        }
+        (Ok(value), diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::{Builder, Interpreter, DEFAULT_SANDBOX_FUEL};
+    use crate::node::{Angles, EvalError, Exit, Value};
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    // A builder for each backend, tests run on both the tree walker and the VM
+    fn backends() -> Vec<Builder> {
+        [false, true]
+            .iter()
+            .map(|use_vm| {
+                let mut builder = Interpreter::builder();
+                builder.vm(*use_vm);
+                builder
+            })
+            .collect()
+    }
+
+    // Polls a future until it is ready, standing in for an async runtime
+    fn block_on<F: Future>(future: F) -> F::Output {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn ignore(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, ignore, ignore, ignore);
+        let waker = unsafe { Waker::from_raw(clone(std::ptr::null())) };
+        let mut context = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    // Pending the first time it is polled, like a call waiting on the network
+    struct Later(bool);
+
+    impl Future for Later {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+            match self.0 {
+                true => Poll::Ready(()),
+                false => {
+                    self.0 = true;
+                    context.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn builder_settings_apply() {
+        let mut interpreter = Interpreter::builder().max_recursion(8).build();
+        let deep = interpreter.eval_str("fn down(n) { if n > 0 { down(n - 1); }; }\ndown(20)");
+        assert!(deep.unwrap_err().to_string().contains("8"));
+
+        let mut interpreter = Interpreter::builder().fuel(1000).vm(true).build();
+        let spin = interpreter.eval_str("i = 0\nwhile i < 1000000 { i = i + 1; }");
+        assert!(spin.unwrap_err().to_string().contains("fuel"));
+        let value = interpreter.eval_str("i + 1").unwrap();
+        assert!(value.to_number().unwrap() > 1.0);
+
+        let mut interpreter = Interpreter::builder().strict_types(true).build();
+        assert!(interpreter.eval_str("if 0 { 1; }").is_err());
+        let value = interpreter.eval_str("if 1 < 2 { 1; } else { 2; }").unwrap();
+        assert_eq!(value.to_number().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn host_functions_are_called() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            interpreter.register_fn("sqrt", |arguments: &[Value]| match arguments {
+                [Value::Number(number)] if *number >= 0.0 => Ok(Value::Number(number.sqrt())),
+                _ => Err(EvalError::from("sqrt expects one non-negative number")),
+            });
+            let value = interpreter
+                .eval_str("fn sqrt(a) { a; }\nsqrt(16) + 1")
+                .unwrap();
+            assert_eq!(value.to_number().unwrap(), 5.0);
+            let error = interpreter.eval_str("sqrt(0 - 1)").unwrap_err();
+            assert_eq!(error.to_string(), "sqrt expects one non-negative number");
+        }
+    }
+
+    #[test]
+    fn traces_with_either_backend() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            let lines = Rc::new(RefCell::new(Vec::new()));
+            let traced = lines.clone();
+            interpreter
+                .context_mut()
+                .set_tracer(Some(Rc::new(move |line: &str| {
+                    traced.borrow_mut().push(line.to_string())
+                })));
+            interpreter.eval_str("x = 2").unwrap();
+            assert_eq!(
+                *lines.borrow(),
+                vec!["  2 => 2 []", "x = 2 => None [x = 2]"]
+            );
+        }
+    }
+
+    #[test]
+    fn sandbox_limits_scripts() {
+        for mut builder in backends() {
+            let mut interpreter = builder.sandbox(true).build();
+            assert_eq!(interpreter.context().fuel(), Some(DEFAULT_SANDBOX_FUEL));
+            for call in ["exit(1)", "arg(0)", "breakpoint()"].iter() {
+                let error = interpreter.eval_str(call).unwrap_err();
+                let name = &call[..call.find('(').unwrap()];
+                assert_eq!(
+                    error.to_string(),
+                    format!("{} is not available in a sandbox", name)
+                );
+            }
+
+            let mut interpreter = builder.fuel(1000).build();
+            assert!(interpreter.eval_str("while 0 == 0 { }").is_err());
+        }
+    }
+
+    #[test]
+    fn reads_script_arguments() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            let arguments = vec!["4".to_string(), "x".to_string()];
+            interpreter.context_mut().set_arguments(arguments);
+            let value = interpreter.eval_str("arg(0) * argc").unwrap();
+            assert_eq!(value, Value::Number(8.0));
+            let error = interpreter.eval_str("arg(1)").unwrap_err();
+            assert_eq!(error.to_string(), "Argument x is not a number");
+            let error = interpreter.eval_str("arg(2)").unwrap_err();
+            assert_eq!(error.to_string(), "There is no argument 2");
+        }
+    }
+
+    #[test]
+    fn exits_with_the_code() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            let error = interpreter.eval_str("exit(3)\nx = 1").unwrap_err();
+            assert_eq!(error.downcast_ref::<Exit>().unwrap().0, 3);
+            assert!(interpreter.context().get_var("x").is_none());
+            let error = interpreter.eval_str("exit(1 / 2)").unwrap_err();
+            assert_eq!(error.to_string(), "Exit code 0.5 is not an integer");
+        }
+    }
+
+    #[test]
+    fn limits_variables() {
+        for mut builder in backends() {
+            let mut interpreter = builder.max_variables(2).build();
+            interpreter
+                .context_mut()
+                .set_var("input", Value::Number(1.0));
+            let error = interpreter.eval_str("a = 1\na = 2\nb = 3").unwrap_err();
+            match error.downcast_ref::<EvalError>() {
+                Some(EvalError::TooManyVariables(2)) => {}
+                other => panic!("unexpected error {:?}", other),
+            }
+            assert!(interpreter.context().get_var("b").is_none());
+        }
+    }
+
+    #[test]
+    fn checks_annotations_on_calls() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            let source = "fn half(x: number) -> number { x / 2; }\n\
+                          fn wrong(a) -> bool { a; }\n\
+                          half(4)";
+            assert_eq!(
+                interpreter.eval_str(source).unwrap().to_number().unwrap(),
+                2.0
+            );
+            let error = interpreter.eval_str("half(1 < 2)").unwrap_err();
+            assert_eq!(error.to_string(), "half expects x to be a number, got true");
+            let error = interpreter.eval_str("wrong(1)").unwrap_err();
+            assert_eq!(error.to_string(), "wrong should return a bool, got 1");
+        }
+    }
+
+    #[test]
+    fn returns_diagnostics_with_results() {
+        let mut interpreter = Interpreter::builder().optimize(true).build();
+        let (value, diagnostics) = interpreter.eval_checked("x = 2\nif 1 > 2 { x = 3; }\nx + 1");
+        assert_eq!(value.unwrap().to_number().unwrap(), 3.0);
+        assert!(!diagnostics.has_errors());
+        assert_eq!(diagnostics.warnings.len(), 1);
+        assert_eq!(diagnostics.warnings[0].0, 2);
+
+        let (value, diagnostics) = interpreter.eval_checked("y = 1\nz = w");
+        assert_eq!(value.unwrap_err().to_string(), "2: w is not defined");
+        assert_eq!(
+            diagnostics.errors,
+            vec![(2, "w is not defined".to_string())]
+        );
+        assert!(interpreter.context().get_var("y").is_none());
+    }
+
+    #[test]
+    fn counts_steps_of_the_last_run() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            interpreter
+                .eval_str("i = 0\nwhile i < 10 { i = i + 1; }")
+                .unwrap();
+            let short = interpreter.context().steps();
+            interpreter
+                .eval_str("while i < 100 { i = i + 1; }")
+                .unwrap();
+            assert!(short > 0 && interpreter.context().steps() > 5 * short);
+        }
+    }
+
+    #[test]
+    fn dispatches_on_arity() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            let source = "fn area(r) { 3 * r * r; }\n\
+                          fn area(w, h) { w * h; }\n\
+                          area(2) + area(2, 5)";
+            let value = interpreter.eval_str(source).unwrap();
+            assert_eq!(value.to_number().unwrap(), 22.0);
+            assert_eq!(interpreter.context().overloads("area").count(), 2);
+
+            let value = interpreter
+                .eval_str("fn area(w, h) { w + h; }\narea(2, 5)")
+                .unwrap();
+            assert_eq!(value.to_number().unwrap(), 7.0);
+            let error = interpreter.eval_str("area()").unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "area function takes 1 or 2 params provided 0"
+            );
+        }
+    }
+
+    #[test]
+    fn awaits_async_host_functions() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            interpreter.register_async_fn("fetch", |arguments: Vec<Value>| async move {
+                Later(false).await;
+                match arguments.as_slice() {
+                    [Value::Number(number)] => Ok(Value::Number(number * 2.0)),
+                    _ => Err(EvalError::from("fetch expects a number")),
+                }
+            });
+            let source = "fn twice(a) { fetch(a) + 1; }\ntwice(20) + fetch(1)";
+            let value = block_on(interpreter.eval_str_async(source)).unwrap();
+            assert_eq!(value.to_number().unwrap(), 43.0);
+            let error = block_on(interpreter.eval_str_async("twice(1 < 2)")).unwrap_err();
+            assert_eq!(error.to_string(), "fetch expects a number");
+            assert_eq!(interpreter.context().depth(), 0);
+
+            let error = interpreter.eval_str("fetch(1)").unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "fetch is async, the script has to be evaluated with evaluate_async"
+            );
+        }
+    }
+
+    #[test]
+    fn rounds_numbers() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            let value = interpreter.eval_str("round(2 / 3, 2)").unwrap();
+            assert_eq!(value, Value::Number(0.67));
+            let value = interpreter.eval_str("round(1234, 0 - 2)").unwrap();
+            assert_eq!(value, Value::Number(1200.0));
+            let value = interpreter.eval_str("round_to(2 / 3, 2)").unwrap();
+            assert_eq!(value, Value::Number(0.67));
+            let error = interpreter.eval_str("round(1, 0.5)").unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "round expects a number and optionally a whole number of digits"
+            );
+            let value = interpreter.eval_str("round(2.5) + 0.125").unwrap();
+            assert_eq!(format!("{:.2}", value), "3.12");
+            let value = interpreter
+                .eval_str("fn round(x, digits) { x; }\nround(0.125, 1)")
+                .unwrap();
+            assert_eq!(value, Value::Number(0.125));
+        }
+    }
+
+    #[test]
+    fn trigonometry_follows_angles() {
+        for mut builder in backends() {
+            let mut interpreter = builder.angles(Angles::Degrees).build();
+            let value = interpreter
+                .eval_str("round(sin(30), 4) + deg(rad(90))")
+                .unwrap();
+            assert_eq!(value, Value::Number(90.5));
+            let value = interpreter.eval_str("round(atan(1), 4)").unwrap();
+            assert_eq!(value, Value::Number(45.0));
+
+            interpreter.context_mut().set_angles(Angles::Radians);
+            let value = interpreter.eval_str("round(cos(0) + atan(1), 4)").unwrap();
+            assert_eq!(value, Value::Number(1.7854));
+            let error = interpreter.eval_str("tan(1 < 2)").unwrap_err();
+            assert_eq!(error.to_string(), "tan expects a number");
+        }
+    }
+
+    #[test]
+    fn computes_with_units() {
+        for builder in backends() {
+            let mut interpreter = builder.build();
+            let value = interpreter.eval_str("x = 3 m\nx / 2 s").unwrap();
+            assert_eq!(value.to_string(), "1.5 m/s");
+            let value = interpreter.eval_str("(4 m) / (2 m) + 1").unwrap();
+            assert_eq!(value, Value::Number(3.0));
+            let value = interpreter
+                .eval_str("(2 kg*m/s^2 * 3 s) < 7 kg*m/s")
+                .unwrap();
+            assert_eq!(value, Value::Bool(true));
+            let error = interpreter.eval_str("x + 3 s").unwrap_err();
+            assert_eq!(error.to_string(), "Can't add m and s");
+            let error = interpreter.eval_str("x < 1").unwrap_err();
+            assert_eq!(error.to_string(), "Can't compare m with a number");
+        }
+    }
+
+    // Records the names of the spans opened while it is the default subscriber
+    #[cfg(feature = "tracing")]
+    struct Spans(std::sync::Mutex<Vec<&'static str>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Spans {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut names = self.0.lock().unwrap();
+            names.push(span.metadata().name());
+            tracing::span::Id::from_u64(names.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn opens_spans() {
+        for builder in backends() {
+            let spans = std::sync::Arc::new(Spans(Default::default()));
+            tracing::subscriber::with_default(spans.clone(), || {
+                let mut interpreter = builder.build();
+                let source = "fn twice(a) { b = a * 2; b; }\nx = twice(2)";
+                interpreter.eval_str(source).unwrap();
+            });
+            let names = spans.0.lock().unwrap();
+            for name in ["parse", "evaluate", "call", "statement"].iter() {
+                assert!(names.contains(name), "no {} span in {:?}", name, names);
+            }
+        }
+    }
+}
@@ -0,0 +1,519 @@
+use crate::node::{Context, Function, Node, Operation, Value};
+use cranelift::codegen::ir::FuncRef;
+use cranelift::prelude::{
+    settings, AbiParam, Block, FloatCC, FunctionBuilder, FunctionBuilderContext, InstBuilder,
+    IntCC, MemFlags, StackSlotData, StackSlotKind, Type, Variable,
+};
+use cranelift::prelude::{types, Configurable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+
+type NativeValue = cranelift::prelude::Value;
+
+// Compiled functions take a pointer to their f32 arguments, the number of calls
+// still allowed before hitting the recursion limit, and a status byte set to 1
+// when that limit was hit.
+type NativeFunction = extern "C" fn(*const f32, i64, *mut u8) -> f32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    None,
+    Bool,
+    Number,
+}
+
+struct Compiled {
+    code: NativeFunction,
+    // Names the function assigns to, they only stay local while no global has the same name
+    locals: Vec<String>,
+}
+
+pub struct Jit {
+    module: JITModule,
+    // Keyed by the address of the definition, None when it can't be compiled
+    compiled: BTreeMap<*const Function, (Rc<Function>, Option<Compiled>)>,
+}
+
+impl Jit {
+    pub fn new() -> Result<Jit, Box<dyn std::error::Error>> {
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false")?;
+        flags.set("is_pic", "false")?;
+        let isa = cranelift_native::builder()?.finish(settings::Flags::new(flags))?;
+        let module = JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()));
+        Ok(Jit {
+            module,
+            compiled: BTreeMap::new(),
+        })
+    }
+
+    // Runs a numeric-only function natively. None means the interpreter has to run it.
+    pub fn call(
+        &mut self,
+        name: &str,
+        function: &Rc<Function>,
+        arguments: &[Value],
+        context: &Context,
+    ) -> Option<Result<Value, Box<dyn std::error::Error>>> {
+        if function.memoized {
+            return None;
+        }
+        let numbers = arguments
+            .iter()
+            .map(Value::to_number)
+            .collect::<Option<Vec<f32>>>()?;
+        let key = Rc::as_ptr(function);
+        if !self.compiled.contains_key(&key) {
+            let compiled = self.compile(name, function);
+            self.compiled.insert(key, (function.clone(), compiled));
+        }
+        let compiled = self.compiled[&key].1.as_ref()?;
+        if compiled
+            .locals
+            .iter()
+            .any(|local| context.global(local).is_some())
+        {
+            return None;
+        }
+
+        let remaining = context.max_depth.saturating_sub(context.depth()) as i64;
+        let mut status = 0u8;
+        let value = (compiled.code)(numbers.as_ptr(), remaining, &mut status);
+        if status != 0 {
+            return Some(Err(format!(
+                "Maximum recursion depth of {} exceeded in {}",
+                context.max_depth, name
+            )
+            .into()));
+        }
+        Some(Ok(Value::Number(value)))
+    }
+
+    fn compile(&mut self, name: &str, function: &Function) -> Option<Compiled> {
+        let mut assigned = BTreeSet::new();
+        assigned_names(&function.body, &mut assigned);
+        let locals: Vec<String> = assigned
+            .into_iter()
+            .filter(|local| !function.parameters.contains(local))
+            .collect();
+
+        let pointer = self.module.target_config().pointer_type();
+        let mut ctx = self.module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(pointer));
+        ctx.func.signature.params.push(AbiParam::new(types::I64));
+        ctx.func.signature.params.push(AbiParam::new(pointer));
+        ctx.func.signature.returns.push(AbiParam::new(types::F32));
+        let symbol = format!("{}_{}", name, self.compiled.len());
+        let id = self
+            .module
+            .declare_function(&symbol, Linkage::Local, &ctx.func.signature)
+            .ok()?;
+
+        let mut builder_context = FunctionBuilderContext::new();
+        {
+            let mut builder = FunctionBuilder::new(&mut ctx.func, &mut builder_context);
+            let callee = self.module.declare_func_in_func(id, builder.func);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let arguments = builder.block_params(entry)[0];
+            let remaining = builder.block_params(entry)[1];
+            let status = builder.block_params(entry)[2];
+
+            let mut variables = BTreeMap::new();
+            for (index, parameter) in function.parameters.iter().enumerate() {
+                let variable = Variable::from_u32(variables.len() as u32);
+                builder.declare_var(variable, types::F32);
+                let value = builder.ins().load(
+                    types::F32,
+                    MemFlags::trusted(),
+                    arguments,
+                    index as i32 * 4,
+                );
+                builder.def_var(variable, value);
+                variables.insert(parameter.clone(), variable);
+            }
+            for local in locals.iter() {
+                let variable = Variable::from_u32(variables.len() as u32);
+                builder.declare_var(variable, types::F32);
+                variables.insert(local.clone(), variable);
+            }
+
+            let exit = builder.create_block();
+            let limit_hit = builder.create_block();
+            let body = builder.create_block();
+            let allowed = builder
+                .ins()
+                .icmp_imm(IntCC::SignedGreaterThan, remaining, 0);
+            builder.ins().brif(allowed, body, &[], limit_hit, &[]);
+
+            builder.switch_to_block(limit_hit);
+            let one = builder.ins().iconst(types::I8, 1);
+            builder.ins().store(MemFlags::trusted(), one, status, 0);
+            builder.ins().jump(exit, &[]);
+
+            builder.switch_to_block(exit);
+            let zero = builder.ins().f32const(0.0);
+            builder.ins().return_(&[zero]);
+
+            builder.switch_to_block(body);
+            let mut translator = Translator {
+                builder,
+                next_variable: variables.len() as u32,
+                variables,
+                assigned: function.parameters.iter().cloned().collect(),
+                name: name.to_string(),
+                arity: function.parameters.len(),
+                callee,
+                pointer,
+                remaining,
+                status,
+                exit,
+            };
+            let (kind, value) = translator.translate(&function.body)?;
+            if kind != Kind::Number {
+                return None;
+            }
+            let mut builder = translator.builder;
+            builder.ins().return_(&[value?]);
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+
+        self.module.define_function(id, &mut ctx).ok()?;
+        self.module.clear_context(&mut ctx);
+        self.module.finalize_definitions().ok()?;
+        let code = self.module.get_finalized_function(id);
+        Some(Compiled {
+            code: unsafe { std::mem::transmute::<*const u8, NativeFunction>(code) },
+            locals,
+        })
+    }
+}
+
+fn assigned_names(node: &Node, names: &mut BTreeSet<String>) {
+    match node {
+        Node::Assignment(name, _, value) => {
+            names.insert(name.clone());
+            assigned_names(value, names);
+        }
+        Node::BinaryOperation(_, left_node, right_node) => {
+            assigned_names(left_node, names);
+            assigned_names(right_node, names);
+        }
+        Node::Block(body) => body.iter().for_each(|node| assigned_names(node, names)),
+        Node::Call(_, parameters) => parameters
+            .iter()
+            .for_each(|node| assigned_names(node, names)),
+        Node::IfElse(condition, if_body, else_body) => {
+            assigned_names(condition, names);
+            assigned_names(if_body, names);
+            if let Some(else_body) = else_body {
+                assigned_names(else_body, names);
+            }
+        }
+        Node::While(condition, body) => {
+            assigned_names(condition, names);
+            assigned_names(body, names);
+        }
+        Node::For(init, condition, body, step) => {
+            assigned_names(init, names);
+            assigned_names(condition, names);
+            assigned_names(body, names);
+            assigned_names(step, names);
+        }
+        Node::Constant(_) | Node::Variable(_, _) | Node::Function(_, _) => {}
+    }
+}
+
+struct Translator<'a> {
+    builder: FunctionBuilder<'a>,
+    variables: BTreeMap<String, Variable>,
+    // Names every path to the current point has assigned, reading any other
+    // local is left to the interpreter to fail on
+    assigned: BTreeSet<String>,
+    next_variable: u32,
+    name: String,
+    arity: usize,
+    callee: FuncRef,
+    pointer: Type,
+    remaining: NativeValue,
+    status: NativeValue,
+    exit: Block,
+}
+
+impl<'a> Translator<'a> {
+    // Returns None for anything the interpreter has to handle, including
+    // operations that would be runtime errors there
+    fn translate(&mut self, node: &Node) -> Option<(Kind, Option<NativeValue>)> {
+        match node {
+            Node::Constant(Value::Number(number)) => {
+                Some((Kind::Number, Some(self.builder.ins().f32const(*number))))
+            }
+            Node::Constant(Value::Bool(boolean)) => Some((
+                Kind::Bool,
+                Some(self.builder.ins().iconst(types::I8, *boolean as i64)),
+            )),
+            Node::Constant(Value::None) => Some((Kind::None, None)),
+            Node::Constant(Value::Quantity(_, _)) => None,
+            Node::Variable(name, _) => {
+                if !self.assigned.contains(name) {
+                    return None;
+                }
+                let variable = *self.variables.get(name)?;
+                Some((Kind::Number, Some(self.builder.use_var(variable))))
+            }
+            Node::Assignment(name, _, value) => {
+                let variable = *self.variables.get(name)?;
+                match self.translate(value)? {
+                    (Kind::Number, Some(value)) => self.builder.def_var(variable, value),
+                    _ => return None,
+                }
+                self.assigned.insert(name.clone());
+                Some((Kind::None, None))
+            }
+            Node::BinaryOperation(operation, left_node, right_node) => {
+                let (left_kind, left) = self.translate(left_node)?;
+                let (right_kind, right) = self.translate(right_node)?;
+                self.operation(*operation, left_kind, left?, right_kind, right?)
+            }
+            Node::Block(body) => {
+                let mut result = (Kind::None, None);
+                for expression in body.iter() {
+                    result = self.translate(expression)?;
+                }
+                Some(result)
+            }
+            Node::IfElse(condition, if_body, else_body) => {
+                let condition = self.condition(condition)?;
+                let then_block = self.builder.create_block();
+                let else_block = self.builder.create_block();
+                let merge = self.builder.create_block();
+                self.builder
+                    .ins()
+                    .brif(condition, then_block, &[], else_block, &[]);
+
+                // Branch values go through a variable per kind and are only read
+                // back when both sides agree on the kind
+                let number = self.variable(types::F32);
+                let boolean = self.variable(types::I8);
+                let before = self.assigned.clone();
+                self.builder.switch_to_block(then_block);
+                let then_kind = self.translate_branch(if_body, number, boolean)?;
+                self.builder.ins().jump(merge, &[]);
+                let then_assigned = std::mem::replace(&mut self.assigned, before);
+                self.builder.switch_to_block(else_block);
+                let else_kind = match else_body {
+                    Some(else_body) => self.translate_branch(else_body, number, boolean)?,
+                    None => Kind::None,
+                };
+                self.builder.ins().jump(merge, &[]);
+                self.assigned.retain(|name| then_assigned.contains(name));
+
+                self.builder.switch_to_block(merge);
+                match (then_kind, else_kind) {
+                    (Kind::Number, Kind::Number) => {
+                        Some((Kind::Number, Some(self.builder.use_var(number))))
+                    }
+                    (Kind::Bool, Kind::Bool) => {
+                        Some((Kind::Bool, Some(self.builder.use_var(boolean))))
+                    }
+                    _ => Some((Kind::None, None)),
+                }
+            }
+            Node::While(condition, body) => {
+                self.translate_loop(condition, body, None)?;
+                Some((Kind::None, None))
+            }
+            Node::For(init, condition, body, step) => {
+                self.translate(init)?;
+                self.translate_loop(condition, body, Some(step))?;
+                Some((Kind::None, None))
+            }
+            Node::Call(name, parameters) => {
+                if *name != self.name || parameters.len() != self.arity {
+                    return None;
+                }
+                let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
+                    StackSlotKind::ExplicitSlot,
+                    (self.arity.max(1) * 4) as u32,
+                ));
+                for (index, parameter) in parameters.iter().enumerate() {
+                    match self.translate(parameter)? {
+                        (Kind::Number, Some(value)) => {
+                            self.builder
+                                .ins()
+                                .stack_store(value, slot, index as i32 * 4);
+                        }
+                        _ => return None,
+                    }
+                }
+                let arguments = self.builder.ins().stack_addr(self.pointer, slot, 0);
+                let remaining = self.builder.ins().iadd_imm(self.remaining, -1);
+                let call = self
+                    .builder
+                    .ins()
+                    .call(self.callee, &[arguments, remaining, self.status]);
+                let value = self.builder.inst_results(call)[0];
+
+                let status =
+                    self.builder
+                        .ins()
+                        .load(types::I8, MemFlags::trusted(), self.status, 0);
+                let next = self.builder.create_block();
+                self.builder.ins().brif(status, self.exit, &[], next, &[]);
+                self.builder.switch_to_block(next);
+                Some((Kind::Number, Some(value)))
+            }
+            Node::Function(_, _) => None,
+        }
+    }
+
+    fn variable(&mut self, kind: Type) -> Variable {
+        let variable = Variable::from_u32(self.next_variable);
+        self.next_variable += 1;
+        self.builder.declare_var(variable, kind);
+        variable
+    }
+
+    fn translate_branch(
+        &mut self,
+        body: &Node,
+        number: Variable,
+        boolean: Variable,
+    ) -> Option<Kind> {
+        match self.translate(body)? {
+            (Kind::Number, Some(value)) => {
+                self.builder.def_var(number, value);
+                Some(Kind::Number)
+            }
+            (Kind::Bool, Some(value)) => {
+                self.builder.def_var(boolean, value);
+                Some(Kind::Bool)
+            }
+            _ => Some(Kind::None),
+        }
+    }
+
+    fn translate_loop(
+        &mut self,
+        condition: &Node,
+        body: &Node,
+        step: Option<&Rc<Node>>,
+    ) -> Option<()> {
+        let header = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let after = self.builder.create_block();
+        self.builder.ins().jump(header, &[]);
+
+        self.builder.switch_to_block(header);
+        let condition = self.condition(condition)?;
+        self.builder
+            .ins()
+            .brif(condition, body_block, &[], after, &[]);
+
+        // The body may not run at all, what it assigns is only known inside it
+        let before = self.assigned.clone();
+        self.builder.switch_to_block(body_block);
+        self.translate(body)?;
+        if let Some(step) = step {
+            self.translate(step)?;
+        }
+        self.builder.ins().jump(header, &[]);
+        self.assigned = before;
+
+        self.builder.switch_to_block(after);
+        Some(())
+    }
+
+    // Mirrors is_true: booleans as they are, numbers are true when equal to zero
+    fn condition(&mut self, condition: &Node) -> Option<NativeValue> {
+        match self.translate(condition)? {
+            (Kind::Bool, Some(value)) => Some(value),
+            (Kind::Number, Some(value)) => {
+                let zero = self.builder.ins().f32const(0.0);
+                Some(self.builder.ins().fcmp(FloatCC::Equal, value, zero))
+            }
+            _ => None,
+        }
+    }
+
+    fn operation(
+        &mut self,
+        operation: Operation,
+        left_kind: Kind,
+        left: NativeValue,
+        right_kind: Kind,
+        right: NativeValue,
+    ) -> Option<(Kind, Option<NativeValue>)> {
+        let ins = self.builder.ins();
+        let (kind, value) = match (left_kind, right_kind, operation) {
+            (Kind::Number, Kind::Number, Operation::Plus) => (Kind::Number, ins.fadd(left, right)),
+            (Kind::Number, Kind::Number, Operation::Minus) => (Kind::Number, ins.fsub(left, right)),
+            (Kind::Number, Kind::Number, Operation::Multiply) => {
+                (Kind::Number, ins.fmul(left, right))
+            }
+            (Kind::Number, Kind::Number, Operation::Divide) => {
+                (Kind::Number, ins.fdiv(left, right))
+            }
+            (Kind::Number, Kind::Number, Operation::Less) => {
+                (Kind::Bool, ins.fcmp(FloatCC::LessThan, left, right))
+            }
+            (Kind::Number, Kind::Number, Operation::More) => {
+                (Kind::Bool, ins.fcmp(FloatCC::GreaterThan, left, right))
+            }
+            (Kind::Number, Kind::Number, Operation::Equal) => {
+                (Kind::Bool, ins.fcmp(FloatCC::Equal, left, right))
+            }
+            (Kind::Number, Kind::Number, Operation::NotEqual) => {
+                (Kind::Bool, ins.fcmp(FloatCC::NotEqual, left, right))
+            }
+            (Kind::Bool, Kind::Bool, Operation::Equal) => {
+                (Kind::Bool, ins.icmp(IntCC::Equal, left, right))
+            }
+            (Kind::Bool, Kind::Bool, Operation::NotEqual) => {
+                (Kind::Bool, ins.icmp(IntCC::NotEqual, left, right))
+            }
+            (Kind::Bool, Kind::Bool, Operation::Or) => (Kind::Bool, ins.bor(left, right)),
+            (Kind::Bool, Kind::Bool, Operation::And) => (Kind::Bool, ins.band(left, right)),
+            _ => return None,
+        };
+        Some((kind, Some(value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jit::Jit;
+    use crate::node::{Context, Value};
+    use crate::parser::fixture;
+
+    #[test]
+    fn runs_numeric_functions() {
+        let mut jit = Jit::new().unwrap();
+        let context = Context::default();
+        let sum = fixture("fn sum(n) { i = 0; s = 0; while i < n { s = s + i; i = i + 1; }; s; }");
+        let value = jit.call("sum", &sum, &[Value::Number(10.0)], &context);
+        assert_eq!(value.unwrap().unwrap(), Value::Number(45.0));
+
+        let down = fixture("fn down(n) { down(n + 1); }");
+        let error = jit.call("down", &down, &[Value::Number(1.0)], &context);
+        assert_eq!(
+            error.unwrap().unwrap_err().to_string(),
+            "Maximum recursion depth of 256 exceeded in down"
+        );
+        let unassigned = fixture("fn f(n) { if n > 0 { t = 1; }; t; }");
+        assert!(jit
+            .call("f", &unassigned, &[Value::Number(-1.0)], &context)
+            .is_none());
+        let assigned = fixture("fn g(n) { if n > 0 { t = 1; } else { t = 2; }; t; }");
+        let value = jit.call("g", &assigned, &[Value::Number(-1.0)], &context);
+        assert_eq!(value.unwrap().unwrap(), Value::Number(2.0));
+        let quantity = fixture("fn far(n) { n * 2 m; }");
+        assert!(jit
+            .call("far", &quantity, &[Value::Number(1.0)], &context)
+            .is_none());
+    }
+}
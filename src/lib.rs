@@ -0,0 +1,85 @@
+// The interpreter as a library: statements are parsed into Nodes and evaluated
+// in a Context, which keeps variables and functions between calls. The sp
+// binary is a REPL on top of it.
+pub mod analysis;
+pub mod codegen;
+pub mod debugger;
+pub mod diagnostics;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod interpreter;
+#[cfg(feature = "jit")]
+mod jit;
+pub mod lint;
+pub mod node;
+pub mod optimizer;
+pub mod parser;
+pub mod printer;
+mod profiler;
+pub mod resolver;
+pub mod typecheck;
+pub mod units;
+pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use interpreter::Interpreter;
+pub use node::{Context, EvalError, Exit, Node, Value};
+
+// Parses a single statement, all of the source has to be used
+pub fn parse(source: &str) -> Result<Node, Box<dyn std::error::Error>> {
+    match parser::parse_line(source) {
+        Ok((b"", node)) => Ok(node),
+        _ => Err(format!("can't parse {}", source).into()),
+    }
+}
+
+// Runs a whole program and returns the value of its last statement
+pub fn eval_str(source: &str, context: &mut Context) -> Result<Value, Box<dyn std::error::Error>> {
+    let mut value = Value::None;
+    for (_, statement) in parser::program(source)? {
+        let statement = resolver::resolve(&statement, context);
+        value = context.evaluate(&statement)?;
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{eval_str, parse, Context, Value};
+
+    #[test]
+    fn evaluates_in_shared_context() {
+        let mut context = Context::default();
+        let value = eval_str("fn twice(a) { a * 2; }\nx = twice(3)\nx + 1", &mut context);
+        assert_eq!(value.unwrap().to_number().unwrap(), 7.0);
+        let value = context.evaluate(&parse("twice(x)").unwrap()).unwrap();
+        assert_eq!(value.to_number().unwrap(), 12.0);
+        assert!(parse("x = ").is_err());
+    }
+
+    #[test]
+    fn seeds_and_reads_variables() {
+        let mut context = Context::default();
+        context.set_var("width", Value::Number(3.0));
+        context.set_var("height", Value::Number(4.0));
+        eval_str("area = width * height", &mut context).unwrap();
+        assert_eq!(context.get_var("area").unwrap().to_number().unwrap(), 12.0);
+        assert!(context.get_var("depth").is_none());
+        let mut names: Vec<String> = context.bindings().map(|(name, _)| name).collect();
+        names.sort();
+        assert_eq!(names, vec!["area", "height", "width"]);
+    }
+
+    #[test]
+    fn restoring_a_clone_undoes_statements() {
+        let mut context = Context::default();
+        eval_str("x = 1", &mut context).unwrap();
+        let snapshot = context.clone();
+        eval_str("x = 2\ny = 3\nfn f() { x; }", &mut context).unwrap();
+        context.restore(&snapshot);
+        assert_eq!(context.get_var("x").unwrap().to_number().unwrap(), 1.0);
+        assert!(context.get_var("y").is_none());
+        assert!(context.function("f").is_none());
+    }
+}
@@ -0,0 +1,378 @@
+use crate::node::{is_true, Context, Node};
+use crate::parser::{self, Token};
+use crate::typecheck::{operation_error, Type};
+use crate::{optimizer, resolver};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Variables assigned but never read and parameters a function never uses, with
+// the line of the statement they are in. Statements are resolved against a
+// detached copy of the context first so names are compared by slot. What a function assigns to a
+// global of the program or of the context may be read elsewhere, so it's only
+// reported at the top level.
+pub fn unused_variables(program: &[(usize, Node)], context: &Context) -> Vec<(usize, String)> {
+    let context = &context.detached();
+    let program: Vec<(usize, Node)> = program
+        .iter()
+        .map(|(line, node)| (*line, resolver::resolve(node, context)))
+        .collect();
+    let mut read = BTreeSet::new();
+    let mut globals = BTreeMap::new();
+    for (line, node) in program.iter() {
+        reads(node, &mut read);
+        if let Node::Function(_, _) = node {
+            continue;
+        }
+        assignments(node, *line, &mut globals);
+    }
+
+    let mut warnings = Vec::new();
+    for (line, node) in program.iter() {
+        let (name, function) = match node {
+            Node::Function(name, function) => (name, function),
+            _ => continue,
+        };
+        let mut used = BTreeSet::new();
+        reads(&function.body, &mut used);
+        for parameter in function.parameters.iter() {
+            if !used.contains(&context.slot(parameter)) {
+                let warning = format!("parameter {} of {} is never used", parameter, name);
+                warnings.push((*line, warning));
+            }
+        }
+        let mut locals = BTreeMap::new();
+        assignments(&function.body, *line, &mut locals);
+        for (slot, (_, local)) in locals {
+            if used.contains(&slot)
+                || globals.contains_key(&slot)
+                || function.parameters.contains(&local)
+                || context.get_var(&local).is_some()
+            {
+                continue;
+            }
+            let warning = format!("{} is assigned in {} but never read", local, name);
+            warnings.push((*line, warning));
+        }
+    }
+    for (slot, (line, name)) in globals {
+        if !read.contains(&slot) {
+            warnings.push((line, format!("{} is assigned but never read", name)));
+        }
+    }
+    warnings.sort_by_key(|(line, _)| *line);
+    warnings
+}
+
+// Code that can never run, with the line of the statement it is in: branches
+// and loops the constant folder decides, and statements after an exit() or a
+// loop whose condition is always true, since nothing else leaves a loop.
+pub fn unreachable_code(program: &[(usize, Node)]) -> Vec<(usize, String)> {
+    let mut warnings = Vec::new();
+    let mut stopped = None;
+    for (line, node) in program.iter() {
+        if let Some(stop) = stopped.take() {
+            let warning = format!("statements after {} are unreachable", stop);
+            warnings.push((*line, warning));
+            break;
+        }
+        let node = optimizer::fold_constants(node);
+        let mut found = Vec::new();
+        optimizer::eliminate_dead_code(&node, &mut found);
+        after_stops(&node, &mut found);
+        warnings.extend(found.into_iter().map(|warning| (*line, warning)));
+        stopped = stops(&node);
+    }
+    warnings
+}
+
+// Describes a statement evaluation never gets past
+fn stops(node: &Node) -> Option<String> {
+    match node {
+        Node::Call(name, parameters) if name == "exit" && parameters.len() == 1 => {
+            Some(node.to_string())
+        }
+        Node::While(condition, _) => match &**condition {
+            Node::Constant(value) if is_true(value) => Some(format!("while {}", condition)),
+            _ => None,
+        },
+        Node::For(_, condition, _, _) => match &**condition {
+            Node::Constant(value) if is_true(value) => {
+                Some(format!("for loop with condition {}", condition))
+            }
+            _ => None,
+        },
+        Node::Block(body) => body.iter().find_map(|node| stops(node)),
+        Node::IfElse(condition, if_body, Some(else_body)) => {
+            match (stops(if_body), stops(else_body)) {
+                (Some(_), Some(_)) => Some(format!("if {} with both branches", condition)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn after_stops(node: &Node, warnings: &mut Vec<String>) {
+    match node {
+        Node::Block(body) => {
+            for (index, node) in body.iter().enumerate() {
+                after_stops(node, warnings);
+                if let Some(stop) = stops(node) {
+                    if index + 1 < body.len() {
+                        warnings.push(format!("statements after {} are unreachable", stop));
+                    }
+                    break;
+                }
+            }
+        }
+        Node::Function(_, function) => after_stops(&function.body, warnings),
+        Node::IfElse(_, if_body, else_body) => {
+            after_stops(if_body, warnings);
+            else_body
+                .iter()
+                .for_each(|body| after_stops(body, warnings));
+        }
+        Node::While(_, body) => after_stops(body, warnings),
+        Node::For(_, _, body, _) => after_stops(body, warnings),
+        _ => {}
+    }
+}
+
+// Binary operations that are ill-typed by the shape of their operands alone,
+// like a comparison fed into *. That's easy to write by accident since
+// comparisons bind tightest, a < b * 2 is (a<b)*2. Each warning comes with the
+// byte range of the operation when the statement could be matched to source.
+pub fn mixed_types(source: &str, node: &Node) -> Vec<(Option<(usize, usize)>, String)> {
+    let mut found = Vec::new();
+    let mut leaves = 0;
+    shapes(node, &mut leaves, &mut found);
+    // Leaves are names and numbers, the same as those tokens in source
+    let tokens: Vec<(usize, usize)> = parser::tokens(source.as_bytes())
+        .into_iter()
+        .filter(|(token, _, _)| *token == Token::Identifier || *token == Token::Number)
+        .map(|(_, start, end)| (start, end))
+        .collect();
+    let matched = tokens.len() == leaves;
+    found
+        .into_iter()
+        .map(|(first, last, warning)| {
+            let span = match matched {
+                true => Some(widen(source, tokens[first].0, tokens[last].1)),
+                false => None,
+            };
+            (span, warning)
+        })
+        .collect()
+}
+
+fn shape(node: &Node) -> Type {
+    match node {
+        Node::Constant(value) => Type::of(value),
+        Node::BinaryOperation(operation, _, _) if operation.is_arithmetic() => Type::Number,
+        Node::BinaryOperation(_, _, _) => Type::Bool,
+        _ => Type::Unknown,
+    }
+}
+
+// Walks leaves in source order and records the first and last leaf of every
+// ill-typed operation
+fn shapes(node: &Node, leaves: &mut usize, found: &mut Vec<(usize, usize, String)>) {
+    match node {
+        Node::Constant(_) | Node::Variable(_, _) => *leaves += 1,
+        Node::BinaryOperation(operation, left_node, right_node) => {
+            let first = *leaves;
+            shapes(left_node, leaves, found);
+            shapes(right_node, leaves, found);
+            if let Some(error) = operation_error(operation, shape(left_node), shape(right_node)) {
+                found.push((first, *leaves - 1, format!("{} in {}", error, node)));
+            }
+        }
+        Node::Block(body) => body.iter().for_each(|node| shapes(node, leaves, found)),
+        Node::Assignment(_, _, value) => {
+            *leaves += 1;
+            shapes(value, leaves, found);
+        }
+        Node::Function(_, function) => {
+            // Annotations are names too
+            let annotations = function.types.iter().flatten().count();
+            *leaves += 1 + function.parameters.len() + annotations;
+            *leaves += function.returns.iter().count();
+            shapes(&function.body, leaves, found);
+        }
+        Node::Call(_, parameters) => {
+            *leaves += 1;
+            parameters
+                .iter()
+                .for_each(|node| shapes(node, leaves, found));
+        }
+        Node::IfElse(condition, if_body, else_body) => {
+            shapes(condition, leaves, found);
+            shapes(if_body, leaves, found);
+            else_body
+                .iter()
+                .for_each(|body| shapes(body, leaves, found));
+        }
+        Node::While(condition, body) => {
+            shapes(condition, leaves, found);
+            shapes(body, leaves, found);
+        }
+        Node::For(init, condition, body, step) => {
+            shapes(init, leaves, found);
+            shapes(condition, leaves, found);
+            shapes(step, leaves, found);
+            shapes(body, leaves, found);
+        }
+    }
+}
+
+// Takes in the brackets of operands at the edges of a span
+fn widen(source: &str, start: usize, end: usize) -> (usize, usize) {
+    let bytes = source.as_bytes();
+    let (mut depth, mut lowest) = (0, 0);
+    for c in bytes[start..end].iter() {
+        match c {
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            _ => {}
+        }
+        lowest = std::cmp::min(lowest, depth);
+    }
+    let (mut closed, mut opened) = (-lowest, depth - lowest);
+    let mut start = start;
+    while closed > 0 && start > 0 {
+        start -= 1;
+        match bytes[start] {
+            b'(' => closed -= 1,
+            b')' => closed += 1,
+            _ => {}
+        }
+    }
+    let mut end = end;
+    while opened > 0 && end < bytes.len() {
+        match bytes[end] {
+            b'(' => opened += 1,
+            b')' => opened -= 1,
+            _ => {}
+        }
+        end += 1;
+    }
+    (start, end)
+}
+
+fn reads(node: &Node, slots: &mut BTreeSet<usize>) {
+    match node {
+        Node::Constant(_) => {}
+        Node::Variable(_, slot) => slots.extend(*slot),
+        Node::BinaryOperation(_, left_node, right_node) => {
+            reads(left_node, slots);
+            reads(right_node, slots);
+        }
+        Node::Block(body) => body.iter().for_each(|node| reads(node, slots)),
+        Node::Assignment(_, _, value) => reads(value, slots),
+        Node::Function(_, function) => reads(&function.body, slots),
+        Node::Call(_, parameters) => parameters.iter().for_each(|node| reads(node, slots)),
+        Node::IfElse(condition, if_body, else_body) => {
+            reads(condition, slots);
+            reads(if_body, slots);
+            else_body.iter().for_each(|body| reads(body, slots));
+        }
+        Node::While(condition, body) => {
+            reads(condition, slots);
+            reads(body, slots);
+        }
+        Node::For(init, condition, body, step) => {
+            reads(init, slots);
+            reads(condition, slots);
+            reads(body, slots);
+            reads(step, slots);
+        }
+    }
+}
+
+// Keeps the line and name of the first assignment to every slot
+fn assignments(node: &Node, line: usize, slots: &mut BTreeMap<usize, (usize, String)>) {
+    match node {
+        Node::Assignment(name, Some(slot), _) => {
+            slots.entry(*slot).or_insert_with(|| (line, name.clone()));
+        }
+        Node::Block(body) => body.iter().for_each(|node| assignments(node, line, slots)),
+        Node::IfElse(_, if_body, else_body) => {
+            assignments(if_body, line, slots);
+            else_body
+                .iter()
+                .for_each(|body| assignments(body, line, slots));
+        }
+        Node::While(_, body) => assignments(body, line, slots),
+        Node::For(init, _, body, step) => {
+            assignments(init, line, slots);
+            assignments(body, line, slots);
+            assignments(step, line, slots);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lint::{mixed_types, unreachable_code, unused_variables};
+    use crate::node::{Context, Value};
+    use crate::parser::program;
+    use crate::parser::statement;
+
+    #[test]
+    fn reports_unused_variables() {
+        let source = "fn f(a, b) { t = a; c = 1; c; }\n\
+                      x = f(1, 2)\n\
+                      y = x\n\
+                      fn g() { seen = 1; }";
+        let mut context = Context::default();
+        context.set_var("seen", Value::Number(0.0));
+        let warnings = unused_variables(&program(source).unwrap(), &context);
+        assert_eq!(
+            warnings,
+            vec![
+                (1, "parameter b of f is never used".to_string()),
+                (1, "t is assigned in f but never read".to_string()),
+                (3, "y is assigned but never read".to_string()),
+            ]
+        );
+        // Linting leaves the context's slots alone, seen has the only one
+        assert_eq!(context.slot("other"), 1);
+    }
+
+    #[test]
+    fn reports_unreachable_code() {
+        let source = "fn f(a) { exit(a); a = 2; }\n\
+                      while 1 > 2 { f(1); }\n\
+                      while 0 { }\n\
+                      f(2)";
+        let warnings = unreachable_code(&program(source).unwrap());
+        assert_eq!(
+            warnings,
+            vec![
+                (1, "statements after exit(a) are unreachable".to_string()),
+                (2, "body of while false is never executed".to_string()),
+                (4, "statements after while 0 are unreachable".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn points_at_mixed_types() {
+        let source = "y = f(x) < 2 * (3 + 1)";
+        let (_, node) = statement(source.as_bytes()).unwrap();
+        let warnings = mixed_types(source, &node);
+        assert_eq!(
+            warnings,
+            vec![(
+                Some((4, 22)),
+                "arithmetic on bool in f(x)<2*(3+1)".to_string()
+            )]
+        );
+
+        let source = "y = 0 - (1 < 2)";
+        let (_, node) = statement(source.as_bytes()).unwrap();
+        assert_eq!(mixed_types(source, &node)[0].0, Some((4, 15)));
+        let (_, node) = statement(b"y = -(1 < 2)").unwrap();
+        assert_eq!(mixed_types("y = -(1 < 2)", &node)[0].0, None);
+    }
+}
@@ -1,35 +1,1030 @@
-use node::Context;
-use parser::statement;
-use rustyline;
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use rustyline::completion::Completer;
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
-mod node;
-mod parser;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::History;
+use rustyline::{Config, Editor, Helper};
+use sp::debugger::{Debugger, Resume};
+use sp::parser::{parse_line, Token};
+use sp::{analysis, codegen, diagnostics, lint, node, parser, printer, resolver, typecheck, vm};
+use sp::{Context, Exit, Interpreter, Value};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::io::{IsTerminal, Read};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Sub-prompt shown while evaluation is paused. Statements typed there are
+// evaluated in the paused frame, and watched ones are printed at every pause.
+#[derive(Default)]
+struct Prompt {
+    watches: Vec<(String, node::Node)>,
+}
+
+impl Debugger for Prompt {
+    fn pause(&mut self, context: &mut Context, reason: &str) -> Resume {
+        println!("Paused {}", reason);
+        for (source, watch) in self.watches.iter() {
+            let watch = resolver::resolve(watch, context);
+            match watch.evaluate(context) {
+                Ok(value) => println!("  {}: {}", source, value),
+                Err(error) => println!("  {}: {}", source, diagnostics::runtime_error(&*error)),
+            }
+        }
+        let mut rl = Editor::<()>::new();
+        loop {
+            let line = match rl.readline("(debug) ") {
+                Ok(line) => line,
+                Err(_) => return Resume::Continue,
+            };
+            rl.add_history_entry(line.as_str());
+            match line.trim() {
+                ":continue" => return Resume::Continue,
+                ":step" => return Resume::Step,
+                line if line.starts_with(":watch") => {
+                    let source = line[":watch".len()..].trim();
+                    match parse_line(source) {
+                        Ok((b"", watch)) => self.watches.push((source.to_string(), watch)),
+                        _ => println!("Can't watch {}", source),
+                    }
+                }
+                line if line.starts_with(":unwatch") => {
+                    let source = line[":unwatch".len()..].trim();
+                    self.watches.retain(|(watched, _)| watched != source);
+                }
+                ":vars" => {
+                    for (name, value) in context.bindings() {
+                        println!("{} = {}", name, value);
+                    }
+                }
+                line => match parse_line(line) {
+                    Ok((b"", ast)) => {
+                        let ast = resolver::resolve(&ast, context);
+                        match ast.evaluate(context) {
+                            Ok(Value::None) => {}
+                            Ok(value) => println!("{}", value),
+                            Err(error) => println!("{}", diagnostics::runtime_error(&*error)),
+                        }
+                    }
+                    _ => println!(
+                        "Expected a statement, :vars, :watch, :unwatch, :step or :continue"
+                    ),
+                },
+            }
+        }
+    }
+}
+
+const KEYWORDS: [&str; 5] = ["fn", "if", "else", "while", "for"];
+
+// Completes keywords and the names defined in a copy of the session Context,
+// which main refreshes after every statement
+struct Completion {
+    context: Rc<RefCell<Context>>,
+}
+
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        // Qualified names like math::sqrt complete as a whole
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_ascii_alphabetic() || c == '_' || c == ':'))
+            .map_or(0, |index| index + 1);
+        let prefix = line[start..pos].trim_start_matches(':');
+        let start = pos - prefix.len();
+        let context = self.context.borrow();
+        let mut names: Vec<String> = KEYWORDS
+            .iter()
+            .map(|keyword| keyword.to_string())
+            .chain(context.functions().map(|(name, _)| name.clone()))
+            .chain(context.bindings().map(|(name, _)| name))
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok((start, names))
+    }
+}
+
+impl Hinter for Completion {}
+
+// Colors tokens as the user types, brackets without a match are shown in red
+impl Highlighter for Completion {
+    fn highlight<'l>(&self, line: &'l str, _: usize) -> Cow<'l, str> {
+        let tokens = parser::tokens(line.as_bytes());
+        let bytes = line.as_bytes();
+        let mut open: Vec<usize> = Vec::new();
+        let mut unmatched = vec![false; tokens.len()];
+        for (index, (token, start, _)) in tokens.iter().enumerate() {
+            match (token, bytes[*start]) {
+                (Token::Bracket, b'{') | (Token::Bracket, b'(') => open.push(index),
+                (Token::Bracket, closing) => match open.pop() {
+                    Some(opening) if (bytes[tokens[opening].1], closing) == (b'(', b')') => {}
+                    Some(opening) if (bytes[tokens[opening].1], closing) == (b'{', b'}') => {}
+                    Some(opening) => {
+                        unmatched[opening] = true;
+                        unmatched[index] = true;
+                    }
+                    None => unmatched[index] = true,
+                },
+                _ => {}
+            }
+        }
+        for index in open {
+            unmatched[index] = true;
+        }
+
+        let mut highlighted = String::new();
+        for (index, (token, start, end)) in tokens.iter().enumerate() {
+            let color = match token {
+                _ if unmatched[index] => "\x1b[1;31m",
+                Token::Keyword => "\x1b[1;34m",
+                Token::Number => "\x1b[33m",
+                Token::Operator => "\x1b[36m",
+                _ => "",
+            };
+            if color.is_empty() {
+                highlighted.push_str(&line[*start..*end]);
+            } else {
+                highlighted.push_str(&format!("{}{}\x1b[0m", color, &line[*start..*end]));
+            }
+        }
+        Cow::Owned(highlighted)
+    }
+
+    fn highlight_char(&self, _: &str, _: usize) -> bool {
+        true
+    }
+}
+
+impl Helper for Completion {}
+
+const MAX_HISTORY: usize = 1000;
+// Statements and commands :undo can take back. Snapshots share structure
+// with the session, so each one costs little more than what its statement changed.
+const MAX_UNDO: usize = 100;
+
+// Keeps the session as it was before a statement or a command replacing it
+fn record(journal: &mut Vec<(String, Context)>, entry: &str, context: &Context) {
+    if journal.len() == MAX_UNDO {
+        journal.remove(0);
+    }
+    journal.push((entry.to_string(), context.clone()));
+}
+
+// History goes to the platform data directory, e.g. ~/.local/share/sp on Linux
+fn default_history() -> Option<PathBuf> {
+    dirs::data_dir().map(|directory| directory.join("sp").join("history.txt"))
+}
+
+#[derive(Clone, Copy)]
+enum AstFormat {
+    Tree,
+    // One statement per line, for tools reading the tree
+    Json,
+    Sexp,
+}
+
+#[derive(Default)]
+struct Options {
+    dump_bytecode: bool,
+    // Prints the parsed tree of every statement instead of evaluating it
+    dump_ast: Option<AstFormat>,
+    // Echoes the parsed tree of every statement
+    verbose: bool,
+    // Prints None results too, which assignments and definitions evaluate to
+    show_none: bool,
+    // Digits shown after the decimal point, as many as needed when not set
+    precision: Option<usize>,
+    notation: node::Notation,
+}
+
+// How the REPL prints a value with the display settings
+fn display(value: &Value, options: &Options) -> String {
+    let value = node::Notated(*value, options.notation);
+    match options.precision {
+        Some(precision) => format!("{:.*}", precision, value),
+        None => value.to_string(),
+    }
+}
+
+// Prints each traced node on a line of its own
+fn print_trace() -> node::Tracer {
+    Rc::new(|line: &str| println!("{}", line))
+}
+
+// Evaluates a parsed statement, or only prints it when asked to
+fn run(
+    ast: &node::Node,
+    interpreter: &mut Interpreter,
+    options: &Options,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    if let Some(format) = options.dump_ast {
+        match format {
+            AstFormat::Tree => print!("{}", printer::tree(ast)),
+            AstFormat::Json => println!("{}", serde_json::to_string(ast)?),
+            AstFormat::Sexp => println!("{}", printer::sexp(ast)),
+        }
+        return Ok(Value::None);
+    }
+    let mut warnings = Vec::new();
+    let ast = interpreter.prepare(ast, &mut warnings);
+    for warning in warnings {
+        println!("{}", diagnostics::warning_line(&warning));
+    }
+    if options.dump_bytecode {
+        print!("{}", vm::compile(&ast));
+    }
+    interpreter.run(&ast)
+}
+
+// Scripts hold one statement per line, the same as typed into the REPL
+fn load(
+    path: &str,
+    interpreter: &mut Interpreter,
+    options: &Options,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let script = std::fs::read_to_string(path)?;
+    execute(path, &script, interpreter, options, false)
+}
+
+// Runs a script again every time it's saved, until interrupted
+fn watch(
+    path: &str,
+    interpreter: &mut Interpreter,
+    options: &Options,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let script = std::fs::canonicalize(path)?;
+    let directory = script.parent().ok_or("the script has no directory")?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(sender, Duration::from_millis(100))?;
+    // Editors often save by replacing the file, which would end a watch on
+    // the file itself
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    rerun(path, interpreter, options);
+    for event in receiver {
+        match event {
+            DebouncedEvent::Write(changed)
+            | DebouncedEvent::Create(changed)
+            | DebouncedEvent::Rename(_, changed)
+                if changed == script =>
+            {
+                rerun(path, interpreter, options)
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Runs a script from scratch, reporting what goes wrong instead of exiting
+fn rerun(path: &str, interpreter: &mut Interpreter, options: &Options) {
+    println!("--- {}", path);
+    interpreter.context_mut().reset();
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            println!("{}: {}", path, error);
+            return;
+        }
+    };
+    let (_, found) = diagnostics::check(&source, interpreter.context());
+    for (line, warning) in found.warnings.iter() {
+        println!("{}:{}: {}", path, line, diagnostics::warning_line(warning));
+    }
+    if let Err(error) = execute(path, &source, interpreter, options, true) {
+        match error.downcast_ref::<Exit>() {
+            Some(Exit(code)) => println!("Exited with code {}", code),
+            None => println!("{}", diagnostics::runtime_error(&*error)),
+        }
+    }
+}
+
+// Ends a non-interactive run, with the code given to exit() or as a failure
+fn fail(error: Box<dyn std::error::Error>) -> ! {
+    if let Some(Exit(code)) = error.downcast_ref::<Exit>() {
+        std::process::exit(*code);
+    }
+    diagnostics::set_color(std::io::stderr().is_terminal());
+    eprintln!("{}", diagnostics::runtime_error(&*error));
+    std::process::exit(1);
+}
+
+// Runs a whole program, echo prints the results the way the REPL does
+fn execute(
+    name: &str,
+    source: &str,
+    interpreter: &mut Interpreter,
+    options: &Options,
+    echo: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let program = parser::program(source).map_err(|error| format!("{}:{}", name, error))?;
+    let found = analysis::errors(&program, interpreter.context());
+    if !found.is_empty() {
+        let errors: Vec<String> = found
+            .iter()
+            .map(|(line, error)| format!("{}:{}: {}", name, line, error))
+            .collect();
+        return Err(errors.join("\n").into());
+    }
+    for (line, ast) in program {
+        let value = run(&ast, interpreter, options).map_err(|error| match error.is::<Exit>() {
+            true => error,
+            false => format!("{}:{}: {}", name, line, error).into(),
+        })?;
+        match value {
+            Value::None if !options.show_none => {}
+            value if echo => println!("{}", display(&value, options)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Writes functions first so variables may be computed by calling them later on
+fn save(path: &str, context: &Context) -> Result<(), Box<dyn std::error::Error>> {
+    let mut script = String::new();
+    for (name, function) in context.functions() {
+        let definition = node::Node::Function(name.clone(), Rc::new(function.clone()));
+        script.push_str(&printer::source(&definition));
+        script.push('\n');
+    }
+    for (name, value) in context.bindings() {
+        if value.is_none() {
+            continue;
+        }
+        let assignment = node::Node::Assignment(name, None, Rc::new(node::Node::Constant(value)));
+        script.push_str(&printer::source(&assignment));
+        script.push('\n');
+    }
+    std::fs::write(path, script)?;
+    Ok(())
+}
+
+const HELP: &str = "\
+:help              show this list
+:history           list entries, !N runs one again and !! the last
+:undo              take back the last statement, :reset, :load or :restore
+:vars              list variables with their values
+:funcs [NAMESPACE] list defined functions, or those in a namespace
+:source NAME       show the definition of a function
+:reset             forget all variables and functions
+:quit              leave the REPL
+:load PATH         evaluate a script in this session
+:save PATH         write functions and variables to a script
+:snapshot PATH     write the whole session state as JSON
+:restore PATH      replace the session state with a snapshot
+:disasm NAME       show the bytecode of a function
+:ast STATEMENT     show how a statement is parsed
+:sexp STATEMENT    the same as an S-expression
+:time STATEMENT    evaluate and show how long it took
+:type STATEMENT    infer the type of a statement, or show a signature
+:set precision N   show N digits after the decimal point, off for all
+:set notation NAME print numbers in fixed, sci or eng notation
+:set angles UNIT   take and return angles in rad or deg
+:trace on|off      print every evaluated node
+:profile on|off    count calls and time per function
+:report            show the last profile
+:break [NAME]      pause when NAME is called, or list breakpoints
+:clear NAME        remove a breakpoint
+";
+
+// The entry !! or !N refers to, numbered from 1 the way :history lists them
+fn recall(line: &str, history: &History) -> Option<String> {
+    let index = match line {
+        "!!" => history.len().checked_sub(1)?,
+        _ => line[1..].parse::<usize>().ok()?.checked_sub(1)?,
+    };
+    history.get(index).cloned()
+}
+
+// Runs a line starting with ':', returns false when the REPL should quit
+fn command(line: &str, interpreter: &mut Interpreter, options: &mut Options) -> bool {
+    let line = line[1..].trim();
+    let (name, argument) = match line.find(' ') {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+    let context = interpreter.context_mut();
+    match name {
+        "help" => print!("{}", HELP),
+        "vars" => {
+            for (name, value) in context.bindings() {
+                println!("{} = {}", name, display(&value, options));
+            }
+        }
+        "funcs" if argument.is_empty() => {
+            for (name, function) in context.functions() {
+                println!("{}", function.signature(name));
+            }
+        }
+        "funcs" => {
+            for (name, function) in context.functions_in(argument) {
+                println!("{}", function.signature(name));
+            }
+        }
+        "source" if context.function(argument).is_some() => {
+            for function in context.overloads(argument) {
+                let definition =
+                    node::Node::Function(argument.to_string(), Rc::new(function.clone()));
+                println!("{}", definition);
+            }
+        }
+        "source" => println!("{} function is not defined", argument),
+        "reset" => context.reset(),
+        "quit" => return false,
+        "load" => {
+            if let Err(error) = load(argument, interpreter, options) {
+                println!("{}", error);
+            }
+        }
+        "save" => {
+            if let Err(error) = save(argument, context) {
+                println!("{}", error);
+            }
+        }
+        "snapshot" => {
+            let written = serde_json::to_string(context)
+                .map_err(|error| error.to_string())
+                .and_then(|json| std::fs::write(argument, json).map_err(|error| error.to_string()));
+            if let Err(error) = written {
+                println!("{}", error);
+            }
+        }
+        "restore" => {
+            let snapshot = std::fs::read_to_string(argument)
+                .map_err(|error| error.to_string())
+                .and_then(|json| {
+                    serde_json::from_str::<Context>(&json).map_err(|error| error.to_string())
+                });
+            match snapshot {
+                Ok(snapshot) => context.restore(&snapshot),
+                Err(error) => println!("{}", error),
+            }
+        }
+        "disasm" if context.function(argument).is_some() => {
+            for function in context.overloads(argument) {
+                println!("{}", function.signature(argument));
+                print!("{}", vm::compile(&function.body));
+            }
+        }
+        "disasm" => println!("{} function is not defined", argument),
+        "ast" => match parse_line(argument) {
+            Ok((b"", ast)) => print!("{}", printer::tree(&ast)),
+            _ => println!("Can't parse {}", argument),
+        },
+        "sexp" => match parse_line(argument) {
+            Ok((b"", ast)) => println!("{}", printer::sexp(&ast)),
+            _ => println!("Can't parse {}", argument),
+        },
+        "type" if context.function(argument).is_some() => {
+            for function in context.overloads(argument) {
+                println!("{}", function.signature(argument));
+            }
+        }
+        "type" => match parse_line(argument) {
+            Ok((b"", ast)) => {
+                let mut checker = typecheck::Checker::from_context(context);
+                let kind = checker.infer(&ast);
+                for error in checker.take_errors() {
+                    println!("{}", diagnostics::warning_line(&error));
+                }
+                println!("{}", kind);
+            }
+            _ => println!("Can't parse {}", argument),
+        },
+        "time" => match parse_line(argument) {
+            Ok((b"", ast)) => {
+                let start = Instant::now();
+                match run(&ast, interpreter, options) {
+                    Ok(value) => println!("{}", display(&value, options)),
+                    Err(error) => println!("{}", diagnostics::runtime_error(&*error)),
+                }
+                let steps = interpreter.context().steps();
+                println!("{:?}, {} steps", start.elapsed(), steps);
+            }
+            _ => println!("Can't parse {}", argument),
+        },
+        "set" => match argument.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["precision", "off"] => options.precision = None,
+            ["precision", digits] => match digits.parse::<usize>() {
+                Ok(digits) => options.precision = Some(digits),
+                Err(_) => println!("Expected a number of digits, got {}", digits),
+            },
+            ["notation", notation] => match notation.parse() {
+                Ok(notation) => options.notation = notation,
+                Err(error) => println!("{}", error),
+            },
+            ["angles", angles] => match angles.parse() {
+                Ok(angles) => context.set_angles(angles),
+                Err(error) => println!("{}", error),
+            },
+            _ => println!("Expected :set precision, :set notation or :set angles"),
+        },
+        "trace" => match argument {
+            "on" => context.set_tracer(Some(print_trace())),
+            "off" => context.set_tracer(None),
+            other => println!("Expected :trace on or :trace off, got {}", other),
+        },
+        "profile" => match argument {
+            "on" => context.set_profiling(true),
+            "off" => context.set_profiling(false),
+            other => println!("Expected :profile on or :profile off, got {}", other),
+        },
+        "report" => match context.profile_report() {
+            Some(report) => print!("{}", report),
+            None => println!("Profiling was never turned on"),
+        },
+        "break" if argument.is_empty() => {
+            for name in context.breakpoints() {
+                println!("{}", name);
+            }
+        }
+        "break" => context.add_breakpoint(argument),
+        "clear" => {
+            if !context.remove_breakpoint(argument) {
+                println!("No breakpoint in {}", argument);
+            }
+        }
+        _ => println!("Unknown command :{}, see :help", name),
+    }
+    true
+}
+
+// Prints warnings about a statement typed into the REPL. Returns false when it
+// refers to names that aren't defined, so it shouldn't run at all.
+fn review(source: &str, ast: &node::Node, context: &Context) -> bool {
+    for (span, warning) in lint::mixed_types(source, ast) {
+        match span {
+            Some((start, end)) => {
+                println!("{}", diagnostics::warning(source, start, end, &warning))
+            }
+            None => println!("{}", diagnostics::warning_line(&warning)),
+        }
+    }
+    // Later statements may still read a variable and definitions may call
+    // functions defined later on, so only definitions are complete enough to
+    // lint and only other statements are checked for undefined names and
+    // wrong argument counts
+    let program = [(1, ast.clone())];
+    if let node::Node::Function(_, _) = ast {
+        for (_, warning) in lint::unused_variables(&program, context) {
+            println!("{}", diagnostics::warning_line(&warning));
+        }
+        return true;
+    }
+    let found = analysis::errors(&program, context);
+    for (_, error) in found.iter() {
+        println!("{}", diagnostics::error(error));
+    }
+    found.is_empty()
+}
+
+// sp fmt [--check] FILE... rewrites scripts in place, or with --check only
+// lists the ones that would change. Returns the process exit code.
+fn fmt(args: Vec<String>) -> i32 {
+    let check = args.iter().any(|arg| arg == "--check");
+    let mut code = 0;
+    for path in args.iter().filter(|arg| *arg != "--check") {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("{}: {}", path, error);
+                code = 1;
+                continue;
+            }
+        };
+        let formatted = match printer::format(&source) {
+            Ok(formatted) => formatted,
+            Err(error) => {
+                eprintln!("{}:{}", path, error);
+                code = 1;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            eprintln!("{} is not formatted", path);
+            code = 1;
+        } else if let Err(error) = std::fs::write(path, formatted) {
+            eprintln!("{}: {}", path, error);
+            code = 1;
+        }
+    }
+    code
+}
+
+// Reads the script named on the command line, or stdin without one, and
+// returns its name with the source
+fn read_source(script: &Option<String>) -> (String, String) {
+    let name = script.clone().unwrap_or_else(|| "<stdin>".to_string());
+    let mut source = String::new();
+    let read = match script {
+        Some(path) => std::fs::read_to_string(path).map(|script| source = script),
+        None => std::io::stdin().read_to_string(&mut source).map(|_| ()),
+    };
+    if let Err(error) = read {
+        eprintln!("{}: {}", name, error);
+        std::process::exit(1);
+    }
+    (name, source)
+}
 
 fn main() {
-    let mut context = Context::default();
+    diagnostics::set_color(std::io::stdout().is_terminal());
+    if std::env::args().nth(1).as_deref() == Some("fmt") {
+        std::process::exit(fmt(std::env::args().skip(2).collect()));
+    }
+
+    let mut builder = Interpreter::builder();
+    let mut options = Options::default();
+    let mut trace = false;
+    #[cfg(feature = "jit")]
+    let mut jit = false;
+    let mut arguments = Vec::new();
+    let mut history = default_history();
+    let mut script = None;
+    let mut eval = None;
+    let mut check = false;
+    let mut check_types = false;
+    let mut watching = false;
+    let mut emit = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => match args.next().map(|ms| ms.parse::<u64>()) {
+                Some(Ok(ms)) => {
+                    builder.timeout(Duration::from_millis(ms));
+                }
+                _ => {
+                    println!("--timeout expects a number of milliseconds");
+                    return;
+                }
+            },
+            "--fuel" => match args.next().map(|steps| steps.parse::<u64>()) {
+                Some(Ok(steps)) => {
+                    builder.fuel(steps);
+                }
+                _ => {
+                    println!("--fuel expects a number of evaluation steps");
+                    return;
+                }
+            },
+            "--max-variables" => match args.next().map(|count| count.parse::<usize>()) {
+                Some(Ok(count)) => {
+                    builder.max_variables(count);
+                }
+                _ => {
+                    println!("--max-variables expects a number of variables");
+                    return;
+                }
+            },
+            "--max-recursion" => match args.next().map(|depth| depth.parse::<usize>()) {
+                Some(Ok(depth)) => {
+                    builder.max_recursion(depth);
+                }
+                _ => {
+                    println!("--max-recursion expects a number of calls");
+                    return;
+                }
+            },
+            "--history" => match args.next() {
+                Some(path) => history = Some(PathBuf::from(path)),
+                None => {
+                    println!("--history expects a file path");
+                    return;
+                }
+            },
+            "-e" | "--eval" => match args.next() {
+                Some(source) => eval = Some(source),
+                None => {
+                    println!("{} expects a statement", arg);
+                    return;
+                }
+            },
+            "--check" => check = true,
+            "--check-types" => check_types = true,
+            "--watch" => watching = true,
+            "--emit" => match args.next().as_deref() {
+                Some(target @ "rust") | Some(target @ "js") => emit = Some(target.to_string()),
+                _ => {
+                    println!("--emit expects rust or js");
+                    return;
+                }
+            },
+            "--vm" => {
+                builder.vm(true);
+            }
+            "--strict-types" => {
+                builder.strict_types(true);
+            }
+            "--angles" => match args.next().map(|angles| angles.parse()) {
+                Some(Ok(angles)) => {
+                    builder.angles(angles);
+                }
+                _ => {
+                    println!("--angles expects rad or deg");
+                    return;
+                }
+            },
+            "--sandbox" => {
+                builder.sandbox(true);
+            }
+            "--dump-bytecode" => options.dump_bytecode = true,
+            "--ast" => options.dump_ast = Some(AstFormat::Tree),
+            "--ast-json" => options.dump_ast = Some(AstFormat::Json),
+            "--ast-sexp" => options.dump_ast = Some(AstFormat::Sexp),
+            "--verbose" => options.verbose = true,
+            "--show-none" => options.show_none = true,
+            "--precision" => match args.next().map(|digits| digits.parse::<usize>()) {
+                Some(Ok(digits)) => options.precision = Some(digits),
+                _ => {
+                    println!("--precision expects a number of digits");
+                    return;
+                }
+            },
+            "--notation" => match args.next().map(|notation| notation.parse()) {
+                Some(Ok(notation)) => options.notation = notation,
+                _ => {
+                    println!("--notation expects fixed, sci or eng");
+                    return;
+                }
+            },
+            "--trace" => trace = true,
+            #[cfg(feature = "jit")]
+            "--jit" => jit = true,
+            _ if arg.starts_with('-') => {
+                println!("Unknown argument {}", arg);
+                return;
+            }
+            _ => {
+                // Everything after the script belongs to it
+                script = Some(arg);
+                arguments = args.by_ref().collect();
+            }
+        }
+    }
+
+    let mut interpreter = builder.build();
+    let context = interpreter.context_mut();
+    context.set_debugger(Some(Rc::new(RefCell::new(Prompt::default()))));
+    context.set_tracer(trace.then(print_trace));
+    context.set_arguments(arguments);
+    #[cfg(feature = "jit")]
+    {
+        if jit {
+            if let Err(error) = context.enable_jit() {
+                println!("Can't start the JIT: {}", error);
+                return;
+            }
+        }
+    }
+
+    if let Some(source) = eval {
+        match parse_line(&source) {
+            Ok((b"", ast)) => match run(&ast, &mut interpreter, &options) {
+                Ok(Value::None) if !options.show_none => {}
+                Ok(value) => println!("{}", display(&value, &options)),
+                Err(error) => fail(error),
+            },
+            Ok((input, _))
+            | Err(nom::Err::Error((input, _)))
+            | Err(nom::Err::Failure((input, _))) => {
+                let offset = source.len() - input.len();
+                diagnostics::set_color(std::io::stderr().is_terminal());
+                eprintln!(
+                    "{}",
+                    diagnostics::parse_error(&source, offset, "not a statement")
+                );
+                std::process::exit(1);
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                diagnostics::set_color(std::io::stderr().is_terminal());
+                eprintln!(
+                    "{}",
+                    diagnostics::parse_error(&source, source.len(), "unexpected end of input")
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Reports syntax errors, undefined names and lint warnings without running
+    // anything, for editors and hooks validating scripts. Only errors fail it.
+    if check {
+        let (name, source) = read_source(&script);
+        let (_, found) = diagnostics::check(&source, interpreter.context());
+        for (line, error) in found.errors.iter() {
+            eprintln!("{}:{}: {}", name, line, error);
+        }
+        for (line, warning) in found.warnings.iter() {
+            eprintln!("{}:{}: warning: {}", name, line, warning);
+        }
+        std::process::exit(if found.has_errors() { 1 } else { 0 });
+    }
+
+    // Reports operations that would fail on the types of their operands
+    if check_types {
+        let (name, source) = read_source(&script);
+        let errors = match parser::program(&source) {
+            Ok(program) => typecheck::check(&program),
+            Err(error) => vec![error.to_string()],
+        };
+        for error in errors.iter() {
+            eprintln!("{}:{}", name, error);
+        }
+        std::process::exit(if errors.is_empty() { 0 } else { 1 });
+    }
+
+    // Prints the script translated to another language instead of running it
+    if let Some(target) = emit {
+        let (name, source) = read_source(&script);
+        let program = match parser::program(&source) {
+            Ok(program) => program,
+            Err(error) => {
+                eprintln!("{}:{}", name, error);
+                std::process::exit(1);
+            }
+        };
+        let emitted = match target.as_str() {
+            "js" => codegen::javascript(&program),
+            _ => codegen::rust(&program),
+        };
+        match emitted {
+            Ok(emitted) => print!("{}", emitted),
+            Err(error) => {
+                eprintln!("{}:{}", name, error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Piped input is run as a program instead of starting the REPL
+    if script.is_none() && !std::io::stdin().is_terminal() {
+        let mut source = String::new();
+        let read = std::io::stdin().read_to_string(&mut source);
+        let executed = read
+            .map_err(|error| error.into())
+            .and_then(|_| execute("<stdin>", &source, &mut interpreter, &options, true));
+        if let Err(error) = executed {
+            fail(error);
+        }
+        return;
+    }
+
+    if watching {
+        let executed = match &script {
+            Some(script) => watch(script, &mut interpreter, &options),
+            None => Err("--watch expects a script".into()),
+        };
+        if let Err(error) = executed {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let mut rl = Editor::<()>::new();
-    if rl.load_history("history.txt").is_err() {
-        println!("No previous history.");
+    // A script runs without the REPL and only reports errors
+    if let Some(script) = script {
+        if let Err(error) = load(&script, &mut interpreter, &options) {
+            fail(error);
+        }
+        return;
     }
+
+    let completion = Rc::new(RefCell::new(interpreter.context().clone()));
+    let config = Config::builder().max_history_size(MAX_HISTORY).build();
+    let mut rl = Editor::<Completion>::with_config(config);
+    rl.set_helper(Some(Completion {
+        context: completion.clone(),
+    }));
+    if let Some(history) = &history {
+        if rl.load_history(history).is_err() {
+            println!("No previous history.");
+        }
+    }
+    let mut exit_code = 0;
+    // Variables and functions as they were before each statement
+    let mut journal: Vec<(String, Context)> = Vec::new();
+    // Lines of a statement that is still being typed
+    let mut buffer = String::new();
     loop {
-        let readline = rl.readline(">> ");
+        let prompt = if buffer.is_empty() { ">> " } else { ".. " };
+        *completion.borrow_mut() = interpreter.context().clone();
+        let readline = match rl.readline(prompt) {
+            Ok(line) if buffer.is_empty() && line.trim().starts_with('!') => {
+                match recall(line.trim(), rl.history()) {
+                    Some(entry) => {
+                        println!("{}", entry);
+                        Ok(entry)
+                    }
+                    None => {
+                        println!("No history entry {}", line.trim());
+                        continue;
+                    }
+                }
+            }
+            readline => readline,
+        };
         match readline {
-            Ok(line) => match statement(line.as_bytes()) {
-                Ok((b"", ast)) => {
-                    rl.add_history_entry(line.as_str());
-                    println!("Line: {:?}", ast);
-                    println!("Evaluated: {:?}", ast.evaluate(&mut context));
+            Ok(line) if buffer.is_empty() && line.trim() == ":history" => {
+                rl.add_history_entry(line.as_str());
+                for (index, entry) in rl.history().iter().enumerate() {
+                    println!("{:>4}  {}", index + 1, entry);
                 }
-                Ok((input, ast)) => {
-                    println!("Parsing incomplete {:?}", std::str::from_utf8(input));
-                    println!("Line: {:?}", ast);
+            }
+            Ok(line) if buffer.is_empty() && line.trim() == ":undo" => {
+                rl.add_history_entry(line.as_str());
+                match journal.pop() {
+                    Some((statement, snapshot)) => {
+                        interpreter.context_mut().restore(&snapshot);
+                        println!("Undid {}", statement);
+                    }
+                    None => println!("Nothing to undo"),
+                }
+            }
+            Ok(line) if buffer.is_empty() && line.starts_with(':') => {
+                rl.add_history_entry(line.as_str());
+                let name = line[1..].split_whitespace().next().unwrap_or("");
+                if ["reset", "load", "restore"].contains(&name) {
+                    record(&mut journal, line.trim(), interpreter.context());
                 }
-                Err(error) => {
-                    println!("{:?}", error);
+                if !command(&line, &mut interpreter, &mut options) {
+                    break;
                 }
-            },
+            }
+            Ok(line) if !buffer.is_empty() && line.trim().is_empty() => {
+                println!("Discarded incomplete statement");
+                buffer.clear();
+            }
+            Ok(line) => {
+                // The grammar only skips spaces, so lines are joined with one
+                if !buffer.is_empty() {
+                    buffer.push(' ');
+                }
+                buffer.push_str(line.trim());
+                if parser::is_open(buffer.as_bytes()) || parser::is_incomplete(buffer.as_bytes()) {
+                    continue;
+                }
+                rl.add_history_entry(buffer.as_str());
+                match parse_line(&buffer) {
+                    Ok((b"", ast)) => {
+                        if options.verbose {
+                            println!("Line: {:?}", ast);
+                        }
+                        if !review(&buffer, &ast, interpreter.context()) {
+                            buffer.clear();
+                            continue;
+                        }
+                        record(&mut journal, &buffer, interpreter.context());
+                        match run(&ast, &mut interpreter, &options) {
+                            Ok(Value::None) if !options.show_none => {}
+                            Ok(value) => println!("{}", display(&value, &options)),
+                            Err(error) => match error.downcast_ref::<Exit>() {
+                                Some(Exit(code)) => {
+                                    exit_code = *code;
+                                    break;
+                                }
+                                None => println!("{}", diagnostics::runtime_error(&*error)),
+                            },
+                        }
+                    }
+                    Ok((input, _)) => {
+                        let offset = buffer.len() - input.len();
+                        let message = "unexpected input after the statement";
+                        println!("{}", diagnostics::parse_error(&buffer, offset, message));
+                    }
+                    Err(nom::Err::Error((input, _))) | Err(nom::Err::Failure((input, _))) => {
+                        let offset = buffer.len() - input.len();
+                        let message = "not a statement";
+                        println!("{}", diagnostics::parse_error(&buffer, offset, message));
+                    }
+                    Err(nom::Err::Incomplete(_)) => {
+                        println!(
+                            "{}",
+                            diagnostics::parse_error(
+                                &buffer,
+                                buffer.len(),
+                                "unexpected end of input"
+                            )
+                        );
+                    }
+                }
+                buffer.clear();
+            }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
                 break;
@@ -44,5 +1039,13 @@ fn main() {
             }
         }
     }
-    rl.save_history("history.txt").unwrap();
+    if let Some(history) = &history {
+        if let Some(directory) = history.parent() {
+            let _ = std::fs::create_dir_all(directory);
+        }
+        if let Err(error) = rl.save_history(history) {
+            println!("Can't save history to {}: {}", history.display(), error);
+        }
+    }
+    std::process::exit(exit_code);
 }
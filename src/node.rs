@@ -1,7 +1,22 @@
+use crate::debugger::{Debugger, Resume};
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+use crate::profiler::Profile;
+use crate::resolver::resolve_function;
+use crate::units::Unit;
+use crate::vm::Chunks;
+use im::{OrdMap, OrdSet, Vector};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum Operation {
     Plus,
     Minus,
@@ -16,71 +31,146 @@ pub enum Operation {
 }
 
 impl Operation {
-    pub fn from_string(c: &str) -> Result<Operation, ()> {
+    pub fn from_string(c: &str) -> Option<Operation> {
         match c {
-            "+" => Ok(Operation::Plus),
-            "-" => Ok(Operation::Minus),
-            "/" => Ok(Operation::Divide),
-            "*" => Ok(Operation::Multiply),
-            "<" => Ok(Operation::Less),
-            ">" => Ok(Operation::More),
-            "==" => Ok(Operation::Equal),
-            "!=" => Ok(Operation::NotEqual),
-            "||" => Ok(Operation::Or),
-            "&&" => Ok(Operation::And),
-            _ => Err(()),
+            "+" => Some(Operation::Plus),
+            "-" => Some(Operation::Minus),
+            "/" => Some(Operation::Divide),
+            "*" => Some(Operation::Multiply),
+            "<" => Some(Operation::Less),
+            ">" => Some(Operation::More),
+            "==" => Some(Operation::Equal),
+            "!=" => Some(Operation::NotEqual),
+            "||" => Some(Operation::Or),
+            "&&" => Some(Operation::And),
+            _ => None,
         }
     }
 
-    pub fn is_arithmetic(&self) -> bool {
+    // Higher binds tighter, matching the grammar's levels: comparisons and
+    // logic bind tightest, then multiplication and division, then + and -
+    pub fn precedence(&self) -> u8 {
         match self {
-            Operation::Plus | Operation::Minus | Operation::Divide | Operation::Multiply => true,
-            _ => false,
+            Operation::Plus | Operation::Minus => 1,
+            Operation::Multiply | Operation::Divide => 2,
+            _ => 3,
         }
     }
+
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            Operation::Plus | Operation::Minus | Operation::Divide | Operation::Multiply
+        )
+    }
 }
 
 impl fmt::Display for Operation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut result: String;
-        match self {
-            Operation::Plus => result = "+".to_string(),
-            Operation::Minus => result = "-".to_string(),
-            Operation::Divide => result = "/".to_string(),
-            Operation::Multiply => result = "*".to_string(),
-            Operation::Less => result = "<".to_string(),
-            Operation::More => result = ">".to_string(),
-            Operation::Equal => result = "==".to_string(),
-            Operation::NotEqual => result = "!=".to_string(),
-            Operation::Or => result = "||".to_string(),
-            Operation::And => result = "&&".to_string(),
-        }
+        let result = match self {
+            Operation::Plus => "+",
+            Operation::Minus => "-",
+            Operation::Divide => "/",
+            Operation::Multiply => "*",
+            Operation::Less => "<",
+            Operation::More => ">",
+            Operation::Equal => "==",
+            Operation::NotEqual => "!=",
+            Operation::Or => "||",
+            Operation::And => "&&",
+        };
         write!(f, "{}", result)
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum Value {
     None,
     Bool(bool),
     Number(f32),
+    // A number measured in a unit, never a dimensionless one
+    Quantity(f32, Unit),
 }
 
-impl Value {
-    pub fn to_string(&self) -> String {
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Value::None => "None".to_string(),
-            Value::Bool(boolean) => boolean.to_string(),
-            Value::Number(number) => number.to_string(),
+            Value::None => write!(f, "None"),
+            Value::Bool(boolean) => write!(f, "{}", boolean),
+            Value::Number(number) => match f.precision() {
+                Some(precision) => write!(f, "{:.*}", precision, number),
+                None => write!(f, "{}", number),
+            },
+            Value::Quantity(number, unit) => {
+                fmt::Display::fmt(&Value::Number(*number), f)?;
+                write!(f, " {}", unit)
+            }
         }
     }
+}
 
-    pub fn is_number(&self) -> bool {
-        match self {
-            Value::Number(_) => true,
-            _ => false,
+// How numbers are written: fixed as 12500, scientific as 1.25e4 and
+// engineering with an exponent that is a multiple of three, as 12.5e3
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Notation {
+    #[default]
+    Fixed,
+    Scientific,
+    Engineering,
+}
+
+impl std::str::FromStr for Notation {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Notation, String> {
+        match name {
+            "fixed" => Ok(Notation::Fixed),
+            "sci" | "scientific" => Ok(Notation::Scientific),
+            "eng" | "engineering" => Ok(Notation::Engineering),
+            other => Err(format!(
+                "Expected fixed, sci or eng notation, got {}",
+                other
+            )),
         }
     }
+}
+
+// Displays a value with its number in a notation, a precision given to the
+// formatter applies to the digits after the decimal point
+pub struct Notated(pub Value, pub Notation);
+
+impl fmt::Display for Notated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Value::Quantity(number, unit) = self.0 {
+            Notated(Value::Number(number), self.1).fmt(f)?;
+            return write!(f, " {}", unit);
+        }
+        let number = match self.0 {
+            Value::Number(number) if number.is_finite() => number,
+            value => return fmt::Display::fmt(&value, f),
+        };
+        let exponent = match self.1 {
+            Notation::Fixed => return fmt::Display::fmt(&self.0, f),
+            Notation::Scientific => match f.precision() {
+                Some(precision) => return write!(f, "{:.*e}", precision, number),
+                None => return write!(f, "{:e}", number),
+            },
+            Notation::Engineering if number == 0.0 => 0,
+            Notation::Engineering => (number.abs() as f64).log10().floor() as i32,
+        };
+        let exponent = exponent.div_euclid(3) * 3;
+        let mantissa = (number as f64 / 10f64.powi(exponent)) as f32;
+        match f.precision() {
+            Some(precision) => write!(f, "{:.*}e{}", precision, mantissa, exponent),
+            None => write!(f, "{}e{}", mantissa, exponent),
+        }
+    }
+}
+
+impl Value {
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Number(_))
+    }
 
     pub fn to_number(&self) -> Option<f32> {
         match self {
@@ -90,10 +180,7 @@ impl Value {
     }
 
     pub fn is_bool(&self) -> bool {
-        match self {
-            Value::Bool(_) => true,
-            _ => false,
-        }
+        matches!(self, Value::Bool(_))
     }
     pub fn to_bool(&self) -> Option<bool> {
         match self {
@@ -102,71 +189,1139 @@ impl Value {
         }
     }
 
-    pub fn is_none(&self) -> bool {
-        match self {
-            Value::None => true,
-            _ => false,
+    pub fn is_none(&self) -> bool {
+        matches!(self, Value::None)
+    }
+
+    // A number in a unit, or a plain number when the unit is dimensionless
+    pub fn quantity(number: f32, unit: Unit) -> Value {
+        match unit.is_dimensionless() {
+            true => Value::Number(number),
+            false => Value::Quantity(number, unit),
+        }
+    }
+
+    // Numbers and quantities with their unit, plain numbers are dimensionless
+    pub(crate) fn measure(&self) -> Option<(f32, Unit)> {
+        match self {
+            Value::Number(number) => Some((*number, Unit::default())),
+            Value::Quantity(number, unit) => Some((*number, *unit)),
+            _ => None,
+        }
+    }
+}
+
+// Numbers are f32 inside the interpreter, so f64 loses precision on the way in
+impl From<f32> for Value {
+    fn from(number: f32) -> Value {
+        Value::Number(number)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Value {
+        Value::Number(number as f32)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(boolean: bool) -> Value {
+        Value::Bool(boolean)
+    }
+}
+
+impl TryFrom<Value> for f32 {
+    type Error = EvalError;
+
+    fn try_from(value: Value) -> Result<f32, EvalError> {
+        value
+            .to_number()
+            .ok_or_else(|| EvalError::Message(format!("{} is not a number", value)))
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = EvalError;
+
+    fn try_from(value: Value) -> Result<f64, EvalError> {
+        f32::try_from(value).map(f64::from)
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = EvalError;
+
+    fn try_from(value: Value) -> Result<bool, EvalError> {
+        value
+            .to_bool()
+            .ok_or_else(|| EvalError::Message(format!("{} is not a boolean", value)))
+    }
+}
+
+// Hashable form of a Value for use as a cache key, numbers compare by their bits
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum ValueKey {
+    None,
+    Bool(bool),
+    Number(u32),
+    Quantity(u32, Unit),
+}
+
+impl From<&Value> for ValueKey {
+    fn from(value: &Value) -> ValueKey {
+        match value {
+            Value::None => ValueKey::None,
+            Value::Bool(boolean) => ValueKey::Bool(*boolean),
+            Value::Number(number) => ValueKey::Number(number.to_bits()),
+            Value::Quantity(number, unit) => ValueKey::Quantity(number.to_bits(), *unit),
+        }
+    }
+}
+
+// Slots belong to one Context, so they are left out when serializing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Node {
+    Constant(Value),
+    BinaryOperation(Operation, Rc<Node>, Rc<Node>),
+    Variable(String, #[serde(skip)] Option<usize> /* slot */),
+    Block(Vec<Rc<Node>>),
+    Assignment(
+        String,
+        #[serde(skip)] Option<usize>, /* slot */
+        Rc<Node>,
+    ),
+    Function(String, Rc<Function>),
+    Call(String, Vec<Rc<Node>>),
+    IfElse(
+        Rc<Node>,         /* condition */
+        Rc<Node>,         /* if true */
+        Option<Rc<Node>>, /* if false */
+    ),
+    While(Rc<Node> /* condition */, Rc<Node> /* body */),
+    For(
+        Rc<Node>, /* init */
+        Rc<Node>, /* condition */
+        Rc<Node>, /* body */
+        Rc<Node>, /* step */
+    ),
+}
+
+// Optional type of a parameter or a result, as in fn f(x: number) -> bool
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Annotation {
+    Number,
+    Bool,
+}
+
+impl Annotation {
+    pub fn accepts(&self, value: &Value) -> bool {
+        match self {
+            Annotation::Number => value.is_number(),
+            Annotation::Bool => value.is_bool(),
+        }
+    }
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Annotation::Number => write!(f, "number"),
+            Annotation::Bool => write!(f, "bool"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Function {
+    pub parameters: Vec<String>,
+    // Annotations of the parameters by position, missing ones are unannotated
+    #[serde(default)]
+    pub types: Vec<Option<Annotation>>,
+    #[serde(default)]
+    pub returns: Option<Annotation>,
+    // Calls are cached by argument values, for pure functions only
+    pub memoized: bool,
+    pub body: Rc<Node>,
+}
+
+impl Function {
+    pub fn parameter_type(&self, index: usize) -> Option<Annotation> {
+        self.types.get(index).cloned().flatten()
+    }
+
+    // Name, parameters and annotations as written in the definition
+    pub fn signature(&self, name: &str) -> String {
+        let parameters: Vec<String> = self
+            .parameters
+            .iter()
+            .enumerate()
+            .map(|(index, parameter)| match self.parameter_type(index) {
+                Some(annotation) => format!("{}: {}", parameter, annotation),
+                None => parameter.clone(),
+            })
+            .collect();
+        match self.returns {
+            Some(annotation) => format!("{}({}) -> {}", name, parameters.join(", "), annotation),
+            None => format!("{}({})", name, parameters.join(", ")),
+        }
+    }
+
+    pub(crate) fn check_arguments(
+        &self,
+        name: &str,
+        arguments: &[Value],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (index, argument) in arguments.iter().enumerate() {
+            match self.parameter_type(index) {
+                Some(annotation) if !annotation.accepts(argument) => {
+                    return Err(format!(
+                        "{} expects {} to be a {}, got {}",
+                        name, self.parameters[index], annotation, argument
+                    )
+                    .into())
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_result(
+        &self,
+        name: &str,
+        value: &Value,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.returns {
+            Some(annotation) if !annotation.accepts(value) => {
+                Err(format!("{} should return a {}, got {}", name, annotation, value).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Unit of the angles trigonometric builtins take and return
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Angles {
+    Radians,
+    Degrees,
+}
+
+impl std::str::FromStr for Angles {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Angles, String> {
+        match name {
+            "rad" | "radians" => Ok(Angles::Radians),
+            "deg" | "degrees" => Ok(Angles::Degrees),
+            other => Err(format!("Expected rad or deg angles, got {}", other)),
+        }
+    }
+}
+
+// Raised by exit(code) to stop evaluation, callers decide what exiting means
+#[derive(Debug)]
+pub struct Exit(pub i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exit({})", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+// Errors embedders may want to tell apart, they stop evaluation like any other
+#[derive(Debug)]
+pub enum EvalError {
+    // Returned by host functions
+    Message(String),
+    // A script tried to bind more variables than the limit allows
+    TooManyVariables(usize),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::Message(message) => write!(f, "{}", message),
+            EvalError::TooManyVariables(limit) => {
+                write!(f, "Scripts may not bind more than {} variables", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+impl From<&str> for EvalError {
+    fn from(message: &str) -> EvalError {
+        EvalError::Message(message.to_string())
+    }
+}
+
+impl From<String> for EvalError {
+    fn from(message: String) -> EvalError {
+        EvalError::Message(message)
+    }
+}
+
+// A function implemented in Rust and registered by the embedder
+pub type HostFunction = Rc<dyn Fn(&[Value]) -> Result<Value, EvalError>>;
+
+// A host function whose result is awaited, only Context::evaluate_async can
+// call it
+pub type AsyncHostFunction =
+    Rc<dyn Fn(Vec<Value>) -> Pin<Box<dyn Future<Output = Result<Value, EvalError>>>>>;
+
+// Called with the old and the new value when an observed variable is assigned
+pub type Observer = Rc<dyn Fn(Option<Value>, Value)>;
+
+// Called with a line for every node the tree walker evaluates while tracing
+pub type Tracer = Rc<dyn Fn(&str)>;
+
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Builtin {
+    // Reaches outside of the Context, a sandbox doesn't provide it
+    Io,
+    Round,
+    Unary(fn(f32) -> f32),
+    // A trigonometric function, or the inverse of one when true
+    Trigonometric(fn(f32) -> f32, bool),
+}
+
+// Every builtin with the numbers of arguments it takes
+pub(crate) const BUILTINS: [(&str, &[usize], Builtin); 13] = [
+    ("breakpoint", &[0], Builtin::Io),
+    ("arg", &[1], Builtin::Io),
+    ("exit", &[1], Builtin::Io),
+    ("round", &[1, 2], Builtin::Round),
+    ("round_to", &[2], Builtin::Round),
+    ("deg", &[1], Builtin::Unary(f32::to_degrees)),
+    ("rad", &[1], Builtin::Unary(f32::to_radians)),
+    ("sin", &[1], Builtin::Trigonometric(f32::sin, false)),
+    ("cos", &[1], Builtin::Trigonometric(f32::cos, false)),
+    ("tan", &[1], Builtin::Trigonometric(f32::tan, false)),
+    ("asin", &[1], Builtin::Trigonometric(f32::asin, true)),
+    ("acos", &[1], Builtin::Trigonometric(f32::acos, true)),
+    ("atan", &[1], Builtin::Trigonometric(f32::atan, true)),
+];
+
+fn lookup_builtin(name: &str) -> Option<Builtin> {
+    BUILTINS
+        .iter()
+        .find(|(builtin, _, _)| *builtin == name)
+        .map(|(_, _, builtin)| *builtin)
+}
+
+// Calls the builtin of a name with its evaluated arguments, both backends
+// dispatch here when no function of the name is defined
+pub(crate) fn call_builtin(
+    name: &str,
+    arguments: &[Value],
+    context: &mut Context,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    if context.sandboxed && matches!(lookup_builtin(name), Some(Builtin::Io)) {
+        return Err(format!("{} is not available in a sandbox", name).into());
+    }
+    match (name, arguments) {
+        ("breakpoint", []) => {
+            context.pause("at breakpoint()");
+            Ok(Value::None)
+        }
+        ("exit", [Value::Number(code)]) if code.fract() == 0.0 => Err(Box::new(Exit(*code as i32))),
+        ("exit", [code]) => Err(format!("Exit code {} is not an integer", code).into()),
+        ("arg", [index]) => context.argument(*index),
+        _ => match builtin(name, context) {
+            Some(function) => Ok(function(arguments)?),
+            None => Err(format!("{} function is not defined", name).into()),
+        },
+    }
+}
+
+pub(crate) fn is_builtin(name: &str) -> bool {
+    lookup_builtin(name).is_some()
+}
+
+// Builtins computing their value from the arguments alone
+pub(crate) fn builtin(name: &str, context: &Context) -> Option<HostFunction> {
+    let (function, inverse) = match lookup_builtin(name)? {
+        Builtin::Io => return None,
+        Builtin::Round => return Some(Rc::new(round)),
+        Builtin::Unary(function) => return Some(unary(name, function)),
+        Builtin::Trigonometric(function, inverse) => (function, inverse),
+    };
+    // Trigonometric functions take angles and their inverses return them in
+    // the unit the context is set to
+    Some(match (context.angles, inverse) {
+        (Angles::Radians, _) => unary(name, function),
+        (Angles::Degrees, false) => unary(name, move |number| function(number.to_radians())),
+        (Angles::Degrees, true) => unary(name, move |number| function(number).to_degrees()),
+    })
+}
+
+fn unary<F: Fn(f32) -> f32 + 'static>(name: &str, function: F) -> HostFunction {
+    let error = format!("{} expects a number", name);
+    Rc::new(move |arguments: &[Value]| match arguments {
+        [Value::Number(number)] => Ok(Value::Number(function(*number))),
+        _ => Err(EvalError::from(error.as_str())),
+    })
+}
+
+// Rounds to a whole number, or to a number of digits after the decimal point,
+// or to tens, hundreds and so on for negative digits
+fn round(arguments: &[Value]) -> Result<Value, EvalError> {
+    match arguments {
+        [Value::Number(number)] => Ok(Value::Number(number.round())),
+        [Value::Number(number), Value::Number(digits)] if digits.fract() == 0.0 => {
+            let scaled = *number as f64 * 10f64.powi(*digits as i32);
+            match scaled.is_finite() {
+                true => Ok(Value::Number(
+                    (scaled.round() / 10f64.powi(*digits as i32)) as f32,
+                )),
+                false => Ok(Value::Number(*number)),
+            }
+        }
+        _ => Err(EvalError::from(
+            "round expects a number and optionally a whole number of digits",
+        )),
+    }
+}
+
+// Variable names are interned into slots shared by a Context and all of its clones
+#[derive(Default, Clone)]
+struct Symbols {
+    slots: BTreeMap<String, usize>,
+    names: Vec<String>,
+}
+
+// Bindings local to one function call by slot, anything else resolves to the
+// globals
+#[derive(Default, Clone)]
+struct Frame {
+    locals: Vec<Option<Value>>,
+    // How many slots are bound
+    bound: usize,
+}
+
+impl Frame {
+    fn local(&self, slot: usize) -> Option<Value> {
+        self.locals.get(slot).cloned().flatten()
+    }
+
+    fn bind(&mut self, slot: usize, value: Value) {
+        if slot >= self.locals.len() {
+            self.locals.resize(slot + 1, None);
+        }
+        if self.locals[slot].replace(value).is_none() {
+            self.bound += 1;
+        }
+    }
+}
+
+// Bindings live in persistent collections, so cloning a Context to take a
+// snapshot shares structure with the original instead of copying it
+#[derive(Clone)]
+pub struct Context {
+    symbols: Rc<RefCell<Symbols>>,
+    variables: Vector<Option<Value>>,
+    frames: Vec<Frame>,
+    // Overloads of each name by how many parameters they take
+    functions: OrdMap<String, OrdMap<usize, Rc<Function>>>,
+    host_functions: OrdMap<String, HostFunction>,
+    async_functions: OrdMap<String, AsyncHostFunction>,
+    // By slot, locals and globals of the same name are observed alike
+    observers: OrdMap<usize, Vec<Observer>>,
+    memo: OrdMap<String, OrdMap<Vec<ValueKey>, Value>>,
+    pub(crate) max_depth: usize,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    // Evaluation steps allowed per run, and those left in the current one
+    fuel: Option<u64>,
+    fuel_left: Option<u64>,
+    // Steps taken by the current or last run
+    steps: u64,
+    // Conditions have to be booleans instead of numbers counting as true at zero
+    strict_types: bool,
+    sandboxed: bool,
+    angles: Angles,
+    // Limit on globals and locals bound at once, and how many globals are bound
+    max_variables: Option<usize>,
+    globals: usize,
+    profile: Option<Rc<RefCell<Profile>>>,
+    profiling: bool,
+    tracer: Option<Tracer>,
+    debugger: Option<Rc<RefCell<dyn Debugger>>>,
+    breakpoints: OrdSet<String>,
+    stepping: bool,
+    // Command line arguments given to a script
+    arguments: Rc<Vec<String>>,
+    // Function bodies the VM compiled, kept between runs
+    chunks: Rc<RefCell<Chunks>>,
+    #[cfg(feature = "jit")]
+    jit: Option<Rc<RefCell<Jit>>>,
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context {
+            symbols: Rc::default(),
+            variables: Vector::new(),
+            frames: Vec::new(),
+            functions: OrdMap::new(),
+            host_functions: OrdMap::new(),
+            async_functions: OrdMap::new(),
+            observers: OrdMap::new(),
+            memo: OrdMap::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            timeout: None,
+            deadline: None,
+            fuel: None,
+            fuel_left: None,
+            steps: 0,
+            strict_types: false,
+            sandboxed: false,
+            angles: Angles::Radians,
+            max_variables: None,
+            globals: 0,
+            profile: None,
+            profiling: false,
+            tracer: None,
+            debugger: None,
+            breakpoints: OrdSet::new(),
+            stepping: false,
+            arguments: Rc::default(),
+            chunks: Rc::default(),
+            #[cfg(feature = "jit")]
+            jit: None,
+        }
+    }
+}
+
+impl Context {
+    pub fn with_max_depth(max_depth: usize) -> Context {
+        Context {
+            max_depth,
+            ..Context::default()
+        }
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    // Evaluation steps the last run took, calls run as native code aren't
+    // counted
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn strict_types(&self) -> bool {
+        self.strict_types
+    }
+
+    pub fn set_strict_types(&mut self, strict_types: bool) {
+        self.strict_types = strict_types;
+    }
+
+    pub fn angles(&self) -> Angles {
+        self.angles
+    }
+
+    pub fn set_angles(&mut self, angles: Angles) {
+        self.angles = angles;
+    }
+
+    pub fn max_variables(&self) -> Option<usize> {
+        self.max_variables
+    }
+
+    pub fn set_max_variables(&mut self, max_variables: Option<usize>) {
+        self.max_variables = max_variables;
+    }
+
+    pub fn sandboxed(&self) -> bool {
+        self.sandboxed
+    }
+
+    pub fn set_sandboxed(&mut self, sandboxed: bool) {
+        self.sandboxed = sandboxed;
+    }
+
+    pub fn trace(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    // Reports every node the tree walker evaluates along with its result and
+    // the variables bound, tracing stops with None
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn set_debugger(&mut self, debugger: Option<Rc<RefCell<dyn Debugger>>>) {
+        self.debugger = debugger;
+    }
+
+    pub fn add_breakpoint(&mut self, name: &str) {
+        self.breakpoints.insert(name.to_string());
+    }
+
+    pub fn remove_breakpoint(&mut self, name: &str) -> bool {
+        self.breakpoints.remove(name).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &String> {
+        self.breakpoints.iter()
+    }
+
+    pub(crate) fn has_breakpoint(&self, name: &str) -> bool {
+        self.breakpoints.contains(name)
+    }
+
+    pub(crate) fn stepping(&self) -> bool {
+        self.stepping
+    }
+
+    // Hands the context to the debugger. Pauses are ignored while the debugger
+    // itself is evaluating something.
+    pub(crate) fn pause(&mut self, reason: &str) {
+        let debugger = match &self.debugger {
+            Some(debugger) => debugger.clone(),
+            None => return,
+        };
+        let mut debugger = match debugger.try_borrow_mut() {
+            Ok(debugger) => debugger,
+            Err(_) => return,
+        };
+        self.stepping = false;
+        if let Resume::Step = debugger.pause(self, reason) {
+            self.stepping = true;
+        }
+    }
+
+    // Scripts read their arguments with arg(index), and their count from argc
+    pub fn set_arguments(&mut self, arguments: Vec<String>) {
+        self.set_var("argc", Value::Number(arguments.len() as f32));
+        self.arguments = Rc::new(arguments);
+    }
+
+    pub(crate) fn argument(&self, index: Value) -> Result<Value, Box<dyn std::error::Error>> {
+        let argument = index
+            .to_number()
+            .filter(|index| *index >= 0.0 && index.fract() == 0.0)
+            .and_then(|index| self.arguments.get(index as usize));
+        match argument {
+            Some(argument) => match argument.parse::<f32>() {
+                Ok(number) => Ok(Value::Number(number)),
+                Err(_) => Err(format!("Argument {} is not a number", argument).into()),
+            },
+            None => Err(format!("There is no argument {}", index).into()),
+        }
+    }
+
+    // Turning profiling on starts a new profile, turning it off keeps the last one
+    // around for the report
+    pub fn set_profiling(&mut self, profiling: bool) {
+        if profiling {
+            self.profile = Some(Rc::default());
+        }
+        self.profiling = profiling;
+    }
+
+    pub fn profile_report(&self) -> Option<String> {
+        self.profile
+            .as_ref()
+            .map(|profile| profile.borrow().report())
+    }
+
+    pub(crate) fn profile_step(&self) {
+        if self.profiling {
+            self.profile.as_ref().unwrap().borrow_mut().step();
+        }
+    }
+
+    pub(crate) fn profile_enter(&self, name: &str) {
+        if self.profiling {
+            self.profile.as_ref().unwrap().borrow_mut().enter(name);
+        }
+    }
+
+    pub(crate) fn profile_leave(&self) {
+        if self.profiling {
+            self.profile.as_ref().unwrap().borrow_mut().leave();
+        }
+    }
+
+    pub(crate) fn chunks(&self) -> Rc<RefCell<Chunks>> {
+        self.chunks.clone()
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.jit = Some(Rc::new(RefCell::new(Jit::new()?)));
+        Ok(())
+    }
+
+    // Native code doesn't check the deadline, count fuel, check the types of
+    // conditions or tell observers, so it only runs without any of those
+    #[cfg(feature = "jit")]
+    pub(crate) fn call_native(
+        &self,
+        name: &str,
+        function: &Rc<Function>,
+        arguments: &[Value],
+    ) -> Option<Result<Value, Box<dyn std::error::Error>>> {
+        // Native code can't pause for the debugger either
+        let paused = self.stepping || self.breakpoints.contains(name);
+        let limited = self.timeout.is_some() || self.fuel.is_some() || self.strict_types;
+        let observed = !self.observers.is_empty();
+        if limited || observed || paused {
+            return None;
+        }
+        let jit = self.jit.as_ref()?;
+        jit.borrow_mut().call(name, function, arguments, self)
+    }
+
+    #[cfg(not(feature = "jit"))]
+    pub(crate) fn call_native(
+        &self,
+        _name: &str,
+        _function: &Rc<Function>,
+        _arguments: &[Value],
+    ) -> Option<Result<Value, Box<dyn std::error::Error>>> {
+        None
+    }
+
+    // Host functions are called before functions defined in scripts, so a
+    // script can't replace them
+    pub fn register_fn<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, EvalError> + 'static,
+    {
+        self.host_functions
+            .insert(name.to_string(), Rc::new(function));
+    }
+
+    pub(crate) fn host_function(&self, name: &str) -> Option<HostFunction> {
+        self.host_functions.get(name).cloned()
+    }
+
+    // Same as register_fn for functions returning futures, such as calls into
+    // an embedder's async runtime. Scripts calling them have to be evaluated
+    // with evaluate_async.
+    pub fn register_async_fn<F, R>(&mut self, name: &str, function: F)
+    where
+        F: Fn(Vec<Value>) -> R + 'static,
+        R: Future<Output = Result<Value, EvalError>> + 'static,
+    {
+        let function: AsyncHostFunction = Rc::new(move |arguments| Box::pin(function(arguments)));
+        self.async_functions.insert(name.to_string(), function);
+    }
+
+    pub(crate) fn async_function(&self, name: &str) -> Option<AsyncHostFunction> {
+        self.async_functions.get(name).cloned()
+    }
+
+    // The overload taking the fewest parameters
+    pub fn function(&self, name: &str) -> Option<&Function> {
+        self.overloads(name).next()
+    }
+
+    pub fn overloads<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a Function> + 'a {
+        self.functions
+            .get(name)
+            .into_iter()
+            .flat_map(|overloads| overloads.values().map(Rc::as_ref))
+    }
+
+    // Every overload, a name repeats for each of its arities
+    pub fn functions(&self) -> impl Iterator<Item = (&String, &Function)> {
+        self.functions.iter().flat_map(|(name, overloads)| {
+            overloads
+                .values()
+                .map(move |function| (name, function.as_ref()))
+        })
+    }
+
+    // The overload a call with arity arguments dispatches to, or an error if
+    // the name is only defined for other arities
+    pub(crate) fn overload(
+        &self,
+        name: &str,
+        arity: usize,
+    ) -> Result<Option<Rc<Function>>, Box<dyn std::error::Error>> {
+        let overloads = match self.functions.get(name) {
+            Some(overloads) => overloads,
+            None => return Ok(None),
+        };
+        match overloads.get(&arity) {
+            Some(function) => Ok(Some(function.clone())),
+            None => {
+                let arities: Vec<String> = overloads.keys().map(usize::to_string).collect();
+                Err(format!(
+                    "{} function takes {} params provided {}",
+                    name,
+                    arities.join(" or "),
+                    arity
+                )
+                .into())
+            }
+        }
+    }
+
+    // Functions whose names are qualified by a namespace, directly or through
+    // one nested in it, as math::sqrt and math::trig::sin are in math
+    pub fn functions_in<'a>(
+        &'a self,
+        namespace: &str,
+    ) -> impl Iterator<Item = (&'a String, &'a Function)> + 'a {
+        let prefix = format!("{}::", namespace);
+        self.functions()
+            .filter(move |(name, _)| name.starts_with(&prefix))
+    }
+
+    // Forgets every variable and function but keeps the settings
+    pub fn reset(&mut self) {
+        self.variables = Vector::new();
+        self.globals = 0;
+        self.frames.clear();
+        self.functions = OrdMap::new();
+        self.memo = OrdMap::new();
+    }
+
+    // Replaces variables and functions with those of a deserialized Context
+    pub fn restore(&mut self, snapshot: &Context) {
+        self.reset();
+        self.functions = match Rc::ptr_eq(&self.symbols, &snapshot.symbols) {
+            true => snapshot.functions.clone(),
+            // Slots in the bodies index the snapshot's symbols, not these
+            false => snapshot
+                .functions
+                .iter()
+                .map(|(name, overloads)| {
+                    let overloads: OrdMap<usize, Rc<Function>> = overloads
+                        .iter()
+                        .map(|(count, function)| (*count, resolve_function(function, self)))
+                        .collect();
+                    (name.clone(), overloads)
+                })
+                .collect(),
+        };
+        // Bindings come back as they were, observers only hear of assignments
+        for (name, value) in snapshot.bindings() {
+            let slot = self.slot(&name);
+            self.store(slot, value);
+        }
+    }
+
+    // Replaces only an earlier definition taking as many parameters
+    pub(crate) fn define(&mut self, name: &str, function: Rc<Function>) {
+        self.memo.remove(name);
+        let mut overloads = self
+            .functions
+            .get(name)
+            .cloned()
+            .unwrap_or_else(OrdMap::new);
+        overloads.insert(function.parameters.len(), function);
+        self.functions.insert(name.to_string(), overloads);
+    }
+
+    pub(crate) fn remembered(&self, name: &str, arguments: &[ValueKey]) -> Option<Value> {
+        self.memo
+            .get(name)
+            .and_then(|cache| cache.get(arguments))
+            .cloned()
+    }
+
+    pub(crate) fn remember(&mut self, name: String, arguments: Vec<ValueKey>, value: Value) {
+        let mut cache = self.memo.get(&name).cloned().unwrap_or_else(OrdMap::new);
+        cache.insert(arguments, value);
+        self.memo.insert(name, cache);
+    }
+
+    // A clone with a copy of the symbol table, so names interned into it don't
+    // reach this Context or its other clones
+    pub(crate) fn detached(&self) -> Context {
+        let mut context = self.clone();
+        context.symbols = Rc::new(RefCell::new(self.symbols.borrow().clone()));
+        context
+    }
+
+    pub fn slot(&self, name: &str) -> usize {
+        let mut symbols = self.symbols.borrow_mut();
+        if let Some(slot) = symbols.slots.get(name) {
+            return *slot;
+        }
+        let slot = symbols.names.len();
+        symbols.slots.insert(name.to_string(), slot);
+        symbols.names.push(name.to_string());
+        slot
+    }
+
+    pub(crate) fn slot_value(&self, slot: usize) -> Option<Value> {
+        let local = self.frames.last().and_then(|frame| frame.local(slot));
+        local.or_else(|| self.variables.get(slot).cloned().flatten())
+    }
+
+    pub fn observe<F>(&mut self, name: &str, observer: F)
+    where
+        F: Fn(Option<Value>, Value) + 'static,
+    {
+        let slot = self.slot(name);
+        let mut observers = self.observers.get(&slot).cloned().unwrap_or_default();
+        observers.push(Rc::new(observer));
+        self.observers.insert(slot, observers);
+    }
+
+    pub fn unobserve(&mut self, name: &str) {
+        let slot = self.slot(name);
+        self.observers.remove(&slot);
+    }
+
+    pub(crate) fn set_slot(&mut self, slot: usize, value: Value) {
+        match self.observers.get(&slot).cloned() {
+            Some(observers) => {
+                let old = self.slot_value(slot);
+                self.store(slot, value);
+                for observer in observers.iter() {
+                    observer(old, value);
+                }
+            }
+            None => self.store(slot, value),
+        }
+    }
+
+    // Inside a call, assignments update a local or an existing global, and
+    // otherwise create a new local
+    fn store(&mut self, slot: usize, value: Value) {
+        let is_global = matches!(self.variables.get(slot), Some(Some(_)));
+        if let Some(frame) = self.frames.last_mut() {
+            if !is_global || frame.local(slot).is_some() {
+                frame.bind(slot, value);
+                return;
+            }
+        }
+        while slot >= self.variables.len() {
+            self.variables.push_back(None);
+        }
+        if !is_global {
+            self.globals += 1;
+        }
+        self.variables.set(slot, Some(value));
+    }
+
+    // Scripts check this before assigning, values set by embedders don't count
+    // against the limit
+    pub(crate) fn check_new_variable(&self, slot: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let limit = match self.max_variables {
+            Some(limit) if self.slot_value(slot).is_none() => limit,
+            _ => return Ok(()),
+        };
+        let locals: usize = self.frames.iter().map(|frame| frame.bound).sum();
+        if self.globals + locals >= limit {
+            return Err(Box::new(EvalError::TooManyVariables(limit)));
+        }
+        Ok(())
+    }
+
+    // Lets embedders seed inputs before running a script and read results after
+    pub fn get_var(&self, name: &str) -> Option<Value> {
+        let slot = self.symbols.borrow().slots.get(name).cloned();
+        slot.and_then(|slot| self.slot_value(slot))
+    }
+
+    #[cfg(feature = "jit")]
+    pub(crate) fn global(&self, name: &str) -> Option<Value> {
+        let slot = self.symbols.borrow().slots.get(name).cloned();
+        slot.and_then(|slot| self.variables.get(slot).cloned().flatten())
+    }
+
+    // Every variable visible from the current frame
+    pub fn bindings(&self) -> impl Iterator<Item = (String, Value)> {
+        let symbols = self.symbols.borrow();
+        let bindings: Vec<(String, Value)> = symbols
+            .names
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, name)| self.slot_value(slot).map(|value| (name.clone(), value)))
+            .collect();
+        bindings.into_iter()
+    }
+
+    pub fn set_var(&mut self, name: &str, value: Value) {
+        let slot = self.slot(name);
+        self.set_slot(slot, value);
+    }
+
+    pub(crate) fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub(crate) fn push_frame(&mut self, parameters: &[String], arguments: Vec<Value>) {
+        let mut frame = Frame::default();
+        for (name, value) in parameters.iter().zip(arguments) {
+            frame.bind(self.slot(name), value);
+        }
+        self.frames.push(frame);
+    }
+
+    pub(crate) fn pop_frame(&mut self) {
+        self.frames.pop();
+    }
+
+    // Drops the frames of calls that were interrupted by an error
+    pub(crate) fn unwind(&mut self, depth: usize) {
+        self.frames.truncate(depth);
+        if self.profiling {
+            self.profile.as_ref().unwrap().borrow_mut().unwind(depth);
+        }
+    }
+
+    // Evaluates a top-level statement, restarting the timeout clock for it
+    pub fn evaluate(&mut self, node: &Node) -> Result<Value, Box<dyn std::error::Error>> {
+        self.run(|context| node.evaluate(context))
+    }
+
+    // Same as evaluate, for callers bringing their own evaluation backend
+    pub fn run<F>(&mut self, evaluate: F) -> Result<Value, Box<dyn std::error::Error>>
+    where
+        F: FnOnce(&mut Context) -> Result<Value, Box<dyn std::error::Error>>,
+    {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("evaluate").entered();
+        self.start_run();
+        let value = evaluate(self);
+        self.finish_run();
+        value
+    }
+
+    // Same as evaluate, calls of async host functions are awaited instead of
+    // failing. Evaluation always walks the tree, the VM can't suspend a call.
+    // The future isn't Send, with tokio it has to run on a LocalSet.
+    pub async fn evaluate_async(
+        &mut self,
+        node: &Node,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        self.start_run();
+        let value = Machine::run_async(Rc::new(node.clone()), self).await;
+        self.finish_run();
+        value
+    }
+
+    fn start_run(&mut self) {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.fuel_left = self.fuel;
+        self.steps = 0;
+    }
+
+    fn finish_run(&mut self) {
+        self.deadline = None;
+        self.fuel_left = None;
+    }
+
+    // Called once per evaluation step by both backends
+    pub(crate) fn burn_fuel(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.steps += 1;
+        match self.fuel_left {
+            Some(0) => Err(format!(
+                "Evaluation ran out of fuel after {} steps",
+                self.fuel.unwrap_or_default()
+            )
+            .into()),
+            Some(left) => {
+                self.fuel_left = Some(left - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn condition(&self, value: &Value) -> Result<bool, Box<dyn std::error::Error>> {
+        match value {
+            Value::Bool(_) => Ok(is_true(value)),
+            _ if self.strict_types => Err(format!("Condition {} is not a boolean", value).into()),
+            _ => Ok(is_true(value)),
+        }
+    }
+
+    pub(crate) fn check_deadline(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(format!(
+                "Evaluation timed out after {} ms",
+                self.timeout.unwrap_or_default().as_millis()
+            )
+            .into()),
+            _ => Ok(()),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub enum Node {
-    Constant(Value),
-    BinaryOperation(Operation, Box<Node>, Box<Node>),
-    Variable(String),
-    Block(Vec<Node>),
-    Assignment(String, Box<Node>),
-    Function(String, Function),
-    Call(String, Vec<Node>),
-    IfElse(
-        Box<Node>,         /* condition */
-        Box<Node>,         /* if true */
-        Option<Box<Node>>, /* if false */
-    ),
-    While(Box<Node> /* condition */, Box<Node> /* body */),
-    For(
-        Box<Node>, /* init */
-        Box<Node>, /* condition */
-        Box<Node>, /* body */
-        Box<Node>, /* step */
-    ),
+// Serialized form of a Context, only its globals and functions are kept
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    variables: Vec<(String, Value)>,
+    functions: Vec<(String, Function)>,
 }
 
-#[derive(Debug, Clone)]
-pub struct Function {
-    pub parameters: Vec<String>,
-    pub body: Box<Node>,
+impl Serialize for Context {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let symbols = self.symbols.borrow();
+        let variables = symbols
+            .names
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, name)| match self.variables.get(slot) {
+                Some(Some(value)) => Some((name.clone(), *value)),
+                _ => None,
+            })
+            .collect();
+        let functions = self
+            .functions()
+            .map(|(name, function)| (name.clone(), function.clone()))
+            .collect();
+        Snapshot {
+            variables,
+            functions,
+        }
+        .serialize(serializer)
+    }
 }
 
-impl Function {
-    fn call(
-        &self,
-        context: &mut Context,
-        parameters: &[Node],
-    ) -> Result<Value, Box<dyn std::error::Error>> {
-        debug_assert_eq!(self.parameters.len(), parameters.len());
-        let mut param_values = Vec::new();
-        for (name, value) in self.parameters.iter().cloned().zip(parameters.iter()) {
-            let value = value.evaluate(context);
-            param_values.push((name, value));
+impl<'de> Deserialize<'de> for Context {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Context, D::Error> {
+        let snapshot = Snapshot::deserialize(deserializer)?;
+        let mut context = Context::default();
+        for (name, function) in snapshot.functions {
+            context.define(&name, Rc::new(function));
         }
-
-        for (name, value) in param_values {
-            context.variables.insert(name, value.unwrap());
+        for (name, value) in snapshot.variables {
+            context.set_var(&name, value);
         }
-
-        let value = self.body.evaluate(context)?;
-        Ok(value)
+        Ok(context)
     }
 }
 
-#[derive(Default, Clone)]
-pub struct Context {
-    variables: BTreeMap<String, Value>,
-    functions: BTreeMap<String, Function>,
-}
-
 fn evaluate_binary_operation(
     operation: &Operation,
     left_value: f32,
@@ -177,7 +1332,7 @@ fn evaluate_binary_operation(
         Operation::Minus => Ok(Value::Number(left_value - right_value)),
         Operation::Divide => Ok(Value::Number(left_value / right_value)),
         Operation::Multiply => Ok(Value::Number(left_value * right_value)),
-        _ => Err(format!("Logical operation in arithmetical expression").into()),
+        _ => Err("Logical operation in arithmetical expression".into()),
     }
 }
 
@@ -186,218 +1341,614 @@ fn evaluate_logical_operation(
     left_value: Value,
     right_value: Value,
 ) -> Result<Value, Box<dyn std::error::Error>> {
+    match operation {
+        Operation::Equal => return Ok(Value::Bool(left_value == right_value)),
+        Operation::NotEqual => return Ok(Value::Bool(left_value != right_value)),
+        _ => {}
+    }
     match left_value {
         Value::Number(left) => {
             let right = right_value.to_number().unwrap();
             match operation {
                 Operation::Less => Ok(Value::Bool(left < right)),
                 Operation::More => Ok(Value::Bool(left > right)),
-                Operation::Equal => Ok(Value::Bool(left == right)),
-                Operation::NotEqual => Ok(Value::Bool(left != right)),
-                _ => Err(format!("Arithemtical operation in logical expression").into()),
+                _ => Err("Arithemtical operation in logical expression".into()),
             }
         }
         Value::Bool(left) => {
             let right = right_value.to_bool().unwrap();
             match operation {
-                Operation::Equal => Ok(Value::Bool(left == right)),
-                Operation::NotEqual => Ok(Value::Bool(left != right)),
                 Operation::Or => Ok(Value::Bool(left || right)),
                 Operation::And => Ok(Value::Bool(left && right)),
-                _ => Err(format!("Arithemtical operation in logical expression").into()),
+                _ => Err("Arithemtical operation in logical expression".into()),
             }
         }
-        _ => Err(format!("None as operand in logical operation").into()),
+        _ => Err("None as operand in logical operation".into()),
     }
 }
 
-fn evaluate_condition(
-    condition: &Box<Node>,
-    context: &mut Context,
-) -> Result<bool, Box<dyn std::error::Error>> {
-    let cond_result = condition.evaluate(context)?;
-    Ok(
-        cond_result.is_bool() && cond_result.to_bool().unwrap() == true
-            || cond_result.is_number() && cond_result.to_number().unwrap() == 0.0,
-    )
+pub(crate) fn is_true(condition: &Value) -> bool {
+    condition.is_bool() && condition.to_bool().unwrap()
+        || condition.is_number() && condition.to_number().unwrap() == 0.0
 }
 
-fn evaluate_operation(
+pub(crate) fn evaluate_operation(
     operation: &Operation,
-    left_node: &Node,
-    right_node: &Node,
-    context: &mut Context,
+    left_value: Value,
+    right_value: Value,
 ) -> Result<Value, Box<dyn std::error::Error>> {
-    let left_value = left_node.evaluate(context)?;
-    let right_value = right_node.evaluate(context)?;
-
     if operation.is_arithmetic() {
-        if !left_value.is_number() || !right_value.is_number() {
-            return Err(format!("One of operands in arithmetic expression is not number").into());
+        if left_value.is_number() && right_value.is_number() {
+            return evaluate_binary_operation(
+                operation,
+                left_value.to_number().unwrap(),
+                right_value.to_number().unwrap(),
+            );
+        }
+        match (left_value.measure(), right_value.measure()) {
+            (Some(left), Some(right)) => evaluate_quantity_operation(operation, left, right),
+            _ => Err("One of operands in arithmetic expression is not number".into()),
         }
-        return evaluate_binary_operation(
-            operation,
-            left_value.to_number().unwrap(),
-            right_value.to_number().unwrap(),
-        );
     } else {
         if left_value.is_none() || right_value.is_none() {
-            return Err(format!("None value in binary expression").into());
+            return Err("None value in binary expression".into());
+        }
+
+        if left_value.is_bool() != right_value.is_bool() {
+            return Err("Operands have different types in expression".into());
         }
 
-        if left_value.is_bool() && right_value.is_number()
-            || left_value.is_number() && right_value.is_bool()
+        // Quantities compare as numbers once their units agree
+        if let (Some((left, left_unit)), Some((right, right_unit))) =
+            (left_value.measure(), right_value.measure())
         {
-            return Err(format!("Operands have different types in expression").into());
+            if left_unit != right_unit {
+                return Err(format!(
+                    "Can't compare {} with {}",
+                    unit_name(left_unit),
+                    unit_name(right_unit)
+                )
+                .into());
+            }
+            return evaluate_logical_operation(
+                operation,
+                Value::Number(left),
+                Value::Number(right),
+            );
         }
 
-        return evaluate_logical_operation(operation, left_value, right_value);
+        evaluate_logical_operation(operation, left_value, right_value)
     }
 }
 
-impl Node {
-    pub fn to_string(&self) -> String {
-        match self {
-            Node::Constant(number) => number.to_string(),
-            Node::BinaryOperation(operation, left_node, right_node) => {
-                left_node.to_string() + &operation.to_string() + &right_node.to_string()
-            }
-            Node::Variable(name) => name.clone(),
-            Node::Assignment(name, value) => name.clone() + "=" + &value.to_string(),
-            Node::Block(body) => body
-                .iter()
-                .map(|expr| "  ".to_string() + &expr.to_string())
-                .collect::<Vec<String>>()
-                .join(";\n"),
-            Node::Function(name, Function { parameters, body }) => {
-                "fn ".to_string()
-                    + &name
-                    + "("
-                    + &parameters.join(", ")
-                    + ") {\n"
-                    + &body.to_string()
-                    + "}\n"
-            }
-            Node::Call(name, params) => {
-                name.clone()
-                    + "("
-                    + &params
-                        .iter()
-                        .map(|expr| expr.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", ")
-                    + ")"
+// Adding and subtracting needs operands in the same unit, multiplying and
+// dividing composes their units
+fn evaluate_quantity_operation(
+    operation: &Operation,
+    (left, left_unit): (f32, Unit),
+    (right, right_unit): (f32, Unit),
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let unit = match operation {
+        Operation::Plus | Operation::Minus if left_unit != right_unit => {
+            return Err(format!(
+                "Can't {} {} and {}",
+                match operation {
+                    Operation::Plus => "add",
+                    _ => "subtract",
+                },
+                unit_name(left_unit),
+                unit_name(right_unit)
+            )
+            .into())
+        }
+        Operation::Multiply => left_unit.times(right_unit),
+        Operation::Divide => left_unit.per(right_unit),
+        _ => Some(left_unit),
+    };
+    let unit = unit.ok_or("Unit of the result is out of range")?;
+    let number = evaluate_binary_operation(operation, left, right)?;
+    Ok(Value::quantity(number.to_number().unwrap(), unit))
+}
+
+fn unit_name(unit: Unit) -> String {
+    match unit.is_dimensionless() {
+        true => "a number".to_string(),
+        false => unit.to_string(),
+    }
+}
+
+pub(crate) fn async_call_error(name: &str) -> Box<dyn std::error::Error> {
+    format!(
+        "{} is async, the script has to be evaluated with evaluate_async",
+        name
+    )
+    .into()
+}
+
+// Nodes are evaluated from an explicit task stack instead of the host stack, so
+// deeply nested expressions and deep script recursion can't overflow it.
+enum Task {
+    Eval(Rc<Node>),
+    // Finishes a node once the values it scheduled are on the value stack
+    Apply(Rc<Node>),
+    Discard,
+    Enter(String, Rc<Function>),
+    // Replaces the arguments on the value stack with what the host function returns
+    Host(HostFunction, usize),
+    // Same for an async host function, the machine stops until it is awaited
+    Await(String, AsyncHostFunction, usize),
+    // Replaces the arguments on the value stack with what the builtin returns
+    Builtin(String, usize),
+    // Stores the value a memoized call returned
+    Remember(String, Vec<ValueKey>),
+    // Checks the value a call returned against its annotation
+    Return(String, Rc<Function>),
+    Leave,
+    // Prints a traced node with the value it evaluated to
+    Report(Rc<Node>),
+    // Opens and closes the span of a statement in a block
+    #[cfg(feature = "tracing")]
+    OpenSpan(Rc<Node>),
+    #[cfg(feature = "tracing")]
+    CloseSpan,
+}
+
+struct Machine {
+    tasks: Vec<Task>,
+    values: Vec<Value>,
+    // Whether async host functions may be called, and the call to await
+    // before execution can go on
+    suspendable: bool,
+    waiting: Option<(AsyncHostFunction, Vec<Value>)>,
+    // Nodes that are traced but not yet finished
+    level: usize,
+    // Spans of the calls and statements being evaluated, innermost last
+    #[cfg(feature = "tracing")]
+    spans: Vec<tracing::span::EnteredSpan>,
+}
+
+impl Machine {
+    fn new(root: Rc<Node>) -> Machine {
+        Machine {
+            tasks: vec![Task::Eval(root)],
+            values: Vec::new(),
+            suspendable: false,
+            waiting: None,
+            level: 0,
+            #[cfg(feature = "tracing")]
+            spans: Vec::new(),
+        }
+    }
+
+    fn run(root: Rc<Node>, context: &mut Context) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut machine = Machine::new(root);
+        let depth = context.depth();
+        let value = machine.execute(context);
+        machine.finish(value, context, depth)
+    }
+
+    async fn run_async(
+        root: Rc<Node>,
+        context: &mut Context,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut machine = Machine::new(root);
+        machine.suspendable = true;
+        let depth = context.depth();
+        let value = loop {
+            let value = machine.execute(context);
+            let (function, arguments) = match machine.waiting.take() {
+                Some(call) => call,
+                None => break value,
+            };
+            match function(arguments).await {
+                Ok(value) => machine.values.push(value),
+                Err(error) => break Err(error.into()),
             }
-            Node::IfElse(condition, if_body, else_body) => {
-                let result = "if ".to_string()
-                    + &condition.to_string()
-                    + " {\n"
-                    + &if_body.to_string()
-                    + "}\n";
-                if else_body.is_some() {
-                    let body = else_body.as_ref().unwrap();
-                    let result = result + "else {\n" + &body.to_string() + "}\n";
-                    return result;
+        };
+        machine.finish(value, context, depth)
+    }
+
+    fn finish(
+        &mut self,
+        value: Result<Value, Box<dyn std::error::Error>>,
+        context: &mut Context,
+        depth: usize,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        if value.is_err() {
+            context.unwind(depth);
+        }
+        #[cfg(feature = "tracing")]
+        while let Some(span) = self.spans.pop() {
+            drop(span);
+        }
+        value
+    }
+
+    fn execute(&mut self, context: &mut Context) -> Result<Value, Box<dyn std::error::Error>> {
+        while let Some(task) = self.tasks.pop() {
+            match task {
+                Task::Eval(node) => {
+                    context.profile_step();
+                    context.burn_fuel()?;
+                    if context.stepping {
+                        match &*node {
+                            Node::Constant(_) | Node::Variable(_, _) => {}
+                            _ => context.pause(&format!("at {}", node)),
+                        }
+                    }
+                    if context.tracer.is_some() {
+                        self.level += 1;
+                        self.tasks.push(Task::Report(node.clone()));
+                    }
+                    self.schedule(node, context)?
+                }
+                Task::Report(node) => {
+                    self.level -= 1;
+                    if let Some(tracer) = &context.tracer {
+                        let bindings: Vec<String> = context
+                            .bindings()
+                            .map(|(name, value)| format!("{} = {}", name, value))
+                            .collect();
+                        tracer(&format!(
+                            "{:indent$}{} => {} [{}]",
+                            "",
+                            node,
+                            self.values.last().unwrap(),
+                            bindings.join(", "),
+                            indent = self.level * 2
+                        ));
+                    }
+                }
+                Task::Apply(node) => self.apply(node, context)?,
+                Task::Discard => {
+                    self.values.pop();
+                }
+                Task::Enter(name, function) => {
+                    let first_argument = self.values.len() - function.parameters.len();
+                    let arguments = self.values.split_off(first_argument);
+                    function.check_arguments(&name, &arguments)?;
+                    context.profile_enter(&name);
+                    if let Some(value) = context.call_native(&name, &function, &arguments) {
+                        context.profile_leave();
+                        let value = value?;
+                        function.check_result(&name, &value)?;
+                        self.values.push(value);
+                        continue;
+                    }
+                    if function.memoized {
+                        let key: Vec<ValueKey> = arguments.iter().map(ValueKey::from).collect();
+                        if let Some(value) = context.remembered(&name, &key) {
+                            context.profile_leave();
+                            self.values.push(value);
+                            continue;
+                        }
+                        self.tasks.push(Task::Remember(name.clone(), key));
+                    }
+                    context.push_frame(&function.parameters, arguments);
+                    if context.breakpoints.contains(&name) {
+                        context.pause(&format!("at breakpoint in {}", name));
+                    }
+                    #[cfg(feature = "tracing")]
+                    self.spans
+                        .push(tracing::debug_span!("call", function = %name).entered());
+                    self.tasks.push(Task::Leave);
+                    if function.returns.is_some() {
+                        self.tasks
+                            .push(Task::Return(name.clone(), function.clone()));
+                    }
+                    self.tasks.push(Task::Eval(function.body.clone()));
+                }
+                Task::Host(function, count) => {
+                    let arguments = self.values.split_off(self.values.len() - count);
+                    self.values.push(function(&arguments)?);
+                }
+                Task::Await(name, function, count) => {
+                    if !self.suspendable {
+                        return Err(async_call_error(&name));
+                    }
+                    let arguments = self.values.split_off(self.values.len() - count);
+                    self.waiting = Some((function, arguments));
+                    return Ok(Value::None);
+                }
+                Task::Builtin(name, count) => {
+                    let arguments = self.values.split_off(self.values.len() - count);
+                    self.values.push(call_builtin(&name, &arguments, context)?);
+                }
+                Task::Remember(name, key) => {
+                    let value = *self.values.last().unwrap();
+                    context.remember(name, key, value);
+                }
+                Task::Return(name, function) => {
+                    function.check_result(&name, self.values.last().unwrap())?;
+                }
+                Task::Leave => {
+                    context.pop_frame();
+                    context.profile_leave();
+                    #[cfg(feature = "tracing")]
+                    self.spans.pop();
+                }
+                #[cfg(feature = "tracing")]
+                Task::OpenSpan(node) => {
+                    let span = tracing::trace_span!("statement", node = %node.to_string());
+                    self.spans.push(span.entered());
+                }
+                #[cfg(feature = "tracing")]
+                Task::CloseSpan => {
+                    self.spans.pop();
                 }
-                result
-            }
-            Node::While(condition, body) => {
-                "while ".to_string() + &condition.to_string() + " {\n" + &body.to_string() + "}\n"
-            }
-            Node::For(init, condition, body, step) => {
-                "for ".to_string()
-                    + &init.to_string()
-                    + "; "
-                    + &condition.to_string()
-                    + "; "
-                    + &step.to_string()
-                    + " {\n"
-                    + &body.to_string()
-                    + "}\n"
             }
         }
+
+        Ok(self.values.pop().unwrap())
     }
 
-    pub fn evaluate(&self, context: &mut Context) -> Result<Value, Box<dyn std::error::Error>> {
-        match self {
-            Node::Constant(number) => Ok(*number),
-            Node::BinaryOperation(operation, left_node, right_node) => {
-                evaluate_operation(operation, left_node, right_node, context)
+    fn schedule(
+        &mut self,
+        node: Rc<Node>,
+        context: &mut Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match &*node {
+            Node::Constant(number) => self.values.push(*number),
+            Node::BinaryOperation(_, left_node, right_node) => {
+                let (left_node, right_node) = (left_node.clone(), right_node.clone());
+                self.tasks.push(Task::Apply(node));
+                self.tasks.push(Task::Eval(right_node));
+                self.tasks.push(Task::Eval(left_node));
             }
-            Node::Variable(name) => {
-                let variable = context.variables.get(name);
+            Node::Variable(name, slot) => {
+                let variable = match slot {
+                    Some(slot) => context.slot_value(*slot),
+                    None => context.get_var(name),
+                };
                 match variable {
-                    Some(value) => Ok(*value),
-                    None => Err(format!("{} is not defined", name).into()),
+                    Some(value) => self.values.push(value),
+                    None => return Err(format!("{} is not defined", name).into()),
                 }
             }
-            Node::Assignment(name, value) => {
-                let value = value.evaluate(context)?;
-                context.variables.insert(name.clone(), value);
-                Ok(Value::None)
+            Node::Assignment(_, _, value) => {
+                let value = value.clone();
+                self.tasks.push(Task::Apply(node));
+                self.tasks.push(Task::Eval(value));
             }
             Node::Block(body) => {
-                let mut value = Value::None;
-                for expression in body.iter() {
-                    value = expression.evaluate(context)?;
+                if body.is_empty() {
+                    self.values.push(Value::None);
+                }
+                for (index, expression) in body.iter().enumerate().rev() {
+                    if index + 1 != body.len() {
+                        self.tasks.push(Task::Discard);
+                    }
+                    #[cfg(feature = "tracing")]
+                    self.tasks.push(Task::CloseSpan);
+                    self.tasks.push(Task::Eval(expression.clone()));
+                    #[cfg(feature = "tracing")]
+                    self.tasks.push(Task::OpenSpan(expression.clone()));
                 }
-                Ok(value)
             }
             Node::Function(name, function) => {
-                context.functions.insert(name.clone(), function.clone());
-                Ok(Value::None)
+                context.define(name, function.clone());
+                self.values.push(Value::None);
             }
             Node::Call(name, parameters) => {
-                let function = context.functions.get(name);
-                match function {
+                if let Some(function) = context.host_function(name) {
+                    self.tasks.push(Task::Host(function, parameters.len()));
+                    for parameter in parameters.iter().rev() {
+                        self.tasks.push(Task::Eval(parameter.clone()));
+                    }
+                    return Ok(());
+                }
+                if let Some(function) = context.async_function(name) {
+                    self.tasks
+                        .push(Task::Await(name.clone(), function, parameters.len()));
+                    for parameter in parameters.iter().rev() {
+                        self.tasks.push(Task::Eval(parameter.clone()));
+                    }
+                    return Ok(());
+                }
+                match context.overload(name, parameters.len())? {
                     Some(function) => {
-                        let mut context = context.clone();
-                        if function.parameters.len() != parameters.len() {
+                        context.check_deadline()?;
+                        if context.depth() + 1 > context.max_depth {
                             return Err(format!(
-                                "{} function takes {} params provided {}",
-                                name,
-                                function.parameters.len(),
-                                parameters.len()
+                                "Maximum recursion depth of {} exceeded in {}",
+                                context.max_depth, name
                             )
                             .into());
                         }
 
-                        function.call(&mut context, parameters)
+                        self.tasks.push(Task::Enter(name.clone(), function));
+                        for parameter in parameters.iter().rev() {
+                            self.tasks.push(Task::Eval(parameter.clone()));
+                        }
                     }
-                    None => Err(format!("{} function is not defined", name).into()),
+                    None if is_builtin(name) => {
+                        self.tasks
+                            .push(Task::Builtin(name.clone(), parameters.len()));
+                        for parameter in parameters.iter().rev() {
+                            self.tasks.push(Task::Eval(parameter.clone()));
+                        }
+                    }
+                    None => return Err(format!("{} function is not defined", name).into()),
                 }
             }
-            Node::IfElse(condition, if_body, else_body) => {
-                let cond = evaluate_condition(condition, context)?;
-                if cond {
-                    if_body.evaluate(context)
-                } else if else_body.is_some() {
-                    else_body.as_ref().unwrap().evaluate(context)
+            Node::IfElse(condition, _, _) | Node::While(condition, _) => {
+                let condition = condition.clone();
+                self.tasks.push(Task::Apply(node));
+                self.tasks.push(Task::Eval(condition));
+            }
+            Node::For(init, condition, _, _) => {
+                let (init, condition) = (init.clone(), condition.clone());
+                self.tasks.push(Task::Apply(node));
+                self.tasks.push(Task::Eval(condition));
+                self.tasks.push(Task::Discard);
+                self.tasks.push(Task::Eval(init));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        node: Rc<Node>,
+        context: &mut Context,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match &*node {
+            Node::BinaryOperation(operation, _, _) => {
+                let right_value = self.values.pop().unwrap();
+                let left_value = self.values.pop().unwrap();
+                let value = evaluate_operation(operation, left_value, right_value)?;
+                self.values.push(value);
+            }
+            Node::Assignment(name, slot, _) => {
+                let value = self.values.pop().unwrap();
+                let slot = slot.unwrap_or_else(|| context.slot(name));
+                context.check_new_variable(slot)?;
+                context.set_slot(slot, value);
+                self.values.push(Value::None);
+            }
+            Node::IfElse(_, if_body, else_body) => {
+                if context.condition(&self.values.pop().unwrap())? {
+                    self.tasks.push(Task::Eval(if_body.clone()));
+                } else if let Some(else_body) = else_body {
+                    self.tasks.push(Task::Eval(else_body.clone()));
                 } else {
-                    Ok(Value::None)
+                    self.values.push(Value::None);
                 }
             }
             Node::While(condition, body) => {
-                while evaluate_condition(condition, context)? {
-                    body.evaluate(context)?;
+                if context.condition(&self.values.pop().unwrap())? {
+                    context.check_deadline()?;
+                    let (condition, body) = (condition.clone(), body.clone());
+                    self.tasks.push(Task::Apply(node));
+                    self.tasks.push(Task::Eval(condition));
+                    self.tasks.push(Task::Discard);
+                    self.tasks.push(Task::Eval(body));
+                } else {
+                    self.values.push(Value::None);
                 }
-                Ok(Value::None)
             }
-            Node::For(init, condition, body, step) => {
-                init.evaluate(context);
-                while evaluate_condition(condition, context)? {
-                    body.evaluate(context)?;
-                    step.evaluate(context)?;
+            Node::For(_, condition, body, step) => {
+                if context.condition(&self.values.pop().unwrap())? {
+                    context.check_deadline()?;
+                    let (condition, body, step) = (condition.clone(), body.clone(), step.clone());
+                    self.tasks.push(Task::Apply(node));
+                    self.tasks.push(Task::Eval(condition));
+                    self.tasks.push(Task::Discard);
+                    self.tasks.push(Task::Eval(step));
+                    self.tasks.push(Task::Discard);
+                    self.tasks.push(Task::Eval(body));
+                } else {
+                    self.values.push(Value::None);
+                }
+            }
+            _ => unreachable!("nothing to apply for {:?}", node),
+        }
+        Ok(())
+    }
+}
+
+// Prints source the parser reads back into the same tree, with one statement
+// per line inside blocks. Boolean constants only come from constant folding
+// and print as true and false.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl Node {
+    fn write(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        match self {
+            // A unit would otherwise take in what follows, as s does in (2 m)*s
+            Node::Constant(value @ Value::Quantity(_, _)) => write!(f, "({})", value),
+            Node::Constant(value) => write!(f, "{}", value),
+            // Operations are right associative, so the left operand needs
+            // brackets at the same precedence and the right one only below it
+            Node::BinaryOperation(operation, left_node, right_node) => {
+                let precedence = operation.precedence();
+                left_node.write_operand(f, precedence + 1)?;
+                write!(f, "{}", operation)?;
+                right_node.write_operand(f, precedence)
+            }
+            Node::Variable(name, _) => write!(f, "{}", name),
+            Node::Assignment(name, _, value) => write!(f, "{} = {}", name, value),
+            Node::Block(_) => self.write_block(f, indent),
+            Node::Function(name, function) => {
+                if function.memoized {
+                    write!(f, "@memo ")?;
+                }
+                write!(f, "fn {} ", function.signature(name))?;
+                function.body.write_block(f, indent)
+            }
+            Node::Call(name, parameters) => {
+                write!(f, "{}(", name)?;
+                for (index, parameter) in parameters.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", parameter)?;
+                }
+                write!(f, ")")
+            }
+            Node::IfElse(condition, if_body, else_body) => {
+                write!(f, "if {} ", condition)?;
+                if_body.write_block(f, indent)?;
+                if let Some(else_body) = else_body {
+                    write!(f, " else ")?;
+                    else_body.write_block(f, indent)?;
                 }
-                Ok(Value::None)
+                Ok(())
+            }
+            Node::While(condition, body) => {
+                write!(f, "while {} ", condition)?;
+                body.write_block(f, indent)
+            }
+            Node::For(init, condition, body, step) => {
+                write!(f, "for {}; {}; {} ", init, condition, step)?;
+                body.write_block(f, indent)
             }
         }
     }
+
+    fn write_operand(&self, f: &mut fmt::Formatter<'_>, precedence: u8) -> fmt::Result {
+        match self {
+            Node::BinaryOperation(operation, _, _) if operation.precedence() < precedence => {
+                write!(f, "(")?;
+                self.write(f, 0)?;
+                write!(f, ")")
+            }
+            _ => self.write(f, 0),
+        }
+    }
+
+    fn write_block(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let body = match self {
+            Node::Block(body) if body.is_empty() => return write!(f, "{{}}"),
+            Node::Block(body) => body.iter().map(|statement| &**statement).collect(),
+            statement => vec![statement],
+        };
+        writeln!(f, "{{")?;
+        for statement in body {
+            write!(f, "{}", "  ".repeat(indent + 1))?;
+            statement.write(f, indent + 1)?;
+            writeln!(f, ";")?;
+        }
+        write!(f, "{}}}", "  ".repeat(indent))
+    }
+
+    pub fn evaluate(&self, context: &mut Context) -> Result<Value, Box<dyn std::error::Error>> {
+        Machine::run(Rc::new(self.clone()), context)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::node::{Context, Node, Operation, Value};
+    use crate::node::{Context, Exit, Node, Notated, Notation, Operation, Value};
+    use crate::parser::fixture;
+    use std::cell::RefCell;
+    use std::convert::TryFrom;
+    use std::rc::Rc;
+    use std::time::Duration;
     use Operation::*;
 
     fn num(num: f32) -> Node {
@@ -409,23 +1960,15 @@ mod tests {
     }
 
     fn bin(oper: Operation, left: Node, right: Node) -> Node {
-        Node::BinaryOperation(oper, Box::new(left), Box::new(right))
+        Node::BinaryOperation(oper, Rc::new(left), Rc::new(right))
     }
 
     fn ifelse(condition: Node, if_expr: Node, else_expr: Option<Node>) -> Node {
-        if else_expr.is_none() {
-            Node::IfElse(Box::new(condition), Box::new(if_expr), None)
-        } else {
-            Node::IfElse(
-                Box::new(condition),
-                Box::new(if_expr),
-                Some(Box::new(else_expr.unwrap())),
-            )
-        }
+        Node::IfElse(Rc::new(condition), Rc::new(if_expr), else_expr.map(Rc::new))
     }
 
     fn block(body: Vec<Node>) -> Node {
-        Node::Block(body)
+        Node::Block(body.into_iter().map(Rc::new).collect())
     }
 
     #[test]
@@ -456,7 +1999,7 @@ mod tests {
             minus.evaluate(&mut context).unwrap().to_number().unwrap(),
             -9.0
         );
-        assert_eq!(minus.to_string(), "1+2-3*4");
+        assert_eq!(minus.to_string(), "(1+2)-3*4");
     }
 
     #[test]
@@ -464,7 +2007,7 @@ mod tests {
         let operation = bin(Less, num(3.0), num(4.0));
         let mut context = Context::default();
         let value = operation.evaluate(&mut context).unwrap();
-        assert_eq!(value.to_bool().unwrap(), true);
+        assert!(value.to_bool().unwrap());
         assert_eq!(operation.to_string(), "3<4");
     }
 
@@ -481,6 +2024,22 @@ mod tests {
         )
     }
 
+    #[test]
+    fn equality_compares_values() {
+        let mut context = Context::default();
+        let equal = bin(Equal, bin(Plus, num(1.0), num(2.0)), num(3.0));
+        assert_eq!(equal.evaluate(&mut context).unwrap(), Value::Bool(true));
+        let unequal = bin(NotEqual, logic(true), bin(Less, num(1.0), num(2.0)));
+        assert_eq!(unequal.evaluate(&mut context).unwrap(), Value::Bool(false));
+        let nan = bin(Equal, num(f32::NAN), num(f32::NAN));
+        assert_eq!(nan.evaluate(&mut context).unwrap(), Value::Bool(false));
+        assert!(bin(Equal, num(1.0), logic(true))
+            .evaluate(&mut context)
+            .is_err());
+        assert_ne!(Value::Number(1.0), Value::Bool(true));
+        assert_eq!(Value::None, Value::None);
+    }
+
     #[test]
     fn simple_if_expression() {
         let mut context = Context::default();
@@ -504,4 +2063,296 @@ mod tests {
         let value = if_else.evaluate(&mut context).unwrap();
         assert_eq!(value.to_number().unwrap(), 7.0)
     }
+
+    #[test]
+    fn evaluation_timeout() {
+        let mut context = Context::default();
+        context.set_timeout(Some(Duration::from_millis(10)));
+        let condition = bin(Less, num(1.0), num(2.0));
+        let infinite = Node::While(Rc::new(condition), Rc::new(block(vec![num(1.0)])));
+        let value = context.evaluate(&infinite);
+        assert_eq!(
+            value.unwrap_err().to_string(),
+            "Evaluation timed out after 10 ms"
+        );
+    }
+
+    #[test]
+    fn deeply_nested_expression() {
+        let mut operation = num(0.0);
+        for _ in 0..5000 {
+            operation = bin(Plus, num(1.0), operation);
+        }
+        let mut context = Context::default();
+        let value = operation.evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 5000.0);
+    }
+
+    #[test]
+    fn definitions_share_function() {
+        let function = fixture("fn one() { 1; }");
+        let definition = Node::Function("one".to_string(), function.clone());
+        let mut context = Context::default();
+        definition.evaluate(&mut context).unwrap();
+        assert!(Rc::ptr_eq(
+            &context.overload("one", 0).unwrap().unwrap(),
+            &function
+        ));
+
+        let call = Node::Call("one".to_string(), vec![]);
+        let value = call.evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn calls_share_globals() {
+        let mut context = Context::default();
+        let assign =
+            |name: &str, value: Node| Node::Assignment(name.to_string(), None, Rc::new(value));
+        let var = |name: &str| Node::Variable(name.to_string(), None);
+        let function = fixture("fn inc() { x = x + 1; y = x; }");
+        Node::Function("inc".to_string(), function)
+            .evaluate(&mut context)
+            .unwrap();
+
+        assign("x", num(1.0)).evaluate(&mut context).unwrap();
+        Node::Call("inc".to_string(), vec![])
+            .evaluate(&mut context)
+            .unwrap();
+        let value = var("x").evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 2.0);
+        assert!(var("y").evaluate(&mut context).is_err());
+    }
+
+    #[test]
+    fn snapshots_are_independent() {
+        let mut context = Context::default();
+        let assign = |value: f32| Node::Assignment("x".to_string(), None, Rc::new(num(value)));
+        let var = Node::Variable("x".to_string(), None);
+        assign(1.0).evaluate(&mut context).unwrap();
+
+        let mut snapshot = context.clone();
+        assign(2.0).evaluate(&mut snapshot).unwrap();
+
+        let value = var.evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 1.0);
+        let value = var.evaluate(&mut snapshot).unwrap();
+        assert_eq!(value.to_number().unwrap(), 2.0);
+    }
+
+    #[test]
+    fn memoized_calls_are_cached() {
+        let mut context = Context::default();
+        let var = |name: &str| Node::Variable(name.to_string(), None);
+        let function = fixture("@memo fn twice(a) { calls = calls + 1; a * 2; }");
+        Node::Function("twice".to_string(), function)
+            .evaluate(&mut context)
+            .unwrap();
+        Node::Assignment("calls".to_string(), None, Rc::new(num(0.0)))
+            .evaluate(&mut context)
+            .unwrap();
+
+        let call = Node::Call("twice".to_string(), vec![Rc::new(num(21.0))]);
+        for _ in 0..3 {
+            let value = call.evaluate(&mut context).unwrap();
+            assert_eq!(value.to_number().unwrap(), 42.0);
+        }
+        let value = var("calls").evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn reset_keeps_settings() {
+        let mut context = Context::with_max_depth(8);
+        let function = fixture("fn one() { 1; }");
+        Node::Function("one".to_string(), function)
+            .evaluate(&mut context)
+            .unwrap();
+        Node::Assignment("x".to_string(), None, Rc::new(num(1.0)))
+            .evaluate(&mut context)
+            .unwrap();
+        assert_eq!(context.functions().count(), 1);
+        assert_eq!(context.bindings().count(), 1);
+
+        context.reset();
+        assert_eq!(context.functions().count(), 0);
+        assert!(context.bindings().next().is_none());
+        assert_eq!(context.max_depth(), 8);
+    }
+
+    #[test]
+    fn serialized_session_restores() {
+        let mut context = Context::default();
+        let function = fixture("fn add(a) { a + x; }");
+        Node::Function("add".to_string(), function)
+            .evaluate(&mut context)
+            .unwrap();
+        Node::Assignment("x".to_string(), None, Rc::new(num(2.0)))
+            .evaluate(&mut context)
+            .unwrap();
+
+        let json = serde_json::to_string(&context).unwrap();
+        let snapshot: Context = serde_json::from_str(&json).unwrap();
+        let mut restored = Context::default();
+        restored.restore(&snapshot);
+        let value = Node::Call("add".to_string(), vec![Rc::new(num(1.0))])
+            .evaluate(&mut restored)
+            .unwrap();
+        assert_eq!(value.to_number().unwrap(), 3.0);
+    }
+
+    #[test]
+    fn restores_functions_against_own_slots() {
+        let run = |source: &str, context: &mut Context| {
+            let (_, node) = crate::parser::statement(source.as_bytes()).unwrap();
+            crate::resolver::resolve(&node, context)
+                .evaluate(context)
+                .unwrap()
+        };
+        let mut snapshot = Context::default();
+        run("y = 5", &mut snapshot);
+        run("x = 2", &mut snapshot);
+        run("fn add(a) { a + x; }", &mut snapshot);
+        let mut context = Context::default();
+        run("x = 10", &mut context);
+        context.restore(&snapshot);
+        assert_eq!(run("add(1)", &mut context), Value::Number(3.0));
+    }
+
+    #[test]
+    fn serialized_tree_evaluates() {
+        // x = 2; x * 3
+        let program = block(vec![
+            Node::Assignment("x".to_string(), None, Rc::new(num(2.0))),
+            bin(Multiply, Node::Variable("x".to_string(), None), num(3.0)),
+        ]);
+        let json = serde_json::to_string(&program).unwrap();
+        assert!(json.contains("\"Multiply\""));
+        let tree: Node = serde_json::from_str(&json).unwrap();
+        let value = tree.evaluate(&mut Context::default()).unwrap();
+        assert_eq!(value.to_number().unwrap(), 6.0);
+    }
+
+    #[test]
+    fn script_arguments() {
+        let mut context = Context::default();
+        context.set_arguments(vec!["4".to_string(), "x".to_string()]);
+        let arg = |index: f32| Node::Call("arg".to_string(), vec![Rc::new(num(index))]);
+        let value = arg(0.0).evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 4.0);
+        assert!(arg(1.0).evaluate(&mut context).is_err());
+        assert!(arg(2.0).evaluate(&mut context).is_err());
+        let argc = Node::Variable("argc".to_string(), None);
+        assert_eq!(
+            argc.evaluate(&mut context).unwrap().to_number().unwrap(),
+            2.0
+        );
+    }
+
+    #[test]
+    fn exit_stops_evaluation() {
+        let mut context = Context::default();
+        // exit(3); x = 1
+        let program = block(vec![
+            Node::Call("exit".to_string(), vec![Rc::new(num(3.0))]),
+            Node::Assignment("x".to_string(), None, Rc::new(num(1.0))),
+        ]);
+        let error = program.evaluate(&mut context).unwrap_err();
+        assert_eq!(error.downcast_ref::<Exit>().unwrap().0, 3);
+        assert!(context.get_var("x").is_none());
+    }
+
+    #[test]
+    fn converts_values() {
+        assert_eq!(f32::try_from(Value::from(1.5)).unwrap(), 1.5);
+        assert_eq!(f64::try_from(Value::from(2.0f64)).unwrap(), 2.0);
+        assert!(bool::try_from(Value::from(true)).unwrap());
+        let error = f32::try_from(Value::from(false)).unwrap_err();
+        assert_eq!(error.to_string(), "false is not a number");
+        assert!(bool::try_from(Value::None).is_err());
+    }
+
+    #[test]
+    fn displays_notations() {
+        let number = Value::Number(12500.0);
+        assert_eq!(Notated(number, Notation::Fixed).to_string(), "12500");
+        assert_eq!(Notated(number, Notation::Scientific).to_string(), "1.25e4");
+        assert_eq!(Notated(number, Notation::Engineering).to_string(), "12.5e3");
+        let small = Value::Number(-0.00125);
+        assert_eq!(
+            format!("{:.1}", Notated(small, Notation::Engineering)),
+            "-1.2e-3"
+        );
+        assert_eq!(
+            format!("{:.2}", Notated(small, Notation::Scientific)),
+            "-1.25e-3"
+        );
+        assert_eq!(
+            Notated(Value::Bool(true), Notation::Scientific).to_string(),
+            "true"
+        );
+        assert_eq!("sci".parse::<Notation>(), Ok(Notation::Scientific));
+        assert!("hex".parse::<Notation>().is_err());
+    }
+
+    #[test]
+    fn observes_assignments() {
+        let mut context = Context::default();
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let observed = changes.clone();
+        context.observe("x", move |old, new| {
+            observed.borrow_mut().push((
+                old.and_then(|old| old.to_number()),
+                new.to_number().unwrap(),
+            ))
+        });
+        // x = 1; y = 5; x = x + 1
+        block(vec![
+            Node::Assignment("x".to_string(), None, Rc::new(num(1.0))),
+            Node::Assignment("y".to_string(), None, Rc::new(num(5.0))),
+            Node::Assignment(
+                "x".to_string(),
+                None,
+                Rc::new(bin(Plus, Node::Variable("x".to_string(), None), num(1.0))),
+            ),
+        ])
+        .evaluate(&mut context)
+        .unwrap();
+        assert_eq!(*changes.borrow(), vec![(None, 1.0), (Some(1.0), 2.0)]);
+
+        let snapshot = context.clone();
+        context.set_var("x", Value::Number(5.0));
+        context.restore(&snapshot);
+        assert_eq!(changes.borrow().len(), 3);
+
+        context.unobserve("x");
+        context.set_var("x", Value::Number(7.0));
+        assert_eq!(changes.borrow().len(), 3);
+    }
+
+    #[test]
+    fn traces_evaluated_nodes() {
+        let mut context = Context::default();
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let traced = lines.clone();
+        context.set_tracer(Some(Rc::new(move |line: &str| {
+            traced.borrow_mut().push(line.to_string())
+        })));
+        Node::Assignment(
+            "x".to_string(),
+            None,
+            Rc::new(bin(Plus, num(1.0), num(2.0))),
+        )
+        .evaluate(&mut context)
+        .unwrap();
+        assert_eq!(
+            *lines.borrow(),
+            vec![
+                "    1 => 1 []",
+                "    2 => 2 []",
+                "  1+2 => 3 []",
+                "x = 1+2 => None [x = 3]"
+            ]
+        );
+    }
 }
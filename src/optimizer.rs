@@ -0,0 +1,221 @@
+use crate::node::{evaluate_operation, is_true, Function, Node, Value};
+use std::rc::Rc;
+
+// Pre-evaluates subtrees made only of constants. Operations that would fail at
+// runtime are left untouched so the error still surfaces during evaluation.
+pub fn fold_constants(node: &Node) -> Node {
+    match node {
+        Node::Constant(_) | Node::Variable(_, _) => node.clone(),
+        Node::BinaryOperation(operation, left_node, right_node) => {
+            let left_node = fold(left_node);
+            let right_node = fold(right_node);
+            if let (Node::Constant(left_value), Node::Constant(right_value)) =
+                (&*left_node, &*right_node)
+            {
+                if let Ok(value) = evaluate_operation(operation, *left_value, *right_value) {
+                    return Node::Constant(value);
+                }
+            }
+            Node::BinaryOperation(*operation, left_node, right_node)
+        }
+        Node::Block(body) => Node::Block(body.iter().map(fold).collect()),
+        Node::Assignment(name, slot, value) => Node::Assignment(name.clone(), *slot, fold(value)),
+        Node::Function(name, function) => Node::Function(
+            name.clone(),
+            Rc::new(Function {
+                parameters: function.parameters.clone(),
+                types: function.types.clone(),
+                returns: function.returns,
+                memoized: function.memoized,
+                body: fold(&function.body),
+            }),
+        ),
+        Node::Call(name, parameters) => {
+            Node::Call(name.clone(), parameters.iter().map(fold).collect())
+        }
+        Node::IfElse(condition, if_body, else_body) => {
+            Node::IfElse(fold(condition), fold(if_body), else_body.as_ref().map(fold))
+        }
+        Node::While(condition, body) => Node::While(fold(condition), fold(body)),
+        Node::For(init, condition, body, step) => {
+            Node::For(fold(init), fold(condition), fold(body), fold(step))
+        }
+    }
+}
+
+fn fold(node: &Rc<Node>) -> Rc<Node> {
+    Rc::new(fold_constants(node))
+}
+
+// Prunes branches whose condition is a constant, so it is meant to run after
+// fold_constants. A description of every removed piece is pushed to warnings.
+pub fn eliminate_dead_code(node: &Node, warnings: &mut Vec<String>) -> Node {
+    match node {
+        Node::Constant(_) | Node::Variable(_, _) => node.clone(),
+        Node::BinaryOperation(operation, left_node, right_node) => Node::BinaryOperation(
+            *operation,
+            eliminate(left_node, warnings),
+            eliminate(right_node, warnings),
+        ),
+        Node::Block(body) => Node::Block(
+            body.iter()
+                .map(|expression| eliminate(expression, warnings))
+                .collect(),
+        ),
+        Node::Assignment(name, slot, value) => {
+            Node::Assignment(name.clone(), *slot, eliminate(value, warnings))
+        }
+        Node::Function(name, function) => Node::Function(
+            name.clone(),
+            Rc::new(Function {
+                parameters: function.parameters.clone(),
+                types: function.types.clone(),
+                returns: function.returns,
+                memoized: function.memoized,
+                body: eliminate(&function.body, warnings),
+            }),
+        ),
+        Node::Call(name, parameters) => Node::Call(
+            name.clone(),
+            parameters
+                .iter()
+                .map(|parameter| eliminate(parameter, warnings))
+                .collect(),
+        ),
+        Node::IfElse(condition, if_body, else_body) => match &**condition {
+            Node::Constant(value) if is_true(value) => {
+                if else_body.is_some() {
+                    warnings.push(format!("else branch of if {} is unreachable", condition));
+                }
+                eliminate_dead_code(if_body, warnings)
+            }
+            Node::Constant(_) => {
+                warnings.push(format!("if {} branch is unreachable", condition));
+                match else_body {
+                    Some(else_body) => eliminate_dead_code(else_body, warnings),
+                    None => Node::Constant(Value::None),
+                }
+            }
+            _ => Node::IfElse(
+                eliminate(condition, warnings),
+                eliminate(if_body, warnings),
+                else_body.as_ref().map(|body| eliminate(body, warnings)),
+            ),
+        },
+        Node::While(condition, body) => match &**condition {
+            Node::Constant(value) if !is_true(value) => {
+                warnings.push(format!("body of while {} is never executed", condition));
+                Node::Constant(Value::None)
+            }
+            _ => Node::While(eliminate(condition, warnings), eliminate(body, warnings)),
+        },
+        Node::For(init, condition, body, step) => match &**condition {
+            Node::Constant(value) if !is_true(value) => {
+                warnings.push(format!(
+                    "body of for loop with condition {} is never executed",
+                    condition
+                ));
+                Node::Block(vec![
+                    eliminate(init, warnings),
+                    Rc::new(Node::Constant(Value::None)),
+                ])
+            }
+            _ => Node::For(
+                eliminate(init, warnings),
+                eliminate(condition, warnings),
+                eliminate(body, warnings),
+                eliminate(step, warnings),
+            ),
+        },
+    }
+}
+
+fn eliminate(node: &Rc<Node>, warnings: &mut Vec<String>) -> Rc<Node> {
+    Rc::new(eliminate_dead_code(node, warnings))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, Operation, Value};
+    use crate::optimizer::{eliminate_dead_code, fold_constants};
+    use std::rc::Rc;
+    use Operation::*;
+
+    fn num(num: f32) -> Rc<Node> {
+        Rc::new(Node::Constant(Value::Number(num)))
+    }
+
+    fn var(name: &str) -> Rc<Node> {
+        Rc::new(Node::Variable(name.to_string(), None))
+    }
+
+    fn bin(oper: Operation, left: Rc<Node>, right: Rc<Node>) -> Rc<Node> {
+        Rc::new(Node::BinaryOperation(oper, left, right))
+    }
+
+    #[test]
+    fn folds_constant_subtree() {
+        // 2*3+x
+        let operation = bin(Plus, bin(Multiply, num(2.0), num(3.0)), var("x"));
+        let folded = fold_constants(&operation);
+        match &folded {
+            Node::BinaryOperation(Plus, left, right) => match (&**left, &**right) {
+                (Node::Constant(Value::Number(left)), Node::Variable(right, _)) => {
+                    assert_eq!(*left, 6.0);
+                    assert_eq!(right, "x");
+                }
+                other => panic!("unexpected operands {:?}", other),
+            },
+            other => panic!("unexpected node {:?}", other),
+        }
+        assert_eq!(folded.to_string(), "6+x");
+    }
+
+    #[test]
+    fn folds_whole_expression() {
+        let operation = bin(Less, bin(Minus, num(0.0), num(5.0)), num(4.0));
+        match fold_constants(&operation) {
+            Node::Constant(Value::Bool(value)) => assert!(value),
+            other => panic!("unexpected node {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keeps_ill_typed_operations() {
+        let operation = bin(Plus, bin(Less, num(1.0), num(2.0)), num(3.0));
+        assert_eq!(fold_constants(&operation).to_string(), "true+3");
+    }
+
+    #[test]
+    fn prunes_constant_if() {
+        // if 1 > 2 { 1; } else { 2; }
+        let if_else = Node::IfElse(
+            bin(More, num(1.0), num(2.0)),
+            Rc::new(Node::Block(vec![num(1.0)])),
+            Some(Rc::new(Node::Block(vec![num(2.0)]))),
+        );
+        let mut warnings = Vec::new();
+        let pruned = eliminate_dead_code(&fold_constants(&if_else), &mut warnings);
+        match &pruned {
+            Node::Block(body) => match &*body[0] {
+                Node::Constant(Value::Number(value)) => assert_eq!(*value, 2.0),
+                other => panic!("unexpected node {:?}", other),
+            },
+            other => panic!("unexpected node {:?}", other),
+        }
+        assert_eq!(warnings, vec!["if false branch is unreachable".to_string()]);
+    }
+
+    #[test]
+    fn prunes_never_running_while() {
+        let body = Rc::new(Node::Block(vec![num(1.0)]));
+        let while_loop = Node::While(bin(More, num(1.0), num(2.0)), body);
+        let mut warnings = Vec::new();
+        let pruned = eliminate_dead_code(&fold_constants(&while_loop), &mut warnings);
+        match pruned {
+            Node::Constant(Value::None) => {}
+            other => panic!("unexpected node {:?}", other),
+        }
+        assert_eq!(warnings.len(), 1);
+    }
+}
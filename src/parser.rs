@@ -1,28 +1,125 @@
-use crate::node::{Function, Node, Operation, Value};
+use crate::node::{Annotation, Function, Node, Operation, Value};
+use crate::units::Unit;
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take, take_while, take_while1};
+use nom::bytes::complete::{tag, take_while, take_while1};
+use nom::character::complete::digit1;
 use nom::character::is_alphabetic;
-use nom::combinator::{map, map_res, opt};
+use nom::combinator::{map, map_res, opt, recognize};
 use nom::error_position;
 use nom::multi::fold_many0;
 use nom::number::complete::float;
 use nom::sequence::tuple;
 use nom::IResult;
+use std::rc::Rc;
 
+// Letters and underscores starting with a letter, as in round_to
 fn identifier(input: &[u8]) -> IResult<&[u8], String> {
-    map(take_while1(is_alphabetic), |variable: &[u8]| {
-        String::from_utf8(variable.to_vec()).unwrap()
-    })(input)
+    let word = take_while(|c: u8| is_alphabetic(c) || c == b'_');
+    map(
+        recognize(tuple((take_while1(is_alphabetic), word))),
+        |variable: &[u8]| String::from_utf8(variable.to_vec()).unwrap(),
+    )(input)
+}
+
+// A function name, qualified by the namespaces it is in as in math::sqrt
+fn function_name(input: &[u8]) -> IResult<&[u8], String> {
+    let (input, first) = identifier(input)?;
+    fold_many0(
+        tuple((tag("::"), identifier)),
+        first,
+        |mut name, (_, part)| {
+            name.push_str("::");
+            name.push_str(&part);
+            name
+        },
+    )(input)
 }
 
 fn variable(input: &[u8]) -> IResult<&[u8], Node> {
-    map(identifier, |variable: String| Node::Variable(variable))(input)
+    map(identifier, |variable: String| {
+        Node::Variable(variable, None)
+    })(input)
 }
 
 fn number(input: &[u8]) -> IResult<&[u8], Node> {
     map(float, |num: f32| Node::Constant(Value::Number(num)))(input)
 }
 
+// Values Display prints that aren't plain numbers. Only infinity takes a sign,
+// other operands of - are subtracted from 0.
+fn literal(input: &[u8]) -> IResult<&[u8], Node> {
+    let (rest, minus) = opt(tag("-"))(input)?;
+    let (rest, name) = identifier(rest)?;
+    let value = match (minus.is_some(), name.as_str()) {
+        (false, "true") => Value::Bool(true),
+        (false, "false") => Value::Bool(false),
+        (false, "None") => Value::None,
+        (false, "NaN") => Value::Number(f32::NAN),
+        (false, "inf") => Value::Number(f32::INFINITY),
+        (true, "inf") => Value::Number(f32::NEG_INFINITY),
+        _ => {
+            return Err(nom::Err::Error(error_position!(
+                input,
+                nom::error::ErrorKind::Tag
+            )))
+        }
+    };
+    Ok((rest, Node::Constant(value)))
+}
+
+// A number or literal, which a unit may follow after a space as in 9.8 m/s^2
+fn constant(input: &[u8]) -> IResult<&[u8], Node> {
+    let (input, constant) = alt((number, literal))(input)?;
+    match (&constant, tuple((take_while1(|c| c == b' '), unit))(input)) {
+        (Node::Constant(Value::Number(number)), Ok((input, (_, unit)))) => {
+            Ok((input, Node::Constant(Value::quantity(*number, unit))))
+        }
+        _ => Ok((input, constant)),
+    }
+}
+
+// Base units multiplied and divided from left to right, each with an optional
+// whole power, as in kg*m/s^2
+fn unit(input: &[u8]) -> IResult<&[u8], Unit> {
+    let (rest, first) = unit_power(input)?;
+    let (rest, unit) = fold_many0(
+        tuple((alt((tag("*"), tag("/"))), unit_power)),
+        Some(first),
+        |unit, (operator, power)| match operator {
+            b"*" => unit?.times(power),
+            _ => unit?.per(power),
+        },
+    )(rest)?;
+    match unit {
+        Some(unit) => Ok((rest, unit)),
+        None => Err(nom::Err::Error(error_position!(
+            input,
+            nom::error::ErrorKind::MapRes
+        ))),
+    }
+}
+
+fn unit_power(input: &[u8]) -> IResult<&[u8], Unit> {
+    let (rest, base) = map_res(identifier, |name| Unit::base(&name).ok_or(()))(input)?;
+    let (rest, power) = opt(tuple((
+        tag("^"),
+        map_res(
+            recognize(tuple((opt(tag("-")), digit1))),
+            |power: &[u8]| String::from_utf8_lossy(power).parse::<i8>(),
+        ),
+    )))(rest)?;
+    match power {
+        Some((_, power)) => match base.pow(power) {
+            Some(unit) => Ok((rest, unit)),
+            None => Err(nom::Err::Error(error_position!(
+                input,
+                nom::error::ErrorKind::MapRes
+            ))),
+        },
+        None => Ok((rest, base)),
+    }
+}
+
 fn operation(input: &[u8]) -> IResult<&[u8], Operation> {
     map(
         alt((
@@ -44,7 +141,7 @@ fn operation(input: &[u8]) -> IResult<&[u8], Operation> {
 fn plus_minus_oper(input: &[u8]) -> IResult<&[u8], Operation> {
     let (input, operation) = operation(input)?;
     if operation == Operation::Plus || operation == Operation::Minus {
-        Ok((&input, operation))
+        Ok((input, operation))
     } else {
         Err(nom::Err::Error(error_position!(
             input,
@@ -56,7 +153,7 @@ fn plus_minus_oper(input: &[u8]) -> IResult<&[u8], Operation> {
 fn div_multi_oper(input: &[u8]) -> IResult<&[u8], Operation> {
     let (input, operation) = operation(input)?;
     if (operation == Operation::Multiply) || (operation == Operation::Divide) {
-        Ok((&input, operation))
+        Ok((input, operation))
     } else {
         Err(nom::Err::Error(error_position!(
             input,
@@ -74,7 +171,7 @@ fn logic_oper(input: &[u8]) -> IResult<&[u8], Operation> {
         || (operation == Operation::Less)
         || (operation == Operation::More)
     {
-        Ok((&input, operation))
+        Ok((input, operation))
     } else {
         Err(nom::Err::Error(error_position!(
             input,
@@ -96,17 +193,21 @@ fn unary_minus(input: &[u8]) -> IResult<&[u8], &[u8]> {
 
 fn factor(input: &[u8]) -> IResult<&[u8], Node> {
     let (input, _) = space(input)?;
+    // A negative number is a constant, the same as Display prints it
+    if let Ok((input, constant)) = constant(input) {
+        return Ok((input, constant));
+    }
     let (input, minus) = opt(unary_minus)(input)?;
     let (input, _) = space(input)?;
-    let (input, expression) = alt((number, call, variable, brackets_expression))(input)?;
+    let (input, expression) = alt((constant, call, variable, brackets_expression))(input)?;
 
     if minus.is_some() {
         Ok((
             input,
             Node::BinaryOperation(
                 Operation::Minus,
-                Box::new(Node::Constant(Value::Number(0.0))),
-                Box::new(expression),
+                Rc::new(Node::Constant(Value::Number(0.0))),
+                Rc::new(expression),
             ),
         ))
     } else {
@@ -121,7 +222,7 @@ fn logic(input: &[u8]) -> IResult<&[u8], Node> {
         let (input, right) = logic(input)?;
         Ok((
             input,
-            Node::BinaryOperation(operation, Box::new(left), Box::new(right)),
+            Node::BinaryOperation(operation, Rc::new(left), Rc::new(right)),
         ))
     } else {
         Ok((input, left))
@@ -135,7 +236,7 @@ fn term(input: &[u8]) -> IResult<&[u8], Node> {
         let (input, right) = term(input)?;
         Ok((
             input,
-            Node::BinaryOperation(operation, Box::new(left), Box::new(right)),
+            Node::BinaryOperation(operation, Rc::new(left), Rc::new(right)),
         ))
     } else {
         Ok((input, left))
@@ -150,7 +251,7 @@ fn expression(input: &[u8]) -> IResult<&[u8], Node> {
         let (input, right) = expression(input)?;
         Ok((
             input,
-            Node::BinaryOperation(operation, Box::new(left), Box::new(right)),
+            Node::BinaryOperation(operation, Rc::new(left), Rc::new(right)),
         ))
     } else {
         Ok((input, left))
@@ -161,18 +262,36 @@ fn space(input: &[u8]) -> IResult<&[u8], &[u8]> {
     take_while(|c| c == b' ')(input)
 }
 
+fn annotation(input: &[u8]) -> IResult<&[u8], Annotation> {
+    alt((
+        map(tag("number"), |_| Annotation::Number),
+        map(tag("bool"), |_| Annotation::Bool),
+    ))(input)
+}
+
+// A parameter name with an optional annotation, as in x: number
+fn parameter(input: &[u8]) -> IResult<&[u8], (String, Option<Annotation>)> {
+    let (input, name) = identifier(input)?;
+    let (input, annotation) = opt(tuple((space, tag(":"), space, annotation)))(input)?;
+    Ok((
+        input,
+        (name, annotation.map(|(_, _, _, annotation)| annotation)),
+    ))
+}
+
 fn function(input: &[u8]) -> IResult<&[u8], Node> {
+    let (input, memo) = opt(tuple((space, tag("@memo"), space)))(input)?;
     let (input, _) = skip_tag(input, "fn".to_string())?;
-    let (input, name) = identifier(input)?;
+    let (input, name) = function_name(input)?;
 
     let (input, _) = skip_tag(input, "(".to_string())?;
 
-    let (input, parameters) = if let Ok((input, param)) = identifier(input) {
+    let (input, parameters) = if let Ok((input, param)) = parameter(input) {
         fold_many0(
-            tuple((space, tag(","), space, identifier)),
+            tuple((space, tag(","), space, parameter)),
             vec![param],
-            |mut params, (_, _, _, param_name)| {
-                params.push(param_name);
+            |mut params, (_, _, _, param)| {
+                params.push(param);
                 params
             },
         )(input)?
@@ -180,30 +299,37 @@ fn function(input: &[u8]) -> IResult<&[u8], Node> {
         (input, Vec::new())
     };
     let (input, _) = skip_tag(input, ")".to_string())?;
+    let (input, returns) = opt(tuple((tag("->"), space, annotation)))(input)?;
 
     let (input, body) = body(input)?;
     let (input, _) = skip_tag(input, "}".to_string())?;
-    let boxed_body = Box::new(Node::Block(body));
+    let block = Rc::new(Node::Block(body));
     Ok((
         input,
         Node::Function(
             name,
-            Function {
-                parameters,
-                body: boxed_body,
-            },
+            Rc::new(Function {
+                parameters: parameters.iter().map(|(name, _)| name.clone()).collect(),
+                types: parameters
+                    .iter()
+                    .map(|(_, annotation)| *annotation)
+                    .collect(),
+                returns: returns.map(|(_, _, annotation)| annotation),
+                memoized: memo.is_some(),
+                body: block,
+            }),
         ),
     ))
 }
 
-fn body(input: &[u8]) -> IResult<&[u8], Vec<Node>> {
+fn body(input: &[u8]) -> IResult<&[u8], Vec<Rc<Node>>> {
     let (input, _) = space(input)?;
     let (input, _) = tag("{")(input)?;
     fold_many0(
         tuple((space, statement, space, tag(";"))),
         Vec::new(),
         |mut body, (_, statement, _, _)| {
-            body.push(statement);
+            body.push(Rc::new(statement));
             body
         },
     )(input)
@@ -211,15 +337,15 @@ fn body(input: &[u8]) -> IResult<&[u8], Vec<Node>> {
 
 fn call(input: &[u8]) -> IResult<&[u8], Node> {
     let (input, _) = space(input)?;
-    let (input, name) = identifier(input)?;
+    let (input, name) = function_name(input)?;
     let (input, _) = skip_tag(input, "(".to_string())?;
 
     let (input, parameters) = if let Ok((input, param)) = expression(input) {
         fold_many0(
             tuple((space, tag(","), space, expression)),
-            vec![param],
+            vec![Rc::new(param)],
             |mut params, (_, _, _, param)| {
-                params.push(param);
+                params.push(Rc::new(param));
                 params
             },
         )(input)?
@@ -231,7 +357,7 @@ fn call(input: &[u8]) -> IResult<&[u8], Node> {
     Ok((input, Node::Call(name, parameters)))
 }
 
-fn else_block(input: &[u8]) -> IResult<&[u8], Option<Box<Node>>> {
+fn else_block(input: &[u8]) -> IResult<&[u8], Option<Rc<Node>>> {
     let (input, _) = space(input)?;
     let (input, opt_else) = opt(tag("else"))(input)?;
     if opt_else.is_none() {
@@ -239,9 +365,9 @@ fn else_block(input: &[u8]) -> IResult<&[u8], Option<Box<Node>>> {
     } else {
         let (input, _) = space(input)?;
         let (input, body) = body(input)?;
-        let boxed_body = Box::new(Node::Block(body));
+        let block = Rc::new(Node::Block(body));
         let (input, _) = skip_tag(input, "}".to_string())?;
-        Ok((input, Some(boxed_body)))
+        Ok((input, Some(block)))
     }
 }
 
@@ -252,13 +378,10 @@ fn if_else(input: &[u8]) -> IResult<&[u8], Node> {
 
     let (input, if_body) = body(input)?;
     let (input, _) = skip_tag(input, "}".to_string())?;
-    let boxed_body = Box::new(Node::Block(if_body));
+    let block = Rc::new(Node::Block(if_body));
     let (input, else_body) = else_block(input)?;
 
-    Ok((
-        input,
-        Node::IfElse(Box::new(condition), boxed_body, else_body),
-    ))
+    Ok((input, Node::IfElse(Rc::new(condition), block, else_body)))
 }
 
 fn while_ident(input: &[u8]) -> IResult<&[u8], Node> {
@@ -268,9 +391,9 @@ fn while_ident(input: &[u8]) -> IResult<&[u8], Node> {
 
     let (input, body) = body(input)?;
     let (input, _) = skip_tag(input, "}".to_string())?;
-    let boxed_body = Box::new(Node::Block(body));
+    let block = Rc::new(Node::Block(body));
 
-    Ok((input, Node::While(Box::new(condition), boxed_body)))
+    Ok((input, Node::While(Rc::new(condition), block)))
 }
 
 fn skip_tag(input: &[u8], skipable_tag: String) -> IResult<&[u8], ()> {
@@ -291,39 +414,42 @@ fn for_ident(input: &[u8]) -> IResult<&[u8], Node> {
 
     let (input, body) = body(input)?;
     let (input, _) = skip_tag(input, "}".to_string())?;
-    let boxed_body = Box::new(Node::Block(body));
+    let block = Rc::new(Node::Block(body));
 
     Ok((
         input,
-        Node::For(
-            Box::new(init),
-            Box::new(condition),
-            boxed_body,
-            Box::new(step),
-        ),
+        Node::For(Rc::new(init), Rc::new(condition), block, Rc::new(step)),
     ))
 }
 // Backus-Naur Form of math expression
 //
-// Statement ::=  Function| While| IfElse | Assignment | Expr
+// Statement ::=  Function| While| IfElse | Assignment | Block | Expr
 //
-// Function ::= "fn" Var '(' [Var (',' Var)*]')' Body
+// Function ::= ["@memo"] "fn" Name '(' [Param (',' Param)*]')' ['->' Type] Body
+// Param ::= Var [':' Type]
+// Type ::= "number" | "bool"
 // Body ::= '{' (Statement ';')* '}'
-// Call ::= Var '(' [Expr (',' Expr)*]')'
+// Call ::= Name '(' [Expr (',' Expr)*]')'
+// Name ::= Var ('::' Var)*
 //
 // IfElse ::= "if" Expr Body ["else" Body]
 // While  ::= "while" Expr Body
 // For    ::= "for" Statement ';' Expr ';' Expr ';' Body
 //
 // Assignment ::= Var '=' Expr
-// Var ::= Char+
+// Var ::= Char (Char | '_')*
 //
 // Expr ::= Term ('+' Term | '-' Term)*
 // Term ::= Logic ('*' Logic | '/' Logic)*
 // Logic ::= Factor ('>' Factor | '<' Factor | '==' Factor | '!=' Factor | '||' Factor | '&&' Factor)*
-// Factor ::= ['-'] (Number | Call | '(' Expr ')')
+// Factor ::= Constant | ['-'] (Constant | Call | Var | '(' Expr ')')
+// Constant ::= (Number | Literal) [' '+ Unit]
+// Unit ::= Base ['^' ['-'] Digit+] (('*' | '/') Base ['^' ['-'] Digit+])*
+// Base ::= "kg" | "m" | "s" | "A" | "K" | "mol" | "cd"
 //
-// Number ::= Digit+
+// Number ::= ['-'] Digit+ ['.' Digit+]
+// Literal ::= "true" | "false" | "None" | "NaN" | ['-'] "inf"
+// Block ::= Body
 
 pub fn statement(input: &[u8]) -> IResult<&[u8], Node> {
     alt((
@@ -332,22 +458,205 @@ pub fn statement(input: &[u8]) -> IResult<&[u8], Node> {
         for_ident,
         if_else,
         assignment,
+        block,
         expression,
     ))(input)
 }
 
+// A block on its own, which is what the optimizer leaves of an if or loop with
+// a constant condition
+fn block(input: &[u8]) -> IResult<&[u8], Node> {
+    let (input, body) = body(input)?;
+    let (input, _) = skip_tag(input, "}".to_string())?;
+    Ok((input, Node::Block(body)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Token {
+    Keyword,
+    Identifier,
+    Number,
+    Operator,
+    Bracket,
+    Other,
+}
+
+const KEYWORDS: [&str; 6] = ["fn", "if", "else", "while", "for", "@memo"];
+
+// Splits input into tokens with the same parsers the grammar uses, for
+// highlighting. Each token comes with its start and end offsets.
+pub fn tokens(input: &[u8]) -> Vec<(Token, usize, usize)> {
+    let mut tokens = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let start = input.len() - rest.len();
+        let (next, token) = if rest.starts_with(b"@memo") {
+            (&rest[5..], Token::Keyword)
+        } else if let Ok((next, name)) = function_name(rest) {
+            if KEYWORDS.contains(&name.as_str()) {
+                (next, Token::Keyword)
+            } else {
+                (next, Token::Identifier)
+            }
+        } else if let Ok((next, _)) = operation(rest) {
+            (next, Token::Operator)
+        } else if let Ok((next, _)) = number(rest) {
+            (next, Token::Number)
+        } else if b"{}()".contains(&rest[0]) {
+            (&rest[1..], Token::Bracket)
+        } else {
+            // Keeps multibyte characters in one piece
+            let length = rest[1..]
+                .iter()
+                .position(|c| c.is_ascii())
+                .map_or(rest.len(), |position| position + 1);
+            (&rest[length..], Token::Other)
+        };
+        tokens.push((token, start, input.len() - next.len()));
+        rest = next;
+    }
+    tokens
+}
+
+// Input that doesn't parse yet but stops where a statement can't end, after an
+// opening bracket, a separator or an operator, so more lines are expected
+pub fn is_incomplete(input: &[u8]) -> bool {
+    if let Ok((b"", _)) = statement(input) {
+        return false;
+    }
+    let end = input.iter().rposition(|c| !c.is_ascii_whitespace());
+    match end.map(|end| input[end]) {
+        Some(c) => b"{(;,=+-*/<>|&".contains(&c),
+        None => false,
+    }
+}
+
+// More `{` or `(` opened than closed, whatever the parser makes of the text
+pub fn is_open(input: &[u8]) -> bool {
+    let mut depth = 0;
+    for c in input.iter() {
+        match c {
+            b'{' | b'(' => depth += 1,
+            b'}' | b')' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+fn skip_spaces(input: &[u8]) -> &[u8] {
+    match space(input) {
+        Ok((input, _)) => input,
+        Err(_) => input,
+    }
+}
+
+// Parses the statement at the start of a line, leaving whatever follows it
+pub fn parse_line(line: &str) -> IResult<&[u8], Node> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::debug_span!("parse", source = line).entered();
+    statement(line.as_bytes())
+}
+
+// A script holds statements on separate lines or separated by ';', and a
+// statement carries on over the next lines while it is incomplete, the same as
+// in the REPL. Every statement comes with the line it starts on.
+pub fn program(source: &str) -> Result<Vec<(usize, Node)>, Box<dyn std::error::Error>> {
+    let mut program = Vec::new();
+    for (line, parsed) in statements(source) {
+        program.push((line, parsed?));
+    }
+    Ok(program)
+}
+
+// Every syntax error in a script, so a check doesn't stop at the first one
+pub fn errors(source: &str) -> Vec<String> {
+    statements(source)
+        .into_iter()
+        .filter_map(|(_, parsed)| parsed.err())
+        .collect()
+}
+
+// Parses statements until one fails, then carries on from the next line. Each
+// statement or error comes with the line it starts on.
+pub fn statements(source: &str) -> Vec<(usize, Result<Node, String>)> {
+    let mut statements = Vec::new();
+    let mut buffer = String::new();
+    let mut start = 0;
+    for (index, line) in source.lines().enumerate() {
+        // A shebang line lets scripts be executed directly
+        if index == 0 && line.starts_with("#!") {
+            continue;
+        }
+        let line = line.trim();
+        if buffer.is_empty() {
+            if line.is_empty() {
+                continue;
+            }
+            start = index + 1;
+        } else {
+            buffer.push(' ');
+        }
+        buffer.push_str(line);
+        let input = buffer.trim_end_matches(';').as_bytes();
+        if is_open(input) || is_incomplete(input) {
+            continue;
+        }
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("parse", line = start).entered();
+        let mut rest = input;
+        while !rest.is_empty() {
+            let parsed = statement(rest).ok().and_then(|(next, node)| {
+                let next = skip_spaces(next);
+                match next.split_first() {
+                    None => Some((next, node)),
+                    Some((b';', next)) => Some((skip_spaces(next), node)),
+                    Some(_) => None,
+                }
+            });
+            match parsed {
+                Some((next, node)) => {
+                    statements.push((start, Ok(node)));
+                    rest = next;
+                }
+                None => {
+                    statements.push((start, Err(format!("{}: can't parse {}", start, buffer))));
+                    break;
+                }
+            }
+        }
+        buffer.clear();
+    }
+    if !buffer.is_empty() {
+        statements.push((
+            start,
+            Err(format!("{}: unfinished statement {}", start, buffer)),
+        ));
+    }
+    statements
+}
+
 fn assignment(input: &[u8]) -> IResult<&[u8], Node> {
     map(
         tuple((space, identifier, space, tag("="), space, expression)),
-        |(_, variable, _, _, _, expression)| Node::Assignment(variable, Box::new(expression)),
+        |(_, variable, _, _, _, expression)| Node::Assignment(variable, None, Rc::new(expression)),
     )(input)
 }
 
+// Parses a single function definition, for tests that need a function fixture
 #[cfg(test)]
+pub(crate) fn fixture(source: &str) -> Rc<Function> {
+    match program(source).unwrap().remove(0).1 {
+        Node::Function(_, function) => function,
+        other => panic!("unexpected {:?}", other),
+    }
+}
 
+#[cfg(test)]
 mod tests {
-    use crate::node::{Context, Value};
-    use crate::parser::statement;
+    use crate::node::{Annotation, Context, Node, Value};
+    use crate::parser::{errors, is_incomplete, program, statement, tokens, Token};
+    use crate::printer;
     fn eval(e: &str) -> Result<f32, Box<dyn std::error::Error>> {
         let (_, parsed) = statement(e.as_bytes()).map_err(|err| format!("{:?}", err))?;
 
@@ -382,9 +691,358 @@ mod tests {
             .map_err(|err| format!("{:?}", err))
             .unwrap();
         let mut context = Context::default();
-        let value = parsed.evaluate(&mut context).unwrap();
+        parsed.evaluate(&mut context).unwrap();
         let (_, parsed) = statement("fib(10)".as_bytes()).unwrap();
         let value = parsed.evaluate(&mut context).unwrap();
         assert_eq!(value.to_number().unwrap(), 55.0)
     }
+
+    #[test]
+    fn recursion_depth_limit() {
+        let mut context = Context::with_max_depth(16);
+        let (_, parsed) = statement("fn loop(a) { loop(a+1); }".as_bytes()).unwrap();
+        parsed.evaluate(&mut context).unwrap();
+        let (_, parsed) = statement("loop(0)".as_bytes()).unwrap();
+        let value = parsed.evaluate(&mut context);
+        assert_eq!(
+            value.unwrap_err().to_string(),
+            "Maximum recursion depth of 16 exceeded in loop"
+        );
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let mut context = Context::with_max_depth(100_000);
+        let (_, parsed) =
+            statement("fn count(a) { if a == 0 { 0; } else { count(a-1) + 1; }; }".as_bytes())
+                .unwrap();
+        parsed.evaluate(&mut context).unwrap();
+        let (_, parsed) = statement("count(20000)".as_bytes()).unwrap();
+        let value = parsed.evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 20000.0);
+    }
+
+    #[test]
+    fn memoized_fibonnaci() {
+        let (_, parsed) = statement(
+            "@memo fn fib(a) { if a < 2 { a; } else { fib(a-1) + fib(a-2); }; }".as_bytes(),
+        )
+        .unwrap();
+        let mut context = Context::default();
+        parsed.evaluate(&mut context).unwrap();
+        let (_, parsed) = statement("fib(30)".as_bytes()).unwrap();
+        let value = parsed.evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 832040.0)
+    }
+
+    #[test]
+    fn printed_source_round_trips() {
+        let source = "@memo fn f(a, b) { if a < -2 { a; } else { while b > 1 { b = b/2; }; for i = 0; i < 3; i = i + 1 { b = b - f(a, i); }; }; }";
+        let (_, parsed) = statement(source.as_bytes()).unwrap();
+        let printed = printer::source(&parsed);
+        let (rest, reparsed) = statement(printed.as_bytes()).unwrap();
+        assert_eq!(rest.len(), 0);
+        assert_eq!(printer::source(&reparsed), printed);
+    }
+
+    #[test]
+    fn parses_qualified_names() {
+        let mut context = Context::default();
+        let source = "fn math::twice(a) { a * 2; }\n\
+                      fn math::trig::zero() { 0; }\n\
+                      fn twice(a) { a; }\n\
+                      math::twice(3) + twice(1) + math::trig::zero()";
+        let value = crate::eval_str(source, &mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 7.0);
+        let names: Vec<&String> = context.functions_in("math").map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["math::trig::zero", "math::twice"]);
+        assert!(statement(b"math::twice").unwrap().0 != b"");
+    }
+
+    #[test]
+    fn parses_literals() {
+        let (_, parsed) = statement(b"x = -2.5 * true").unwrap();
+        assert_eq!(printer::sexp(&parsed), "(= x (* -2.5 true))");
+        let (_, parsed) = statement(b"-x - -inf").unwrap();
+        assert_eq!(printer::sexp(&parsed), "(- (- 0 x) -inf)");
+        let (rest, parsed) = statement(b"{ a = None; falsehood; }").unwrap();
+        assert_eq!(rest.len(), 0);
+        assert_eq!(printer::sexp(&parsed), "(block (= a None) falsehood)");
+    }
+
+    #[test]
+    fn parses_units() {
+        let (_, parsed) = statement(b"v = 9.8 m/s^2 * t").unwrap();
+        assert_eq!(printer::sexp(&parsed), "(= v (* (9.8 m/s^2) t))");
+        let (_, parsed) = statement(b"2 s^-1 + 1 kg*m").unwrap();
+        assert_eq!(parsed.to_string(), "(2 s^-1)+(1 kg*m)");
+        let reparsed = program(&parsed.to_string()).unwrap();
+        assert_eq!(printer::source(&reparsed[0].1), printer::source(&parsed));
+        let (_, parsed) = statement(b"3 * ft").unwrap();
+        assert_eq!(printer::sexp(&parsed), "(* 3 ft)");
+    }
+
+    #[test]
+    fn displays_with_needed_brackets() {
+        let (_, parsed) = statement(b"(1+2)-(3*4)").unwrap();
+        assert_eq!(parsed.to_string(), "(1+2)-3*4");
+        let (_, parsed) = statement(b"1-(2-3)*(x<2)").unwrap();
+        assert_eq!(parsed.to_string(), "1-(2-3)*x<2");
+
+        let source = "@memo fn f(a, b) { if a < -2 { a; } else { while b > 1 { b = (b-1)/2; }; for i = 0; i < 3; i = i + 1 { b = b - f(a, i); }; }; }";
+        let (_, parsed) = statement(source.as_bytes()).unwrap();
+        let displayed = parsed.to_string();
+        assert!(displayed.contains("\n    while b>1 {\n      b = (b-1)/2;\n    };\n"));
+        let reparsed = program(&displayed).unwrap();
+        assert_eq!(reparsed.len(), 1);
+        assert_eq!(printer::source(&reparsed[0].1), printer::source(&parsed));
+    }
+
+    #[test]
+    fn incomplete_statements() {
+        assert!(is_incomplete(b"fn f(a) {"));
+        assert!(is_incomplete(b"fn f(a) { if a > 1 { a; } else {"));
+        assert!(is_incomplete(b"while i < 3 { i = i +"));
+        assert!(!is_incomplete(b"fn f(a) { a; }"));
+        assert!(!is_incomplete(b"fn f(a) { a }"));
+        assert!(!is_incomplete(b""));
+    }
+
+    #[test]
+    fn tokenizes_like_parser() {
+        let tokens: Vec<Token> = tokens(b"@memo fn f(a) { a*2.5; }")
+            .into_iter()
+            .map(|(token, _, _)| token)
+            .filter(|token| *token != Token::Other)
+            .collect();
+        use Token::*;
+        assert_eq!(
+            tokens,
+            vec![
+                Keyword, Keyword, Identifier, Bracket, Identifier, Bracket, Bracket, Identifier,
+                Operator, Number, Bracket
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_line_program() {
+        let source = "fn twice(a) {\n  a * 2;\n}\n\nx = twice(\n  3\n);\nx + 1\n";
+        let statements = program(source).unwrap();
+        assert_eq!(
+            statements
+                .iter()
+                .map(|(line, _)| *line)
+                .collect::<Vec<usize>>(),
+            vec![1, 5, 8]
+        );
+        let mut context = Context::default();
+        let mut value = Value::None;
+        for (_, statement) in statements {
+            value = statement.evaluate(&mut context).unwrap();
+        }
+        assert_eq!(value.to_number().unwrap(), 7.0);
+
+        let separated = program("a = 2; a * 3\n").unwrap();
+        assert_eq!(separated.len(), 2);
+
+        let shebang = program("#!/usr/bin/env sp\nx = 1\n").unwrap();
+        assert_eq!(shebang[0].0, 2);
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let source = "x = 1 +* 2\ny = 2\nz = (3\n";
+        assert_eq!(
+            errors(source),
+            vec![
+                "1: can't parse x = 1 +* 2",
+                "3: unfinished statement z = (3"
+            ]
+        );
+        assert!(errors("a = 1; b = a\n").is_empty());
+    }
+
+    #[test]
+    fn parses_annotations() {
+        let source = "fn f(x: number, y) -> bool { x < y; }";
+        let (rest, parsed) = statement(source.as_bytes()).unwrap();
+        assert!(rest.is_empty());
+        match &parsed {
+            Node::Function(_, function) => {
+                assert_eq!(function.types, vec![Some(Annotation::Number), None]);
+                assert_eq!(function.returns, Some(Annotation::Bool));
+            }
+            other => panic!("unexpected node {:?}", other),
+        }
+        assert_eq!(
+            parsed.to_string(),
+            "fn f(x: number, y) -> bool {\n  x<y;\n}"
+        );
+    }
+}
+
+// Random statements printed back, with Display or as one line of source, have
+// to parse to the same tree, and expressions have to evaluate to the same
+// result in both evaluators
+#[cfg(test)]
+mod properties {
+    use crate::node::{Annotation, Context, Function, Node, Operation, Value};
+    use crate::parser::{program, statement};
+    use crate::{printer, resolver, vm};
+    use proptest::prelude::*;
+    use std::rc::Rc;
+
+    const OPERATIONS: [Operation; 10] = [
+        Operation::Plus,
+        Operation::Minus,
+        Operation::Divide,
+        Operation::Multiply,
+        Operation::Less,
+        Operation::More,
+        Operation::Equal,
+        Operation::NotEqual,
+        Operation::Or,
+        Operation::And,
+    ];
+
+    fn name() -> impl Strategy<Value = String> {
+        prop::sample::select(vec!["a", "b", "f"]).prop_map(str::to_string)
+    }
+
+    fn constant() -> impl Strategy<Value = Node> {
+        prop_oneof![
+            (0u16..1000).prop_map(|number| Value::Number(number as f32 / 8.0)),
+            any::<f32>().prop_map(Value::Number),
+            prop::sample::select(vec![f32::INFINITY, f32::NEG_INFINITY, f32::NAN])
+                .prop_map(Value::Number),
+            any::<bool>().prop_map(Value::Bool),
+            Just(Value::None),
+        ]
+        .prop_map(Node::Constant)
+    }
+
+    // Variables hold a number and a bool
+    fn expression() -> impl Strategy<Value = Node> {
+        let leaf = prop_oneof![
+            constant(),
+            prop::sample::select(vec!["a", "b"])
+                .prop_map(|name| Node::Variable(name.to_string(), None)),
+        ];
+        leaf.prop_recursive(6, 64, 2, |inner| {
+            (
+                prop::sample::select(OPERATIONS.to_vec()),
+                inner.clone(),
+                inner,
+            )
+                .prop_map(|(operation, left, right)| {
+                    Node::BinaryOperation(operation, Rc::new(left), Rc::new(right))
+                })
+        })
+    }
+
+    fn annotation() -> impl Strategy<Value = Option<Annotation>> {
+        prop_oneof![
+            Just(None),
+            Just(Some(Annotation::Number)),
+            Just(Some(Annotation::Bool)),
+        ]
+    }
+
+    // Every kind of node in the places the parser and optimizer put them
+    fn statement_node() -> impl Strategy<Value = Node> {
+        let call =
+            (name(), prop::collection::vec(expression(), 0..3)).prop_map(|(name, arguments)| {
+                Node::Call(name, arguments.into_iter().map(Rc::new).collect())
+            });
+        prop_oneof![expression(), call].prop_recursive(3, 32, 3, |inner| {
+            let block = prop::collection::vec(inner.clone(), 0..3)
+                .prop_map(|body| Rc::new(Node::Block(body.into_iter().map(Rc::new).collect())));
+            let parameters = prop::collection::vec((name(), annotation()), 0..3);
+            prop_oneof![
+                (name(), expression()).prop_map(|(name, value)| Node::Assignment(
+                    name,
+                    None,
+                    Rc::new(value)
+                )),
+                block.clone().prop_map(|block| (*block).clone()),
+                (expression(), block.clone(), prop::option::of(block.clone())).prop_map(
+                    |(condition, if_body, else_body)| {
+                        Node::IfElse(Rc::new(condition), if_body, else_body)
+                    }
+                ),
+                (expression(), block.clone())
+                    .prop_map(|(condition, body)| Node::While(Rc::new(condition), body)),
+                (inner.clone(), expression(), block.clone(), inner).prop_map(
+                    |(init, condition, body, step)| {
+                        Node::For(Rc::new(init), Rc::new(condition), body, Rc::new(step))
+                    }
+                ),
+                (name(), parameters, annotation(), any::<bool>(), block).prop_map(
+                    |(name, parameters, returns, memoized, body)| {
+                        let function = Function {
+                            parameters: parameters.iter().map(|(name, _)| name.clone()).collect(),
+                            types: parameters
+                                .iter()
+                                .map(|(_, annotation)| *annotation)
+                                .collect(),
+                            returns,
+                            memoized,
+                            body,
+                        };
+                        Node::Function(name, Rc::new(function))
+                    }
+                ),
+            ]
+        })
+    }
+
+    fn evaluate(node: &Node, use_vm: bool) -> Result<String, String> {
+        let mut context = Context::default();
+        context.set_var("a", Value::Number(3.0));
+        context.set_var("b", Value::Bool(true));
+        let node = resolver::resolve(node, &context);
+        let value = match use_vm {
+            true => vm::evaluate(&node, &mut context),
+            false => context.evaluate(&node),
+        };
+        value
+            .map(|value| value.to_string())
+            .map_err(|error| error.to_string())
+    }
+
+    proptest! {
+        #[test]
+        fn printed_source_parses_back(node in statement_node()) {
+            let source = printer::source(&node);
+            let (rest, parsed) = statement(source.as_bytes()).unwrap();
+            prop_assert!(rest.is_empty(), "{} left over", source);
+            prop_assert_eq!(printer::sexp(&parsed), printer::sexp(&node));
+        }
+
+        #[test]
+        fn displayed_statements_parse_back(node in statement_node()) {
+            let displayed = node.to_string();
+            let parsed = program(&displayed).unwrap();
+            prop_assert_eq!(parsed.len(), 1);
+            prop_assert_eq!(printer::sexp(&parsed[0].1), printer::sexp(&node));
+            prop_assert_eq!(parsed[0].1.to_string(), displayed);
+        }
+
+        #[test]
+        fn reparsed_expressions_evaluate_the_same(node in expression()) {
+            let (_, parsed) = statement(node.to_string().as_bytes()).unwrap();
+            prop_assert_eq!(evaluate(&parsed, false), evaluate(&node, false));
+        }
+
+        #[test]
+        fn evaluators_agree(node in expression()) {
+            let machine = evaluate(&node, false);
+            let vm = evaluate(&node, true);
+            prop_assert_eq!(machine.is_ok(), vm.is_ok());
+            if machine.is_ok() {
+                prop_assert_eq!(machine, vm);
+            }
+        }
+    }
 }
@@ -0,0 +1,251 @@
+use crate::node::{Node, Value};
+use crate::parser;
+
+// Prints a node back as a single line of source the parser accepts. Binary
+// operations are fully parenthesized because the grammar has no precedence
+// for logical operators.
+pub fn source(node: &Node) -> String {
+    match node {
+        Node::Constant(value) => constant(value),
+        Node::BinaryOperation(operation, left_node, right_node) => {
+            format!("({}{}{})", source(left_node), operation, source(right_node))
+        }
+        Node::Variable(name, _) => name.clone(),
+        Node::Assignment(name, _, value) => format!("{} = {}", name, source(value)),
+        Node::Block(_) => block(node),
+        Node::Function(name, function) => {
+            let prefix = if function.memoized { "@memo fn" } else { "fn" };
+            format!(
+                "{} {} {}",
+                prefix,
+                function.signature(name),
+                block(&function.body)
+            )
+        }
+        Node::Call(name, parameters) => format!(
+            "{}({})",
+            name,
+            parameters
+                .iter()
+                .map(|parameter| source(parameter))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Node::IfElse(condition, if_body, else_body) => {
+            let result = format!("if {} {}", source(condition), block(if_body));
+            match else_body {
+                Some(else_body) => format!("{} else {}", result, block(else_body)),
+                None => result,
+            }
+        }
+        Node::While(condition, body) => format!("while {} {}", source(condition), block(body)),
+        Node::For(init, condition, body, step) => format!(
+            "for {}; {}; {} {}",
+            source(init),
+            source(condition),
+            source(step),
+            block(body)
+        ),
+    }
+}
+
+fn constant(value: &Value) -> String {
+    match value {
+        Value::Number(number) if *number < 0.0 => format!("({})", number),
+        Value::Quantity(_, _) => format!("({})", value),
+        value => value.to_string(),
+    }
+}
+
+fn block(node: &Node) -> String {
+    let body = match node {
+        Node::Block(body) => body
+            .iter()
+            .map(|expression| source(expression) + "; ")
+            .collect::<String>(),
+        _ => source(node) + "; ",
+    };
+    format!("{{ {}}}", body)
+}
+
+// Prints a node as one S-expression, (+ 1 (* 2 3)) for 1+2*3, which keeps
+// golden test output short and easy to diff
+pub fn sexp(node: &Node) -> String {
+    let list = |head: String, items: Vec<&Node>| {
+        let items: Vec<String> = items.into_iter().map(sexp).collect();
+        match items.is_empty() {
+            true => format!("({})", head),
+            false => format!("({} {})", head, items.join(" ")),
+        }
+    };
+    match node {
+        // a quantity is grouped so its unit doesn't read as another operand
+        Node::Constant(value @ Value::Quantity(_, _)) => format!("({})", value),
+        Node::Constant(value) => value.to_string(),
+        Node::BinaryOperation(operation, left_node, right_node) => {
+            list(operation.to_string(), vec![left_node, right_node])
+        }
+        Node::Variable(name, _) => name.clone(),
+        Node::Assignment(name, _, value) => list(format!("= {}", name), vec![value]),
+        Node::Block(body) => list(
+            "block".to_string(),
+            body.iter().map(|expression| &**expression).collect(),
+        ),
+        Node::Function(name, function) => {
+            let head = if function.memoized { "memo-fn" } else { "fn" };
+            let head = format!("{} {} ({})", head, name, function.parameters.join(" "));
+            list(head, vec![&function.body])
+        }
+        Node::Call(name, parameters) => list(
+            format!("call {}", name),
+            parameters.iter().map(|parameter| &**parameter).collect(),
+        ),
+        Node::IfElse(condition, if_body, else_body) => {
+            let mut items: Vec<&Node> = vec![condition, if_body];
+            items.extend(else_body.as_deref());
+            list("if".to_string(), items)
+        }
+        Node::While(condition, body) => list("while".to_string(), vec![condition, body]),
+        Node::For(init, condition, body, step) => {
+            list("for".to_string(), vec![init, condition, step, body])
+        }
+    }
+}
+
+// Rewrites a script with one top level statement per line, laid out the way
+// Node's Display prints it. A shebang line is kept as it is.
+pub fn format(source: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut result = String::new();
+    if let Some(shebang) = source.lines().next().filter(|line| line.starts_with("#!")) {
+        result.push_str(shebang);
+        result.push('\n');
+    }
+    for (_, statement) in parser::program(source)? {
+        result.push_str(&statement.to_string());
+        result.push('\n');
+    }
+    Ok(result)
+}
+
+// Prints a node as an indented tree, one node per line with its children
+// below it, to show how a statement was actually parsed
+pub fn tree(node: &Node) -> String {
+    let mut result = String::new();
+    write_tree(node, 0, &mut result);
+    result
+}
+
+fn write_tree(node: &Node, depth: usize, result: &mut String) {
+    let label = match node {
+        Node::Constant(value) => format!("Constant {}", value),
+        Node::BinaryOperation(operation, _, _) => {
+            format!("BinaryOperation {}", operation)
+        }
+        Node::Variable(name, _) => format!("Variable {}", name),
+        Node::Assignment(name, _, _) => format!("Assignment {}", name),
+        Node::Block(_) => "Block".to_string(),
+        Node::Function(name, function) => format!(
+            "{} {}",
+            if function.memoized {
+                "@memo Function"
+            } else {
+                "Function"
+            },
+            function.signature(name)
+        ),
+        Node::Call(name, _) => format!("Call {}", name),
+        Node::IfElse(_, _, _) => "IfElse".to_string(),
+        Node::While(_, _) => "While".to_string(),
+        Node::For(_, _, _, _) => "For".to_string(),
+    };
+    result.push_str(&"  ".repeat(depth));
+    result.push_str(&label);
+    result.push('\n');
+    let children: Vec<&Node> = match node {
+        Node::Constant(_) | Node::Variable(_, _) => vec![],
+        Node::BinaryOperation(_, left_node, right_node) => vec![left_node, right_node],
+        Node::Assignment(_, _, value) => vec![value],
+        Node::Block(body) => body.iter().map(|expression| &**expression).collect(),
+        Node::Function(_, function) => vec![&function.body],
+        Node::Call(_, parameters) => parameters.iter().map(|parameter| &**parameter).collect(),
+        Node::IfElse(condition, if_body, else_body) => {
+            let mut children: Vec<&Node> = vec![condition, if_body];
+            children.extend(else_body.as_deref());
+            children
+        }
+        Node::While(condition, body) => vec![condition, body],
+        Node::For(init, condition, body, step) => vec![init, condition, step, body],
+    };
+    for child in children {
+        write_tree(child, depth + 1, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Node, Operation, Value};
+    use crate::parser::statement;
+    use crate::printer::{format, sexp, source, tree};
+    use std::rc::Rc;
+
+    fn num(num: f32) -> Rc<Node> {
+        Rc::new(Node::Constant(Value::Number(num)))
+    }
+
+    #[test]
+    fn prints_single_line() {
+        // while x < 3 { x = x + 1; }
+        let x = Rc::new(Node::Variable("x".to_string(), None));
+        let while_loop = Node::While(
+            Rc::new(Node::BinaryOperation(Operation::Less, x.clone(), num(3.0))),
+            Rc::new(Node::Block(vec![Rc::new(Node::Assignment(
+                "x".to_string(),
+                None,
+                Rc::new(Node::BinaryOperation(Operation::Plus, x, num(-1.5))),
+            ))])),
+        );
+        assert_eq!(source(&while_loop), "while (x<3) { x = (x+(-1.5)); }");
+    }
+
+    #[test]
+    fn prints_tree() {
+        // x = 1 + 2 * 3
+        let assignment = Node::Assignment(
+            "x".to_string(),
+            None,
+            Rc::new(Node::BinaryOperation(
+                Operation::Plus,
+                num(1.0),
+                Rc::new(Node::BinaryOperation(
+                    Operation::Multiply,
+                    num(2.0),
+                    num(3.0),
+                )),
+            )),
+        );
+        assert_eq!(
+            tree(&assignment),
+            "Assignment x\n  BinaryOperation +\n    Constant 1\n    BinaryOperation *\n      Constant 2\n      Constant 3\n"
+        );
+    }
+
+    #[test]
+    fn formats_script() {
+        let source = "#!/usr/bin/env sp\nfn f(a) {  a*2;\n}\nx=f(\n 3);   x+1;\n";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "#!/usr/bin/env sp\nfn f(a) {\n  a*2;\n}\nx = f(3)\nx+1\n"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn prints_sexp() {
+        let (_, parsed) = statement(b"fn f(a, b) { if a < 2 { g(a, 1+2*b); }; }").unwrap();
+        assert_eq!(
+            sexp(&parsed),
+            "(fn f (a b) (block (if (< a 2) (block (call g a (+ 1 (* 2 b)))))))"
+        );
+    }
+}
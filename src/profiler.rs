@@ -0,0 +1,89 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+#[derive(Default, Clone, Copy)]
+struct Calls {
+    count: u64,
+    time: Duration,
+}
+
+// Counts evaluation steps and calls per function. A function's time includes
+// the calls it makes, but recursive calls are only timed at the outermost one.
+#[derive(Default)]
+pub struct Profile {
+    steps: u64,
+    functions: BTreeMap<String, Calls>,
+    active: Vec<(String, Instant)>,
+}
+
+impl Profile {
+    pub(crate) fn step(&mut self) {
+        self.steps += 1;
+    }
+
+    pub(crate) fn enter(&mut self, name: &str) {
+        self.active.push((name.to_string(), Instant::now()));
+    }
+
+    pub(crate) fn leave(&mut self) {
+        if let Some((name, start)) = self.active.pop() {
+            let recursive = self.active.iter().any(|(active, _)| *active == name);
+            let calls = self.functions.entry(name).or_default();
+            calls.count += 1;
+            if !recursive {
+                calls.time += start.elapsed();
+            }
+        }
+    }
+
+    // Forgets calls that were interrupted by an error
+    pub(crate) fn unwind(&mut self, depth: usize) {
+        self.active.truncate(depth);
+    }
+
+    pub fn report(&self) -> String {
+        let mut functions: Vec<(&String, &Calls)> = self.functions.iter().collect();
+        functions.sort_by_key(|(_, calls)| Reverse(calls.time));
+
+        let mut report = format!("{} evaluation steps\n", self.steps);
+        report.push_str(&format!(
+            "{:<16} {:>10} {:>12}\n",
+            "function", "calls", "time (ms)"
+        ));
+        for (name, calls) in functions {
+            report.push_str(&format!(
+                "{:<16} {:>10} {:>12.3}\n",
+                name,
+                calls.count,
+                calls.time.as_secs_f64() * 1000.0
+            ));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profiler::Profile;
+
+    #[test]
+    fn counts_recursive_calls() {
+        let mut profile = Profile::default();
+        profile.enter("f");
+        profile.enter("f");
+        profile.step();
+        profile.leave();
+        profile.leave();
+        profile.enter("g");
+        profile.leave();
+
+        let report = profile.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "1 evaluation steps");
+        assert_eq!(lines.len(), 4);
+        assert!(lines
+            .iter()
+            .any(|line| line.starts_with("f ") && line.contains(" 2 ")));
+    }
+}
@@ -0,0 +1,121 @@
+use crate::node::{Context, Function, Node};
+use std::rc::Rc;
+
+// Maps every variable reference to its slot in the Context, so evaluation indexes
+// the variable table instead of looking names up. Slots are shared by a Context
+// and its clones, so the resolved tree must only be evaluated against those.
+pub fn resolve(node: &Node, context: &Context) -> Node {
+    match node {
+        Node::Constant(_) => node.clone(),
+        Node::Variable(name, _) => Node::Variable(name.clone(), Some(context.slot(name))),
+        Node::BinaryOperation(operation, left_node, right_node) => Node::BinaryOperation(
+            *operation,
+            resolve_child(left_node, context),
+            resolve_child(right_node, context),
+        ),
+        Node::Block(body) => Node::Block(
+            body.iter()
+                .map(|expression| resolve_child(expression, context))
+                .collect(),
+        ),
+        Node::Assignment(name, _, value) => Node::Assignment(
+            name.clone(),
+            Some(context.slot(name)),
+            resolve_child(value, context),
+        ),
+        Node::Function(name, function) => {
+            Node::Function(name.clone(), resolve_function(function, context))
+        }
+        Node::Call(name, parameters) => Node::Call(
+            name.clone(),
+            parameters
+                .iter()
+                .map(|parameter| resolve_child(parameter, context))
+                .collect(),
+        ),
+        Node::IfElse(condition, if_body, else_body) => Node::IfElse(
+            resolve_child(condition, context),
+            resolve_child(if_body, context),
+            else_body.as_ref().map(|body| resolve_child(body, context)),
+        ),
+        Node::While(condition, body) => Node::While(
+            resolve_child(condition, context),
+            resolve_child(body, context),
+        ),
+        Node::For(init, condition, body, step) => Node::For(
+            resolve_child(init, context),
+            resolve_child(condition, context),
+            resolve_child(body, context),
+            resolve_child(step, context),
+        ),
+    }
+}
+
+pub(crate) fn resolve_function(function: &Function, context: &Context) -> Rc<Function> {
+    for parameter in function.parameters.iter() {
+        context.slot(parameter);
+    }
+    Rc::new(Function {
+        parameters: function.parameters.clone(),
+        types: function.types.clone(),
+        returns: function.returns,
+        memoized: function.memoized,
+        body: resolve_child(&function.body, context),
+    })
+}
+
+fn resolve_child(node: &Rc<Node>, context: &Context) -> Rc<Node> {
+    Rc::new(resolve(node, context))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Context, Node, Operation, Value};
+    use crate::resolver::resolve;
+    use std::rc::Rc;
+
+    fn var(name: &str) -> Rc<Node> {
+        Rc::new(Node::Variable(name.to_string(), None))
+    }
+
+    #[test]
+    fn resolves_to_shared_slots() {
+        // x = 2; y = x * 3; y
+        let program = Node::Block(vec![
+            Rc::new(Node::Assignment(
+                "x".to_string(),
+                None,
+                Rc::new(Node::Constant(Value::Number(2.0))),
+            )),
+            Rc::new(Node::Assignment(
+                "y".to_string(),
+                None,
+                Rc::new(Node::BinaryOperation(
+                    Operation::Multiply,
+                    var("x"),
+                    Rc::new(Node::Constant(Value::Number(3.0))),
+                )),
+            )),
+            var("y"),
+        ]);
+        let mut context = Context::default();
+        let resolved = resolve(&program, &context);
+
+        match &resolved {
+            Node::Block(body) => match (&*body[0], &*body[2]) {
+                (Node::Assignment(_, Some(x), _), Node::Variable(_, Some(y))) => {
+                    assert_eq!(*x, context.slot("x"));
+                    assert_eq!(*y, context.slot("y"));
+                    assert_ne!(x, y);
+                }
+                other => panic!("unexpected nodes {:?}", other),
+            },
+            other => panic!("unexpected node {:?}", other),
+        }
+
+        let value = resolved.evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 6.0);
+        let value = var("x").evaluate(&mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 2.0);
+    }
+}
@@ -0,0 +1,289 @@
+use crate::node::{Annotation, Context, Function, Node, Operation, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Type {
+    Number,
+    Bool,
+    None,
+    // Parameters, results of host functions and variables given values of
+    // different types, which are only checked when evaluated
+    Unknown,
+}
+
+impl Type {
+    pub(crate) fn of(value: &Value) -> Type {
+        match value {
+            Value::Number(_) => Type::Number,
+            Value::Bool(_) => Type::Bool,
+            Value::None => Type::None,
+            // Whether a quantity fits depends on its unit, left to evaluation
+            Value::Quantity(_, _) => Type::Unknown,
+        }
+    }
+
+    fn join(self, other: Type) -> Type {
+        match self == other {
+            true => self,
+            false => Type::Unknown,
+        }
+    }
+}
+
+impl From<Annotation> for Type {
+    fn from(annotation: Annotation) -> Type {
+        match annotation {
+            Annotation::Number => Type::Number,
+            Annotation::Bool => Type::Bool,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Number => write!(f, "number"),
+            Type::Bool => write!(f, "bool"),
+            Type::None => write!(f, "None"),
+            Type::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+// Infers types through statements in the order they would run and collects
+// the operations that would fail on them. Only what is certain is reported,
+// anything involving an unknown type is left to evaluation.
+#[derive(Default)]
+pub struct Checker {
+    variables: HashMap<String, Type>,
+    // Definitions by name and arity with what each returns when called
+    functions: HashMap<(String, usize), (Rc<Function>, Type)>,
+    errors: Vec<String>,
+}
+
+impl Checker {
+    // Starts from what a session has defined, variables have the types of
+    // their current values
+    pub fn from_context(context: &Context) -> Checker {
+        let mut checker = Checker::default();
+        for (name, value) in context.bindings() {
+            checker.variables.insert(name, Type::of(&value));
+        }
+        for (name, function) in context.functions() {
+            checker.infer(&Node::Function(name.clone(), Rc::new(function.clone())));
+        }
+        checker.errors.clear();
+        checker
+    }
+
+    pub fn infer(&mut self, node: &Node) -> Type {
+        match node {
+            Node::Constant(value) => Type::of(value),
+            Node::Variable(name, _) => self.variables.get(name).cloned().unwrap_or(Type::Unknown),
+            Node::BinaryOperation(operation, left_node, right_node) => {
+                let left = self.infer(left_node);
+                let right = self.infer(right_node);
+                if let Some(error) = operation_error(operation, left, right) {
+                    self.errors.push(format!("{} in {}", error, node));
+                }
+                match operation.is_arithmetic() {
+                    true => Type::Number,
+                    false => Type::Bool,
+                }
+            }
+            Node::Block(body) => body
+                .iter()
+                .map(|node| self.infer(node))
+                .last()
+                .unwrap_or(Type::None),
+            Node::Assignment(name, _, value) => {
+                let value = self.infer(value);
+                let assigned = match self.variables.get(name) {
+                    Some(previous) => previous.join(value),
+                    None => value,
+                };
+                self.variables.insert(name.clone(), assigned);
+                Type::None
+            }
+            Node::Function(name, function) => {
+                let globals = self.variables.clone();
+                for (index, parameter) in function.parameters.iter().enumerate() {
+                    let kind = function
+                        .parameter_type(index)
+                        .map_or(Type::Unknown, Type::from);
+                    self.variables.insert(parameter.clone(), kind);
+                }
+                let mut result = self.infer(&function.body);
+                self.variables = globals;
+                if let Some(annotation) = function.returns {
+                    if result != Type::Unknown && result != Type::from(annotation) {
+                        self.errors.push(format!(
+                            "{} should return a {}, its body gives {}",
+                            name, annotation, result
+                        ));
+                    }
+                    result = Type::from(annotation);
+                }
+                let key = (name.clone(), function.parameters.len());
+                self.functions.insert(key, (function.clone(), result));
+                Type::None
+            }
+            Node::Call(name, parameters) => {
+                let arguments: Vec<Type> = parameters.iter().map(|node| self.infer(node)).collect();
+                let key = (name.clone(), arguments.len());
+                let (function, result) = match self.functions.get(&key) {
+                    Some(function) => function.clone(),
+                    None => return Type::Unknown,
+                };
+                for (index, argument) in arguments.into_iter().enumerate() {
+                    match function.parameter_type(index) {
+                        Some(annotation)
+                            if argument != Type::Unknown && argument != Type::from(annotation) =>
+                        {
+                            self.errors.push(format!(
+                                "{} expects {} to be a {}, got {} in {}",
+                                name, function.parameters[index], annotation, argument, node
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+                result
+            }
+            Node::IfElse(condition, if_body, else_body) => {
+                self.infer(condition);
+                let if_type = self.infer(if_body);
+                match else_body {
+                    Some(else_body) => if_type.join(self.infer(else_body)),
+                    None => if_type.join(Type::None),
+                }
+            }
+            Node::While(condition, body) => {
+                self.infer(condition);
+                self.infer(body);
+                Type::None
+            }
+            Node::For(init, condition, body, step) => {
+                self.infer(init);
+                self.infer(condition);
+                self.infer(body);
+                self.infer(step);
+                Type::None
+            }
+        }
+    }
+
+    // Errors found since the last call
+    pub fn take_errors(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.errors)
+    }
+}
+
+pub(crate) fn operation_error(operation: &Operation, left: Type, right: Type) -> Option<String> {
+    match (left, right) {
+        (Type::Unknown, _) | (_, Type::Unknown) => None,
+        _ if operation.is_arithmetic() => match (left, right) {
+            (Type::Bool, _) | (_, Type::Bool) => Some("arithmetic on bool".to_string()),
+            (Type::None, _) | (_, Type::None) => Some("arithmetic on None".to_string()),
+            _ => None,
+        },
+        (Type::None, _) | (_, Type::None) => Some(format!("None as operand of {}", operation)),
+        (Type::Number, Type::Bool) | (Type::Bool, Type::Number) => {
+            Some(format!("{} between number and bool", operation))
+        }
+        (Type::Number, _) => match operation {
+            Operation::Or | Operation::And => Some(format!("{} on numbers", operation)),
+            _ => None,
+        },
+        _ => match operation {
+            Operation::Less | Operation::More => Some(format!("{} on bools", operation)),
+            _ => None,
+        },
+    }
+}
+
+// Type errors of a whole program, each prefixed with the line of its statement
+pub fn check(program: &[(usize, Node)]) -> Vec<String> {
+    let mut checker = Checker::default();
+    let mut errors = Vec::new();
+    for (line, node) in program.iter() {
+        checker.infer(node);
+        for error in checker.take_errors() {
+            errors.push(format!("{}: {}", line, error));
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eval_str;
+    use crate::node::{Context, Value};
+    use crate::parser::{program, statement};
+    use crate::typecheck::{check, Checker, Type};
+
+    #[test]
+    fn infers_types() {
+        let mut checker = Checker::default();
+        for source in ["x = 1 < 2", "fn f(a) { a * 2; }"].iter() {
+            let (_, node) = statement(source.as_bytes()).unwrap();
+            assert_eq!(checker.infer(&node), Type::None);
+        }
+        let (_, node) = statement(b"x == (1 > 0)").unwrap();
+        assert_eq!(checker.infer(&node), Type::Bool);
+        let (_, node) = statement(b"f(x)").unwrap();
+        assert_eq!(checker.infer(&node), Type::Number);
+        assert!(checker.take_errors().is_empty());
+    }
+
+    #[test]
+    fn reports_ill_typed_operations() {
+        let source = "flag = 1 < 2\n\
+                      y = flag * 3\n\
+                      fn f(a) { a + flag; }\n\
+                      if flag { z = 1 || 2; }\n\
+                      flag = 4\n\
+                      flag + 1";
+        assert_eq!(
+            check(&program(source).unwrap()),
+            vec![
+                "2: arithmetic on bool in flag*3".to_string(),
+                "4: || on numbers in 1||2".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_annotations() {
+        let source = "fn half(x: number) -> number { x / 2; }\n\
+                      fn both(a: bool, b: bool) -> bool { a && b; }\n\
+                      fn wrong(a: bool) -> number { a; }\n\
+                      half(1 < 2) + 1\n\
+                      both(half(4) == 2, 1 < 2)";
+        assert_eq!(
+            check(&program(source).unwrap()),
+            vec![
+                "3: wrong should return a number, its body gives bool".to_string(),
+                "4: half expects x to be a number, got bool in half(1<2)".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn starts_from_a_session() {
+        let mut context = Context::default();
+        eval_str("fn half(x: number) { x / 2; }\nready = 1 < 2", &mut context).unwrap();
+        context.set_var("count", Value::Number(3.0));
+        let mut checker = Checker::from_context(&context);
+        let (_, node) = statement(b"half(count) + 1").unwrap();
+        assert_eq!(checker.infer(&node), Type::Number);
+        let (_, node) = statement(b"ready && count").unwrap();
+        assert_eq!(checker.infer(&node), Type::Bool);
+        assert_eq!(
+            checker.take_errors(),
+            vec!["&& between number and bool in ready&&count".to_string()]
+        );
+    }
+}
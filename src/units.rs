@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// SI base units in the order a unit is written
+pub const BASE_UNITS: [&str; 7] = ["kg", "m", "s", "A", "K", "mol", "cd"];
+
+// Powers of the base units a quantity is measured in, m/s^2 has m to the 1
+// and s to the -2. A unit with every power at zero is a plain number.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Unit([i8; 7]);
+
+impl Unit {
+    pub fn base(name: &str) -> Option<Unit> {
+        let index = BASE_UNITS.iter().position(|base| *base == name)?;
+        let mut powers = [0; 7];
+        powers[index] = 1;
+        Some(Unit(powers))
+    }
+
+    pub fn is_dimensionless(&self) -> bool {
+        self.0.iter().all(|power| *power == 0)
+    }
+
+    // None when a power gets too large to keep
+    pub fn times(self, other: Unit) -> Option<Unit> {
+        self.combine(other, i8::checked_add)
+    }
+
+    pub fn per(self, other: Unit) -> Option<Unit> {
+        self.combine(other, i8::checked_sub)
+    }
+
+    pub fn pow(self, exponent: i8) -> Option<Unit> {
+        self.combine(self, |power, _| power.checked_mul(exponent))
+    }
+
+    fn combine<F>(self, other: Unit, operation: F) -> Option<Unit>
+    where
+        F: Fn(i8, i8) -> Option<i8>,
+    {
+        let mut powers = [0; 7];
+        for (index, power) in powers.iter_mut().enumerate() {
+            *power = operation(self.0[index], other.0[index])?;
+        }
+        Some(Unit(powers))
+    }
+}
+
+// Written the way the parser reads units back: kg*m/s^2, or s^-1 when
+// nothing is multiplied
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let factor = |name: &str, power: i16| match power {
+            1 => name.to_string(),
+            power => format!("{}^{}", name, power),
+        };
+        let powers = BASE_UNITS.iter().zip(self.0.iter());
+        let above: Vec<String> = powers
+            .clone()
+            .filter(|(_, power)| **power > 0)
+            .map(|(name, power)| factor(name, i16::from(*power)))
+            .collect();
+        let below = powers.filter(|(_, power)| **power < 0);
+        if above.is_empty() {
+            let below: Vec<String> = below
+                .map(|(name, power)| factor(name, i16::from(*power)))
+                .collect();
+            return write!(f, "{}", below.join("*"));
+        }
+        write!(f, "{}", above.join("*"))?;
+        for (name, power) in below {
+            write!(f, "/{}", factor(name, -i16::from(*power)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::units::Unit;
+
+    #[test]
+    fn composes_units() {
+        let (metre, second) = (Unit::base("m").unwrap(), Unit::base("s").unwrap());
+        let speed = metre.per(second).unwrap();
+        let acceleration = speed.per(second).unwrap();
+        assert_eq!(acceleration.to_string(), "m/s^2");
+        let force = Unit::base("kg").unwrap().times(acceleration).unwrap();
+        assert_eq!(force.to_string(), "kg*m/s^2");
+        assert_eq!(Unit::default().per(second).unwrap().to_string(), "s^-1");
+        assert!(speed
+            .times(second)
+            .unwrap()
+            .per(metre)
+            .unwrap()
+            .is_dimensionless());
+        assert!(metre
+            .pow(100)
+            .unwrap()
+            .times(metre.pow(100).unwrap())
+            .is_none());
+        assert!(Unit::base("ft").is_none());
+    }
+}
@@ -0,0 +1,527 @@
+use crate::node::{
+    async_call_error, call_builtin, evaluate_operation, Context, Function, Node, Operation, Value,
+    ValueKey,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone)]
+pub enum Instruction {
+    Constant(Value),
+    // Variables by name and the slot the resolver gave them, if any
+    Load(String, Option<usize>),
+    Store(String, Option<usize>),
+    Binary(Operation),
+    Pop,
+    Jump(usize),
+    JumpIfFalse(usize),
+    // Backward jump closing a loop, the place where timeouts are checked
+    Loop(usize),
+    Define(String, Rc<Function>),
+    Call(String, usize),
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Constant(value) => write!(f, "CONSTANT {}", value),
+            Instruction::Load(name, _) => write!(f, "LOAD {}", name),
+            Instruction::Store(name, _) => write!(f, "STORE {}", name),
+            Instruction::Binary(operation) => write!(f, "BINARY {}", operation),
+            Instruction::Pop => write!(f, "POP"),
+            Instruction::Jump(address) => write!(f, "JUMP {:04}", address),
+            Instruction::JumpIfFalse(address) => write!(f, "JUMP_IF_FALSE {:04}", address),
+            Instruction::Loop(address) => write!(f, "LOOP {:04}", address),
+            Instruction::Define(name, function) => {
+                write!(f, "DEFINE {}({})", name, function.parameters.join(", "))
+            }
+            Instruction::Call(name, arguments) => write!(f, "CALL {} {}", name, arguments),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub code: Vec<Instruction>,
+    // Statements of blocks with the address they end at, by the address they
+    // start at, outermost first
+    #[cfg(feature = "tracing")]
+    statements: BTreeMap<usize, Vec<(Rc<Node>, usize)>>,
+}
+
+impl Chunk {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.code.push(instruction);
+        self.code.len() - 1
+    }
+
+    #[cfg(feature = "tracing")]
+    fn statement(&mut self, start: usize, node: Rc<Node>) {
+        let end = self.code.len();
+        self.statements
+            .entry(start)
+            .or_default()
+            .insert(0, (node, end));
+    }
+
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len();
+        match &mut self.code[at] {
+            Instruction::Jump(address) | Instruction::JumpIfFalse(address) => *address = target,
+            _ => unreachable!("only jumps can be patched"),
+        }
+    }
+}
+
+impl fmt::Display for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (address, instruction) in self.code.iter().enumerate() {
+            writeln!(f, "{:04} {}", address, instruction)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn compile(node: &Node) -> Chunk {
+    let mut chunk = Chunk::default();
+    compile_node(node, &mut chunk);
+    chunk
+}
+
+fn compile_node(node: &Node, chunk: &mut Chunk) {
+    match node {
+        Node::Constant(value) => {
+            chunk.emit(Instruction::Constant(*value));
+        }
+        Node::BinaryOperation(operation, left_node, right_node) => {
+            compile_node(left_node, chunk);
+            compile_node(right_node, chunk);
+            chunk.emit(Instruction::Binary(*operation));
+        }
+        Node::Variable(name, slot) => {
+            chunk.emit(Instruction::Load(name.clone(), *slot));
+        }
+        Node::Assignment(name, slot, value) => {
+            compile_node(value, chunk);
+            chunk.emit(Instruction::Store(name.clone(), *slot));
+            chunk.emit(Instruction::Constant(Value::None));
+        }
+        Node::Block(body) => {
+            if body.is_empty() {
+                chunk.emit(Instruction::Constant(Value::None));
+            }
+            for (index, expression) in body.iter().enumerate() {
+                if index != 0 {
+                    chunk.emit(Instruction::Pop);
+                }
+                #[cfg(feature = "tracing")]
+                let start = chunk.code.len();
+                compile_node(expression, chunk);
+                #[cfg(feature = "tracing")]
+                chunk.statement(start, expression.clone());
+            }
+        }
+        Node::Function(name, function) => {
+            chunk.emit(Instruction::Define(name.clone(), function.clone()));
+        }
+        Node::Call(name, parameters) => {
+            for parameter in parameters.iter() {
+                compile_node(parameter, chunk);
+            }
+            chunk.emit(Instruction::Call(name.clone(), parameters.len()));
+        }
+        Node::IfElse(condition, if_body, else_body) => {
+            compile_node(condition, chunk);
+            let to_else = chunk.emit(Instruction::JumpIfFalse(0));
+            compile_node(if_body, chunk);
+            let to_end = chunk.emit(Instruction::Jump(0));
+            chunk.patch(to_else);
+            match else_body {
+                Some(else_body) => compile_node(else_body, chunk),
+                None => {
+                    chunk.emit(Instruction::Constant(Value::None));
+                }
+            }
+            chunk.patch(to_end);
+        }
+        Node::While(condition, body) => {
+            let start = chunk.code.len();
+            compile_node(condition, chunk);
+            let to_end = chunk.emit(Instruction::JumpIfFalse(0));
+            compile_node(body, chunk);
+            chunk.emit(Instruction::Pop);
+            chunk.emit(Instruction::Loop(start));
+            chunk.patch(to_end);
+            chunk.emit(Instruction::Constant(Value::None));
+        }
+        Node::For(init, condition, body, step) => {
+            compile_node(init, chunk);
+            chunk.emit(Instruction::Pop);
+            let start = chunk.code.len();
+            compile_node(condition, chunk);
+            let to_end = chunk.emit(Instruction::JumpIfFalse(0));
+            compile_node(body, chunk);
+            chunk.emit(Instruction::Pop);
+            compile_node(step, chunk);
+            chunk.emit(Instruction::Pop);
+            chunk.emit(Instruction::Loop(start));
+            chunk.patch(to_end);
+            chunk.emit(Instruction::Constant(Value::None));
+        }
+    }
+}
+
+struct Frame {
+    chunk: Rc<Chunk>,
+    ip: usize,
+    // Cache entry to fill with the result of a memoized call
+    remember: Option<(String, Vec<ValueKey>)>,
+    // Function whose result is checked against its annotation
+    returns: Option<(String, Rc<Function>)>,
+}
+
+// Compiled function bodies, keyed by the address of the body they were compiled from
+pub(crate) type Chunks = BTreeMap<*const Node, (Rc<Node>, Rc<Chunk>)>;
+
+struct Vm {
+    compiled: Rc<RefCell<Chunks>>,
+    // Spans of the calls and statements being executed, innermost last, with
+    // the address statements end at
+    #[cfg(feature = "tracing")]
+    spans: Vec<(tracing::span::EnteredSpan, Option<usize>)>,
+}
+
+impl Vm {
+    fn new(context: &Context) -> Vm {
+        Vm {
+            compiled: context.chunks(),
+            #[cfg(feature = "tracing")]
+            spans: Vec::new(),
+        }
+    }
+
+    fn chunk_for(&mut self, function: &Function) -> Rc<Chunk> {
+        let key = Rc::as_ptr(&function.body);
+        let mut compiled = self.compiled.borrow_mut();
+        let (_, chunk) = compiled
+            .entry(key)
+            .or_insert_with(|| (function.body.clone(), Rc::new(compile(&function.body))));
+        chunk.clone()
+    }
+
+    fn run(
+        &mut self,
+        chunk: Rc<Chunk>,
+        context: &mut Context,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let depth = context.depth();
+        let value = self.execute(chunk, context);
+        if value.is_err() {
+            context.unwind(depth);
+        }
+        #[cfg(feature = "tracing")]
+        while let Some(span) = self.spans.pop() {
+            drop(span);
+        }
+        value
+    }
+
+    fn execute(
+        &mut self,
+        chunk: Rc<Chunk>,
+        context: &mut Context,
+    ) -> Result<Value, Box<dyn std::error::Error>> {
+        let mut frames = vec![Frame {
+            chunk,
+            ip: 0,
+            remember: None,
+            returns: None,
+        }];
+        let mut stack: Vec<Value> = Vec::new();
+
+        while let Some(frame) = frames.last_mut() {
+            #[cfg(feature = "tracing")]
+            self.trace_statements(&frame.chunk, frame.ip);
+            if frame.ip == frame.chunk.code.len() {
+                if let Some((name, function)) = frame.returns.take() {
+                    function.check_result(&name, stack.last().unwrap())?;
+                }
+                if let Some((name, key)) = frame.remember.take() {
+                    context.remember(name, key, *stack.last().unwrap());
+                }
+                frames.pop();
+                if !frames.is_empty() {
+                    context.pop_frame();
+                    context.profile_leave();
+                    #[cfg(feature = "tracing")]
+                    self.spans.pop();
+                }
+                continue;
+            }
+            let chunk = frame.chunk.clone();
+            let instruction = &chunk.code[frame.ip];
+            frame.ip += 1;
+            context.profile_step();
+            context.burn_fuel()?;
+            if context.stepping() {
+                match instruction {
+                    Instruction::Constant(_) | Instruction::Load(_, _) => {}
+                    _ => context.pause(&format!("at {}", instruction)),
+                }
+            }
+
+            match instruction {
+                Instruction::Constant(value) => stack.push(*value),
+                Instruction::Load(name, slot) => {
+                    let variable = match slot {
+                        Some(slot) => context.slot_value(*slot),
+                        None => context.get_var(name),
+                    };
+                    match variable {
+                        Some(value) => stack.push(value),
+                        None => return Err(format!("{} is not defined", name).into()),
+                    }
+                }
+                Instruction::Store(name, slot) => {
+                    let value = stack.pop().unwrap();
+                    let slot = slot.unwrap_or_else(|| context.slot(name));
+                    context.check_new_variable(slot)?;
+                    context.set_slot(slot, value);
+                }
+                Instruction::Binary(operation) => {
+                    let right_value = stack.pop().unwrap();
+                    let left_value = stack.pop().unwrap();
+                    stack.push(evaluate_operation(operation, left_value, right_value)?);
+                }
+                Instruction::Pop => {
+                    stack.pop();
+                }
+                Instruction::Jump(address) => frame.ip = *address,
+                Instruction::JumpIfFalse(address) => {
+                    if !context.condition(&stack.pop().unwrap())? {
+                        frame.ip = *address;
+                    }
+                }
+                Instruction::Loop(address) => {
+                    context.check_deadline()?;
+                    frame.ip = *address;
+                }
+                Instruction::Define(name, function) => {
+                    context.define(name, function.clone());
+                    stack.push(Value::None);
+                }
+                Instruction::Call(name, arguments) => {
+                    if let Some(function) = context.host_function(name) {
+                        let values = stack.split_off(stack.len() - arguments);
+                        stack.push(function(&values)?);
+                        continue;
+                    }
+                    if context.async_function(name).is_some() {
+                        return Err(async_call_error(name));
+                    }
+                    let function = match context.overload(name, *arguments)? {
+                        Some(function) => function,
+                        None => {
+                            let values = stack.split_off(stack.len() - arguments);
+                            stack.push(call_builtin(name, &values, context)?);
+                            continue;
+                        }
+                    };
+                    context.check_deadline()?;
+                    if context.depth() + 1 > context.max_depth {
+                        return Err(format!(
+                            "Maximum recursion depth of {} exceeded in {}",
+                            context.max_depth, name
+                        )
+                        .into());
+                    }
+
+                    let values = stack.split_off(stack.len() - arguments);
+                    function.check_arguments(name, &values)?;
+                    let mut remember = None;
+                    context.profile_enter(name);
+                    if let Some(value) = context.call_native(name, &function, &values) {
+                        context.profile_leave();
+                        let value = value?;
+                        function.check_result(name, &value)?;
+                        stack.push(value);
+                        continue;
+                    }
+                    if function.memoized {
+                        let key: Vec<ValueKey> = values.iter().map(ValueKey::from).collect();
+                        if let Some(value) = context.remembered(name, &key) {
+                            context.profile_leave();
+                            stack.push(value);
+                            continue;
+                        }
+                        remember = Some((name.clone(), key));
+                    }
+                    context.push_frame(&function.parameters, values);
+                    if context.has_breakpoint(name) {
+                        context.pause(&format!("at breakpoint in {}", name));
+                    }
+                    #[cfg(feature = "tracing")]
+                    self.spans.push((
+                        tracing::debug_span!("call", function = %name).entered(),
+                        None,
+                    ));
+                    let chunk = self.chunk_for(&function);
+                    let returns = function.returns.map(|_| (name.clone(), function.clone()));
+                    frames.push(Frame {
+                        chunk,
+                        ip: 0,
+                        remember,
+                        returns,
+                    });
+                }
+            }
+        }
+
+        Ok(stack.pop().unwrap_or(Value::None))
+    }
+
+    // Closes the spans of statements ending at the address execution got to,
+    // and opens those of statements starting there
+    #[cfg(feature = "tracing")]
+    fn trace_statements(&mut self, chunk: &Chunk, ip: usize) {
+        while matches!(self.spans.last(), Some((_, Some(end))) if *end == ip) {
+            self.spans.pop();
+        }
+        for (node, end) in chunk.statements.get(&ip).into_iter().flatten() {
+            let span = tracing::trace_span!("statement", node = %node);
+            self.spans.push((span.entered(), Some(*end)));
+        }
+    }
+}
+
+pub fn evaluate(node: &Node, context: &mut Context) -> Result<Value, Box<dyn std::error::Error>> {
+    Vm::new(context).run(Rc::new(compile(node)), context)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::{Context, Node, Operation, Value};
+    use crate::parser::{self, fixture};
+    use crate::resolver::resolve;
+    use crate::vm::{compile, evaluate, Instruction};
+    use std::rc::Rc;
+    use Operation::*;
+
+    fn num(num: f32) -> Rc<Node> {
+        Rc::new(Node::Constant(Value::Number(num)))
+    }
+
+    fn var(name: &str) -> Rc<Node> {
+        Rc::new(Node::Variable(name.to_string(), None))
+    }
+
+    fn bin(oper: Operation, left: Rc<Node>, right: Rc<Node>) -> Rc<Node> {
+        Rc::new(Node::BinaryOperation(oper, left, right))
+    }
+
+    #[test]
+    fn compiles_expression() {
+        let operation = bin(Plus, num(1.0), bin(Multiply, num(2.0), num(3.0)));
+        let chunk = compile(&operation);
+        assert_eq!(chunk.code.len(), 5);
+        match chunk.code[4] {
+            Instruction::Binary(Plus) => {}
+            ref other => panic!("unexpected {:?}", other),
+        }
+        let mut context = Context::default();
+        let value = evaluate(&operation, &mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 7.0);
+    }
+
+    #[test]
+    fn loop_and_call() {
+        // for i = 0; i < 10; i = i + 1 { s = add(s, i); }
+        let add = Node::Function("add".to_string(), fixture("fn add(a, b) { a + b; }"));
+        let call = Rc::new(Node::Call("add".to_string(), vec![var("s"), var("i")]));
+        let program = Node::Block(vec![
+            Rc::new(add),
+            Rc::new(Node::Assignment("s".to_string(), None, num(0.0))),
+            Rc::new(Node::For(
+                Rc::new(Node::Assignment("i".to_string(), None, num(0.0))),
+                bin(Less, var("i"), num(10.0)),
+                Rc::new(Node::Block(vec![Rc::new(Node::Assignment(
+                    "s".to_string(),
+                    None,
+                    call,
+                ))])),
+                Rc::new(Node::Assignment(
+                    "i".to_string(),
+                    None,
+                    bin(Plus, var("i"), num(1.0)),
+                )),
+            )),
+            var("s"),
+        ]);
+
+        let mut context = Context::default();
+        let value = evaluate(&program, &mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 45.0);
+    }
+
+    #[test]
+    fn resolved_variables_use_slots() {
+        let mut context = Context::default();
+        let add = Node::Function("add".to_string(), fixture("fn add(a) { b = a + x; b; }"));
+        let call = Node::Call("add".to_string(), vec![num(2.0)]);
+        let program = Node::Block(vec![
+            Rc::new(Node::Assignment("x".to_string(), None, num(1.0))),
+            Rc::new(add),
+            Rc::new(call),
+        ]);
+        let program = resolve(&program, &context);
+        let chunk = compile(&program);
+        let slot = context.slot("x");
+        match chunk.code[1] {
+            Instruction::Store(ref name, Some(stored)) if name == "x" && stored == slot => {}
+            ref other => panic!("unexpected {:?}", other),
+        }
+        let value = evaluate(&program, &mut context).unwrap();
+        assert_eq!(value.to_number().unwrap(), 3.0);
+        assert!(context.get_var("b").is_none());
+    }
+
+    #[test]
+    fn keeps_compiled_functions_between_runs() {
+        let mut context = Context::default();
+        for source in ["fn double(a) { a * 2; }", "double(1)", "double(2)"].iter() {
+            let (_, node) = parser::statement(source.as_bytes()).unwrap();
+            evaluate(&node, &mut context).unwrap();
+        }
+        assert_eq!(context.chunks().borrow().len(), 1);
+    }
+
+    #[test]
+    fn disassemble_loop() {
+        // while i < 3 { i = i + 1; }
+        let program = Node::While(
+            bin(Less, var("i"), num(3.0)),
+            Rc::new(Node::Block(vec![Rc::new(Node::Assignment(
+                "i".to_string(),
+                None,
+                bin(Plus, var("i"), num(1.0)),
+            ))])),
+        );
+        assert_eq!(
+            compile(&program).to_string(),
+            "0000 LOAD i\n\
+             0001 CONSTANT 3\n\
+             0002 BINARY <\n\
+             0003 JUMP_IF_FALSE 0011\n\
+             0004 LOAD i\n\
+             0005 CONSTANT 1\n\
+             0006 BINARY +\n\
+             0007 STORE i\n\
+             0008 CONSTANT None\n\
+             0009 POP\n\
+             0010 LOOP 0000\n\
+             0011 CONSTANT None\n"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use crate::interpreter::Interpreter;
+use wasm_bindgen::prelude::*;
+
+// Evaluates a program in a fresh session and returns what the REPL would print
+#[wasm_bindgen]
+pub fn eval(source: &str) -> String {
+    Session::new().eval(source)
+}
+
+// Keeps variables and functions between evaluations, for a playground where
+// statements are typed one after another
+#[wasm_bindgen]
+pub struct Session {
+    interpreter: Interpreter,
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session::new()
+    }
+}
+
+#[wasm_bindgen]
+impl Session {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Session {
+        Session {
+            interpreter: Interpreter::builder().build(),
+        }
+    }
+
+    // The value of the last statement, or the error that stopped the program
+    pub fn eval(&mut self, source: &str) -> String {
+        match self.interpreter.eval_str(source) {
+            Ok(value) => value.to_string(),
+            Err(error) => format!("Error: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wasm::{eval, Session};
+
+    #[test]
+    fn sessions_keep_state() {
+        let mut session = Session::new();
+        assert_eq!(session.eval("x = 2"), "None");
+        assert_eq!(session.eval("x * 3"), "6");
+        assert_eq!(eval("x"), "Error: x is not defined");
+    }
+}
@@ -0,0 +1,67 @@
+// Golden tests: every tests/scripts/*.sp is run with both evaluators and what
+// it prints is compared with the .expected file next to it. Each statement
+// with a value prints it the way the REPL echoes it, and an error prints its
+// line and message and ends the script. Run with SP_BLESS=1 to write the
+// expected files from the current output instead.
+use sp::{parser, Interpreter, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn run(source: &str, use_vm: bool) -> String {
+    let mut output = String::new();
+    let program = match parser::program(source) {
+        Ok(program) => program,
+        Err(error) => return format!("{}\n", error),
+    };
+    let mut interpreter = Interpreter::builder().vm(use_vm).build();
+    for (line, statement) in program {
+        match interpreter.evaluate(&statement, &mut Vec::new()) {
+            Ok(Value::None) => {}
+            Ok(value) => output.push_str(&format!("{}\n", value)),
+            Err(error) => {
+                output.push_str(&format!("{}: {}\n", line, error));
+                break;
+            }
+        }
+    }
+    output
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut scripts: Vec<PathBuf> = fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "sp"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+#[test]
+fn scripts_print_expected_output() {
+    let bless = std::env::var_os("SP_BLESS").is_some();
+    let mut failures = Vec::new();
+    for script in scripts() {
+        let source = fs::read_to_string(&script).unwrap();
+        let expected_path = script.with_extension("expected");
+        let output = run(&source, false);
+        if bless {
+            fs::write(&expected_path, &output).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        for (evaluator, output) in [("tree walker", output), ("vm", run(&source, true))].iter() {
+            if *output != expected {
+                failures.push(format!(
+                    "{} with the {}:\n--- expected\n{}--- printed\n{}",
+                    script.display(),
+                    evaluator,
+                    expected,
+                    output
+                ));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}